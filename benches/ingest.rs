@@ -0,0 +1,92 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use picolms::bench::Ingest;
+use picolms::bench::Sharded;
+use serde_json::json;
+use serde_json::value::RawValue;
+use serde_json::Value as JsonValue;
+
+const ROWS: usize = 8192;
+const LEVELS: [&str; 3] = ["info", "warn", "error"];
+
+fn fields() -> JsonValue {
+    json!([
+        {"name": "time", "type": "timestamp"},
+        {"name": "level", "type": "string"},
+        {"name": "service", "type": "string"},
+        {"name": "message", "type": "string"},
+        {"name": "duration", "type": "f64", "nullable": true},
+        {"name": "status", "type": "i64", "nullable": true},
+        {"name": "tags", "type": "vec_string", "nullable": true},
+    ])
+}
+
+fn body() -> String {
+    let rows = (0..ROWS)
+        .map(|i| {
+            json!({
+                "time": format!("2026-01-01T00:00:{:02}.{:06}Z", i % 60, i),
+                "level": LEVELS[i % 3],
+                "service": format!("service-{}", i % 16),
+                "message": format!("request {i} handled by worker {}", i % 8),
+                "duration": i as f64 / 7.0,
+                "status": 200 + (i % 5) as i64,
+                "tags": ["http", "api"],
+                "unused": {"nested": [1, 2, 3]},
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::to_string(&rows).unwrap()
+}
+
+fn ingest(c: &mut Criterion) {
+    let body = body();
+    let mut ingest = Ingest::new(fields());
+    let mut group = c.benchmark_group("ingest");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+
+    group.bench_function("json", |b| {
+        b.iter(|| {
+            let rows: Vec<JsonValue> = serde_json::from_str(&body).unwrap();
+            assert_eq!(ingest.json(rows), 0);
+            ingest.finish()
+        })
+    });
+
+    group.bench_function("raw", |b| {
+        b.iter(|| {
+            let rows: Vec<Box<RawValue>> = serde_json::from_str(&body).unwrap();
+            assert_eq!(ingest.raw(rows), 0);
+            ingest.finish()
+        })
+    });
+
+    group.finish();
+}
+
+/// Rows keyed by service over one shard and over several, converted on
+/// the shards' workers in parallel.
+fn shards(c: &mut Criterion) {
+    let body = body();
+    let mut group = c.benchmark_group("shards");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+
+    for shards in [1, 4] {
+        let sharded = Sharded::new(fields(), shards, "service");
+        group.bench_function(BenchmarkId::from_parameter(shards), |b| {
+            b.iter(|| {
+                let rows: Vec<JsonValue> = serde_json::from_str(&body).unwrap();
+                assert_eq!(sharded.json(rows), 0);
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, ingest, shards);
+criterion_main!(benches);
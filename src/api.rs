@@ -1,45 +1,136 @@
+mod dead_letter;
+mod erasures;
+mod error;
 mod health;
 mod insert;
+mod keys;
+mod origin;
+mod query;
+mod snapshots;
 mod state;
+mod streams;
+mod tls;
 
+use crate::api::tls::MtlsAcceptor;
 use anyhow::Context;
 use anyhow::Result;
+use poem::delete;
 use poem::get;
 use poem::listener::Listener;
-use poem::listener::RustlsCertificate;
-use poem::listener::RustlsConfig;
 use poem::listener::TcpListener;
 use poem::post;
 use poem::EndpointExt;
 use poem::Route;
 use poem::Server;
 pub use state::State;
+use std::fs::File;
+use std::io::BufReader;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::aws_lc_rs;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::rustls::ServerConfig;
 use tokio_util::sync::CancellationToken;
 
 pub async fn start_server(
     addr: SocketAddr,
-    tls: RustlsConfig,
+    tls: Arc<ServerConfig>,
     state: State,
     ct: CancellationToken,
 ) -> Result<()> {
     let router = Route::new()
         .at("/", get(health::handler))
-        .at("/insert", post(insert::handler))
+        .at("/health/blocks", get(health::blocks_handler))
+        .at("/insert/:stream", post(insert::handler))
+        .at(
+            "/streams/:stream",
+            get(streams::get_handler).put(streams::put_handler),
+        )
+        .at("/streams/:stream/query", post(query::handler))
+        .at("/streams/:stream/blocks", post(query::blocks_handler))
+        .at(
+            "/streams/:stream/retention",
+            get(streams::retention_handler),
+        )
+        .at("/streams/:stream/scrub", get(streams::scrub_handler))
+        .at("/streams/:stream/chain", get(streams::chain_handler))
+        .at(
+            "/streams/:stream/snapshots",
+            get(snapshots::list_handler).post(snapshots::create_handler),
+        )
+        .at(
+            "/streams/:stream/snapshots/:id",
+            delete(snapshots::delete_handler),
+        )
+        .at(
+            "/streams/:stream/snapshots/:id/archive",
+            get(snapshots::export_handler),
+        )
+        .at("/streams/:stream/restore", post(snapshots::restore_handler))
+        .at(
+            "/streams/:stream/keys",
+            get(keys::list_handler).delete(keys::shred_handler),
+        )
+        .at("/streams/:stream/keys/rotate", post(keys::rotate_handler))
+        .at(
+            "/streams/:stream/erasures",
+            get(erasures::list_handler).post(erasures::create_handler),
+        )
+        .at("/streams/:stream/erasures/:id", get(erasures::get_handler))
+        .at(
+            "/streams/:stream/dead_letter/query",
+            post(dead_letter::query_handler),
+        )
+        .at(
+            "/streams/:stream/dead_letter/replay",
+            post(dead_letter::replay_handler),
+        )
         .data(state);
 
-    Server::new(TcpListener::bind(addr).rustls(tls))
+    let acceptor = TcpListener::bind(addr)
+        .into_acceptor()
+        .await
+        .context("bind")?;
+
+    Server::new_with_acceptor(MtlsAcceptor::new(acceptor, tls))
         .run_with_graceful_shutdown(router, ct.cancelled_owned(), None)
         .await
         .context("run")
 }
 
-pub fn tls_config(ca: &PathBuf, crt: &PathBuf, key: &PathBuf) -> Result<RustlsConfig> {
-    let ca = std::fs::read(ca).with_context(|| ca.display().to_string())?;
-    let crt = std::fs::read(crt).with_context(|| crt.display().to_string())?;
-    let key = std::fs::read(key).with_context(|| key.display().to_string())?;
-    Ok(RustlsConfig::new()
-        .client_auth_required(ca)
-        .fallback(RustlsCertificate::new().key(key).cert(crt)))
+pub fn tls_config(ca: &PathBuf, crt: &PathBuf, key: &PathBuf) -> Result<Arc<ServerConfig>> {
+    let open = |path: &PathBuf| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| path.display().to_string())
+    };
+
+    let mut roots = RootCertStore::empty();
+    for ca in rustls_pemfile::certs(&mut open(ca)?) {
+        roots.add(ca.context("ca")?).context("ca")?;
+    }
+
+    let crt = rustls_pemfile::certs(&mut open(crt)?)
+        .collect::<Result<Vec<_>, _>>()
+        .context("crt")?;
+    let key = rustls_pemfile::private_key(&mut open(key)?)
+        .context("key")?
+        .context("key: no private key found")?;
+
+    let provider = Arc::new(aws_lc_rs::default_provider());
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .context("client verifier")?;
+
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .context("protocol versions")?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(crt, key)
+        .context("server certificate")?;
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
 }
@@ -0,0 +1,32 @@
+use crate::api::State;
+use crate::engine::Origin;
+use crate::engine::Query;
+use crate::engine::Replay;
+use poem::handler;
+use poem::web::Data;
+use poem::web::Json;
+use poem::web::Path;
+use poem::Result;
+use serde_json::Value as JsonValue;
+
+#[handler]
+pub async fn query_handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+    Json(query): Json<Query>,
+) -> Result<Json<Vec<JsonValue>>> {
+    Ok(Json(
+        state.engine().query_dead_letter(&stream, query).await?,
+    ))
+}
+
+#[handler]
+pub async fn replay_handler(
+    state: Data<&State>,
+    origin: Origin,
+    Path(stream): Path<String>,
+) -> Result<Json<Replay>> {
+    Ok(Json(
+        state.engine().replay_dead_letter(&stream, &origin).await?,
+    ))
+}
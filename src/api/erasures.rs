@@ -0,0 +1,36 @@
+use crate::api::State;
+use crate::engine::Erasure;
+use crate::engine::ErasureRequest;
+use crate::engine::Origin;
+use poem::handler;
+use poem::web::Data;
+use poem::web::Json;
+use poem::web::Path;
+use poem::Result;
+use uuid::Uuid;
+
+#[handler]
+pub async fn create_handler(
+    state: Data<&State>,
+    origin: Origin,
+    Path(stream): Path<String>,
+    Json(request): Json<ErasureRequest>,
+) -> Result<Json<Erasure>> {
+    Ok(Json(state.engine().erase(&stream, request, &origin)?))
+}
+
+#[handler]
+pub async fn list_handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+) -> Result<Json<Vec<Erasure>>> {
+    Ok(Json(state.engine().erasures(&stream)?))
+}
+
+#[handler]
+pub async fn get_handler(
+    state: Data<&State>,
+    Path((stream, id)): Path<(String, Uuid)>,
+) -> Result<Json<Erasure>> {
+    Ok(Json(state.engine().erasure(&stream, id)?))
+}
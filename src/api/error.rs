@@ -0,0 +1,39 @@
+use crate::engine;
+use poem::error::ResponseError;
+use poem::http::header;
+use poem::http::StatusCode;
+use poem::IntoResponse;
+use poem::Response;
+
+impl ResponseError for engine::Error {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidStreamName(_)
+            | Self::InvalidDefinition(_)
+            | Self::InvalidArchive(_)
+            | Self::InvalidRedaction(_)
+            | Self::EncryptionNotConfigured
+            | Self::ChainNotConfigured => StatusCode::BAD_REQUEST,
+            Self::StreamNotFound(_) | Self::SnapshotNotFound(_) | Self::ErasureNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            Self::DiskFull => StatusCode::INSUFFICIENT_STORAGE,
+            _ if self.is_stopped() => StatusCode::SERVICE_UNAVAILABLE,
+            _ if self.retry_after().is_some() => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn as_response(&self) -> Response {
+        let mut resp = self.to_string().into_response();
+        resp.set_status(self.status());
+
+        if let Some(retry_after) = self.retry_after() {
+            // Retry-After takes whole seconds, round up.
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            resp.headers_mut().insert(header::RETRY_AFTER, secs.into());
+        }
+
+        resp
+    }
+}
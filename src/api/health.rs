@@ -1,7 +1,18 @@
+use crate::api::State;
+use crate::engine::ScrubReport;
 use poem::handler;
 use poem::http::StatusCode;
+use poem::web::Data;
+use poem::web::Json;
+use std::collections::BTreeMap;
 
 #[handler]
 pub fn handler() -> StatusCode {
     StatusCode::NO_CONTENT
 }
+
+/// Damaged blocks found by the last scrub of each stream.
+#[handler]
+pub fn blocks_handler(state: Data<&State>) -> Json<BTreeMap<String, ScrubReport>> {
+    Json(state.engine().scrub_reports())
+}
@@ -1,7 +1,44 @@
+use crate::api::State;
+use crate::engine::FailedRows;
+use crate::engine::Origin;
+use crate::engine::Rows;
 use poem::handler;
-use poem::http::StatusCode;
+use poem::http::HeaderMap;
+use poem::web::Data;
+use poem::web::Json;
+use poem::web::Path;
+use poem::Result;
+use serde::Serialize;
+use serde_json::value::RawValue;
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+#[derive(Serialize)]
+pub struct Response {
+    accepted: usize,
+    duplicates: usize,
+    failed: FailedRows,
+}
 
 #[handler]
-pub fn handler() -> StatusCode {
-    StatusCode::NO_CONTENT
+pub async fn handler(
+    state: Data<&State>,
+    origin: Origin,
+    headers: &HeaderMap,
+    Path(stream): Path<String>,
+    Json(rows): Json<Vec<Box<RawValue>>>,
+) -> Result<Json<Response>> {
+    let total = rows.len();
+    let idempotency_key = headers.get(IDEMPOTENCY_KEY).and_then(|v| v.to_str().ok());
+
+    let inserted = state
+        .engine()
+        .insert(&stream, Rows::Raw(rows), &origin, idempotency_key)
+        .await?;
+
+    Ok(Json(Response {
+        accepted: total - inserted.failed.len() - inserted.duplicates,
+        duplicates: inserted.duplicates,
+        failed: inserted.failed,
+    }))
 }
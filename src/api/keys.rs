@@ -0,0 +1,24 @@
+use crate::api::State;
+use crate::engine::KeyInfo;
+use crate::engine::Shred;
+use poem::handler;
+use poem::web::Data;
+use poem::web::Json;
+use poem::web::Path;
+use poem::Result;
+
+#[handler]
+pub fn list_handler(state: Data<&State>, Path(stream): Path<String>) -> Result<Json<Vec<KeyInfo>>> {
+    Ok(Json(state.engine().keys(&stream)?))
+}
+
+#[handler]
+pub fn rotate_handler(state: Data<&State>, Path(stream): Path<String>) -> Result<Json<KeyInfo>> {
+    Ok(Json(state.engine().rotate_key(&stream)?))
+}
+
+/// Crypto-shredding: the stream's data becomes unreadable everywhere.
+#[handler]
+pub async fn shred_handler(state: Data<&State>, Path(stream): Path<String>) -> Result<Json<Shred>> {
+    Ok(Json(state.engine().shred(&stream).await?))
+}
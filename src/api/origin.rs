@@ -0,0 +1,28 @@
+use crate::api::tls::MtlsAcceptor;
+use crate::api::tls::CLIENT_SUBJECT;
+use crate::engine::Origin;
+use poem::Addr;
+use poem::FromRequest;
+use poem::Request;
+use poem::RequestBody;
+use poem::Result;
+use time::OffsetDateTime;
+
+impl<'a> FromRequest<'a> for Origin {
+    async fn from_request(req: &'a Request, _: &mut RequestBody) -> Result<Self> {
+        let (client, peer) = match &req.remote_addr().0 {
+            Addr::Custom(CLIENT_SUBJECT, addr) => {
+                let (peer, subject) = MtlsAcceptor::split(addr);
+                let subject = (!subject.is_empty()).then(|| subject.to_string());
+                (subject, Some(peer.to_string()))
+            }
+            addr => (Some(addr.to_string()), Some(addr.to_string())),
+        };
+
+        Ok(Origin {
+            client,
+            peer,
+            received_at: OffsetDateTime::now_utc(),
+        })
+    }
+}
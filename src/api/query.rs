@@ -0,0 +1,27 @@
+use crate::api::State;
+use crate::engine::BlockEntry;
+use crate::engine::Query;
+use poem::handler;
+use poem::web::Data;
+use poem::web::Json;
+use poem::web::Path;
+use poem::Result;
+use serde_json::Value as JsonValue;
+
+#[handler]
+pub async fn handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+    Json(query): Json<Query>,
+) -> Result<Json<Vec<JsonValue>>> {
+    Ok(Json(state.engine().query(&stream, query).await?))
+}
+
+#[handler]
+pub fn blocks_handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+    Json(query): Json<Query>,
+) -> Result<Json<Vec<BlockEntry>>> {
+    Ok(Json(state.engine().blocks(&stream, &query)?))
+}
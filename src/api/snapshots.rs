@@ -0,0 +1,65 @@
+use crate::api::State;
+use crate::engine::Restore;
+use crate::engine::SnapshotInfo;
+use poem::error::InternalServerError;
+use poem::handler;
+use poem::web::Data;
+use poem::web::Json;
+use poem::web::Path;
+use poem::Body;
+use poem::Response;
+use poem::Result;
+use uuid::Uuid;
+
+#[handler]
+pub async fn create_handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+) -> Result<Json<SnapshotInfo>> {
+    Ok(Json(state.engine().create_snapshot(&stream).await?))
+}
+
+#[handler]
+pub async fn list_handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+) -> Result<Json<Vec<SnapshotInfo>>> {
+    Ok(Json(state.engine().snapshots(&stream).await?))
+}
+
+#[handler]
+pub async fn delete_handler(
+    state: Data<&State>,
+    Path((stream, id)): Path<(String, Uuid)>,
+) -> Result<()> {
+    Ok(state.engine().delete_snapshot(&stream, id).await?)
+}
+
+#[handler]
+pub async fn export_handler(
+    state: Data<&State>,
+    Path((stream, id)): Path<(String, Uuid)>,
+) -> Result<Response> {
+    let path = state.engine().export_snapshot(&stream, id).await?;
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(InternalServerError)?;
+
+    Ok(Response::builder()
+        .content_type("application/octet-stream")
+        .body(Body::from_async_read(file)))
+}
+
+#[handler]
+pub async fn restore_handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+    body: Body,
+) -> Result<Json<Restore>> {
+    Ok(Json(
+        state
+            .engine()
+            .restore(&stream, body.into_async_read())
+            .await?,
+    ))
+}
@@ -0,0 +1,48 @@
+use crate::api::State;
+use crate::engine::ChainReport;
+use crate::engine::RetentionReport;
+use crate::engine::ScrubReport;
+use crate::engine::StreamDefinition;
+use poem::handler;
+use poem::web::Data;
+use poem::web::Json;
+use poem::web::Path;
+use poem::Result;
+
+#[handler]
+pub fn get_handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+) -> Result<Json<StreamDefinition>> {
+    Ok(Json(state.engine().definition(&stream)?))
+}
+
+#[handler]
+pub fn put_handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+    Json(definition): Json<StreamDefinition>,
+) -> Result<Json<StreamDefinition>> {
+    Ok(Json(state.engine().put_stream(&stream, definition)?))
+}
+
+#[handler]
+pub fn retention_handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+) -> Result<Json<RetentionReport>> {
+    Ok(Json(state.engine().retention(&stream)?))
+}
+
+#[handler]
+pub fn scrub_handler(state: Data<&State>, Path(stream): Path<String>) -> Result<Json<ScrubReport>> {
+    Ok(Json(state.engine().scrub(&stream)?))
+}
+
+#[handler]
+pub async fn chain_handler(
+    state: Data<&State>,
+    Path(stream): Path<String>,
+) -> Result<Json<ChainReport>> {
+    Ok(Json(state.engine().verify_chain(&stream).await?))
+}
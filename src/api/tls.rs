@@ -0,0 +1,138 @@
+use poem::http::uri::Scheme;
+use poem::listener::Acceptor;
+use poem::web::LocalAddr;
+use poem::web::RemoteAddr;
+use poem::Addr;
+use std::borrow::Cow;
+use std::io::Result as IoResult;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio::time::timeout;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::FromDer;
+use x509_parser::prelude::X509Certificate;
+
+/// Remote address kind carrying the peer address and the subject of the
+/// client certificate, separated by a space.
+pub const CLIENT_SUBJECT: &str = "mtls";
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_QUEUE: usize = 64;
+/// Pause after a failed accept, e.g. out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+type Accepted = (TlsStream<TcpStream>, LocalAddr, RemoteAddr);
+
+/// TLS acceptor exposing the verified client certificate subject as the
+/// remote address of a connection, so handlers can attribute requests.
+/// Handshakes run in their own tasks and never hold up the accept loop.
+pub struct MtlsAcceptor {
+    local_addr: Vec<LocalAddr>,
+    rx: mpsc::Receiver<Accepted>,
+    task: JoinHandle<()>,
+}
+
+impl MtlsAcceptor {
+    pub fn new<A>(mut inner: A, config: Arc<ServerConfig>) -> Self
+    where
+        A: Acceptor<Io = TcpStream> + 'static,
+    {
+        let local_addr = inner.local_addr();
+        let tls = TlsAcceptor::from(config);
+        let (tx, rx) = mpsc::channel(HANDSHAKE_QUEUE);
+
+        let task = tokio::spawn(async move {
+            loop {
+                let (stream, local_addr, peer, _) = match inner.accept().await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        tracing::warn!("failed to accept a connection: {e}");
+                        sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+
+                let tls = tls.clone();
+                let tx = tx.clone();
+
+                tokio::spawn(async move {
+                    let Ok(Ok(stream)) = timeout(HANDSHAKE_TIMEOUT, tls.accept(stream)).await
+                    else {
+                        return;
+                    };
+
+                    let subject = Self::client_subject(&stream).unwrap_or_default();
+                    let remote_addr = RemoteAddr(Addr::Custom(
+                        CLIENT_SUBJECT,
+                        Cow::Owned(format!("{} {subject}", peer.0)),
+                    ));
+                    tx.send((stream, local_addr, remote_addr)).await.ok();
+                });
+            }
+        });
+
+        Self {
+            local_addr,
+            rx,
+            task,
+        }
+    }
+
+    /// Peer address and client certificate subject of a remote address set
+    /// by the acceptor.
+    pub fn split(addr: &str) -> (&str, &str) {
+        addr.split_once(' ').unwrap_or((addr, ""))
+    }
+
+    fn client_subject(stream: &TlsStream<TcpStream>) -> Option<String> {
+        let certificate = stream.get_ref().1.peer_certificates()?.first()?;
+        let (_, certificate) = X509Certificate::from_der(certificate.as_ref()).ok()?;
+        Some(certificate.subject().to_string())
+    }
+}
+
+impl Acceptor for MtlsAcceptor {
+    type Io = TlsStream<TcpStream>;
+
+    fn local_addr(&self) -> Vec<LocalAddr> {
+        self.local_addr.clone()
+    }
+
+    async fn accept(&mut self) -> IoResult<(Self::Io, LocalAddr, RemoteAddr, Scheme)> {
+        match self.rx.recv().await {
+            Some((stream, local_addr, remote_addr)) => {
+                Ok((stream, local_addr, remote_addr, Scheme::HTTPS))
+            }
+            None => Err(std::io::Error::other("acceptor stopped")),
+        }
+    }
+}
+
+impl Drop for MtlsAcceptor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_keeps_the_peer_and_the_subject() {
+        assert_eq!(
+            MtlsAcceptor::split("socket://10.0.0.1:5000 CN=agent, O=Acme"),
+            ("socket://10.0.0.1:5000", "CN=agent, O=Acme")
+        );
+        assert_eq!(
+            MtlsAcceptor::split("socket://10.0.0.1:5000 "),
+            ("socket://10.0.0.1:5000", "")
+        );
+    }
+}
@@ -1,7 +1,475 @@
-pub struct Engine {}
+mod accumulator;
+mod block;
+mod catalog;
+mod chain;
+mod compaction;
+mod crypto;
+mod dead_letter;
+mod decode;
+mod dedup;
+mod disk;
+mod erasure;
+mod filter;
+mod grok;
+mod object_store;
+mod partition;
+mod pattern;
+mod pipeline;
+mod query;
+mod replication;
+mod retention;
+mod schema;
+mod scrub;
+mod shard;
+mod snapshot;
+mod storage;
+mod stream;
+mod tiering;
+mod value;
+
+#[cfg(feature = "bench")]
+pub use accumulator::bench;
+pub use accumulator::FailedRows;
+pub use accumulator::Limits;
+pub use accumulator::MemoryBudget;
+pub use accumulator::Origin;
+pub use accumulator::Rows;
+pub use catalog::BlockEntry;
+pub use chain::ChainReport;
+pub use chain::ServerKey;
+pub use compaction::CompactionConfig;
+pub use crypto::KeyInfo;
+pub use crypto::MasterKey;
+pub use dedup::Inserted;
+pub use disk::DiskConfig;
+pub use erasure::Erasure;
+pub use erasure::ErasureRequest;
+pub use object_store::ObjectStore;
+pub use object_store::ObjectStoreConfig;
+pub use query::Query;
+pub use replication::ReplicationConfig;
+pub use retention::RetentionReport;
+pub use scrub::ScrubConfig;
+pub use scrub::ScrubReport;
+pub use snapshot::Restore;
+pub use snapshot::SnapshotInfo;
+pub use stream::Replay;
+pub use stream::Shred;
+pub use stream::StreamDefinition;
+pub use tiering::Remote;
+pub use tiering::TieringConfig;
+
+use crate::engine::disk::Watchdog;
+use crate::engine::replication::Message;
+use crate::engine::snapshot::Snapshots;
+use crate::engine::stream::Shared;
+use crate::engine::stream::Stream;
+use crate::picodata::service::ServiceWarnings;
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::task::spawn_blocking;
+use tokio_util::sync::DropGuard;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+type StreamName = String;
+type Streams = Arc<RwLock<HashMap<StreamName, Arc<Stream>>>>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid stream name: {0}")]
+    InvalidStreamName(StreamName),
+    #[error("stream not found: {0}")]
+    StreamNotFound(StreamName),
+    #[error("not enough disk space")]
+    DiskFull,
+    #[error("invalid stream definition: {0}")]
+    InvalidDefinition(String),
+    #[error("accumulator: {0}")]
+    Accumulator(#[from] accumulator::Error),
+    #[error("block: {0}")]
+    Block(#[from] block::Error),
+    #[error("tiering: {0}")]
+    Tiering(#[from] tiering::Error),
+    #[error("replication: {0}")]
+    Replication(#[from] replication::Error),
+    #[error("snapshot not found: {0}")]
+    SnapshotNotFound(Uuid),
+    #[error("invalid archive: {0}")]
+    InvalidArchive(String),
+    #[error("snapshot: {0}")]
+    Snapshot(snapshot::Error),
+    #[error("encryption is not configured")]
+    EncryptionNotConfigured,
+    #[error("encryption: {0}")]
+    Crypto(#[from] crypto::Error),
+    #[error("hash chain is not configured")]
+    ChainNotConfigured,
+    #[error("invalid redaction: {0}")]
+    InvalidRedaction(String),
+    #[error("erasure not found: {0}")]
+    ErasureNotFound(Uuid),
+    #[error("erasure: {0}")]
+    Erasure(#[from] erasure::Error),
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub dir: PathBuf,
+    pub limits: Limits,
+    /// Shared by the accumulators of all streams.
+    pub memory: Arc<MemoryBudget>,
+    /// Recorded with every row ingested by this instance.
+    pub instance_id: Arc<str>,
+    pub compaction: CompactionConfig,
+    pub retention_interval: Duration,
+    pub scrub: ScrubConfig,
+    pub disk: DiskConfig,
+    /// Object store cold blocks are offloaded to, if configured.
+    pub remote: Option<Arc<Remote>>,
+    /// Other instances of the replicaset new blocks are shipped to.
+    pub replication: Option<ReplicationConfig>,
+    /// Wraps the data keys blocks are encrypted with, if configured.
+    pub encryption: Option<Arc<MasterKey>>,
+    /// Signs the hash chains over the blocks of the streams, if configured.
+    pub server_key: Option<Arc<ServerKey>>,
+}
+
+pub struct Engine {
+    config: Config,
+    tt: TaskTracker,
+    sw: ServiceWarnings,
+    streams: Streams,
+    disk: Arc<Watchdog>,
+    _disk: DropGuard,
+}
+
+impl Error {
+    /// When the request may succeed if retried later.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Accumulator(accumulator::Error::QueueFull(v))
+            | Self::Accumulator(accumulator::Error::MemoryBudgetExceeded(v)) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// When the stream is shutting down and takes no more rows.
+    pub fn is_stopped(&self) -> bool {
+        matches!(self, Self::Accumulator(accumulator::Error::Stopped))
+    }
+}
+
+impl From<snapshot::Error> for Error {
+    fn from(e: snapshot::Error) -> Self {
+        match e {
+            snapshot::Error::NotFound(id) => Self::SnapshotNotFound(id),
+            snapshot::Error::Malformed(v) => Self::InvalidArchive(v),
+            e => Self::Snapshot(e),
+        }
+    }
+}
 
 impl Engine {
-    pub fn new() -> Self {
-        Self {}
+    /// Free space is checked before each chunk of a restore upload is written.
+    const UPLOAD_CHUNK: usize = 1 << 20;
+
+    pub fn open(config: Config, tt: TaskTracker, sw: ServiceWarnings) -> Result<Self, Error> {
+        std::fs::create_dir_all(&config.dir)?;
+        let mut streams = HashMap::new();
+
+        for entry in std::fs::read_dir(&config.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(definition) = StreamDefinition::load(&entry.path())? {
+                let shared = Shared::open(&name, &entry.path(), &config)?;
+                let stream = Stream::new(name.clone(), definition, &config, shared, &tt, &sw);
+                streams.insert(name, Arc::new(stream));
+            }
+        }
+
+        let streams = Arc::new(RwLock::new(streams));
+        let disk = Watchdog::new(config.dir.clone(), config.disk, sw.clone());
+        let disk_guard = disk.spawn(streams.clone(), &tt);
+
+        Ok(Self {
+            config,
+            tt,
+            sw,
+            streams,
+            disk,
+            _disk: disk_guard,
+        })
+    }
+
+    pub fn stream(&self, name: &str) -> Result<Arc<Stream>, Error> {
+        self.streams
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::StreamNotFound(name.to_string()))
+    }
+
+    pub fn definition(&self, name: &str) -> Result<StreamDefinition, Error> {
+        Ok(self.stream(name)?.definition().clone())
+    }
+
+    pub fn put_stream(
+        &self,
+        name: &str,
+        mut definition: StreamDefinition,
+    ) -> Result<StreamDefinition, Error> {
+        if !Stream::is_valid_name(name) {
+            Err(Error::InvalidStreamName(name.to_string()))?;
+        }
+
+        definition.validate()?;
+
+        if definition.tiering.after.is_some() && self.config.remote.is_none() {
+            Err(Error::InvalidDefinition(
+                "tiering requires an object store".into(),
+            ))?;
+        }
+
+        let dir = self.config.dir.join(name);
+        let mut streams = self.streams.write().unwrap();
+
+        definition.version = streams.get(name).map_or(1, |v| v.definition().version + 1);
+
+        std::fs::create_dir_all(&dir)?;
+        definition.save(&dir)?;
+
+        let shared = match streams.get(name) {
+            Some(v) => v.shared(),
+            None => Shared::open(name, &dir, &self.config)?,
+        };
+
+        let stream = Stream::new(
+            name.to_string(),
+            definition.clone(),
+            &self.config,
+            shared,
+            &self.tt,
+            &self.sw,
+        );
+        streams.insert(name.to_string(), Arc::new(stream));
+
+        Ok(definition)
+    }
+
+    pub async fn insert(
+        &self,
+        name: &str,
+        rows: Rows,
+        origin: &Origin,
+        idempotency_key: Option<&str>,
+    ) -> Result<Inserted, Error> {
+        if self.disk.is_full() {
+            Err(Error::DiskFull)?;
+        }
+
+        let result = self
+            .stream(name)?
+            .insert(rows, origin, idempotency_key)
+            .await;
+
+        match &result {
+            Err(e) if e.retry_after().is_some() => {
+                self.sw.set_ingest_backpressure(name, Some(e.to_string()))
+            }
+            _ => self.sw.set_ingest_backpressure(name, None),
+        }
+
+        result
+    }
+
+    pub fn retention(&self, name: &str) -> Result<RetentionReport, Error> {
+        Ok(self.stream(name)?.retention())
+    }
+
+    pub fn scrub(&self, name: &str) -> Result<ScrubReport, Error> {
+        Ok(self.stream(name)?.scrub())
+    }
+
+    /// Scrub reports of all streams.
+    pub fn scrub_reports(&self) -> BTreeMap<StreamName, ScrubReport> {
+        self.streams
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, stream)| (name.clone(), stream.scrub()))
+            .collect()
+    }
+
+    pub async fn verify_chain(&self, name: &str) -> Result<ChainReport, Error> {
+        self.stream(name)?.verify_chain().await
+    }
+
+    pub fn blocks(&self, name: &str, query: &Query) -> Result<Vec<BlockEntry>, Error> {
+        Ok(self.stream(name)?.blocks(query))
+    }
+
+    pub async fn query(&self, name: &str, query: Query) -> Result<Vec<JsonValue>, Error> {
+        self.stream(name)?.query(query).await
+    }
+
+    pub async fn query_dead_letter(
+        &self,
+        name: &str,
+        query: Query,
+    ) -> Result<Vec<JsonValue>, Error> {
+        self.stream(name)?.query_dead_letter(query).await
+    }
+
+    /// Answers a `Path::Replicate` request of another instance.
+    pub async fn replicate(&self, message: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let (header, _) = Message::decode(&message)?;
+        self.stream(header.stream())?.replicate(message).await
+    }
+
+    pub async fn create_snapshot(&self, name: &str) -> Result<SnapshotInfo, Error> {
+        self.stream(name)?.create_snapshot().await
+    }
+
+    pub async fn snapshots(&self, name: &str) -> Result<Vec<SnapshotInfo>, Error> {
+        self.stream(name)?.snapshots().await
+    }
+
+    pub async fn delete_snapshot(&self, name: &str, id: Uuid) -> Result<(), Error> {
+        self.stream(name)?.delete_snapshot(id).await
+    }
+
+    pub async fn export_snapshot(&self, name: &str, id: Uuid) -> Result<PathBuf, Error> {
+        self.stream(name)?.export_snapshot(id).await
+    }
+
+    /// Restores a snapshot archive into a stream, which is created with the
+    /// definition of the snapshot if it does not exist. An existing stream
+    /// must have the fields and time field of the snapshot. Like inserts, it
+    /// is rejected when the disk is full, and it stops once the upload or its
+    /// blocks would take the free space below the hard limit.
+    pub async fn restore(
+        &self,
+        name: &str,
+        archive: impl AsyncRead + Unpin,
+    ) -> Result<Restore, Error> {
+        if !Stream::is_valid_name(name) {
+            Err(Error::InvalidStreamName(name.to_string()))?;
+        }
+
+        if self.disk.is_full() {
+            Err(Error::DiskFull)?;
+        }
+
+        let path = self.config.dir.join(format!(".restore-{}", Uuid::now_v7()));
+        let result = match self.upload(archive, &path).await {
+            Ok(()) => self.restore_file(name, &path).await,
+            Err(e) => Err(e),
+        };
+
+        // The upload is left behind only if it cannot be removed.
+        tokio::fs::remove_file(&path).await.ok();
+        result
+    }
+
+    async fn upload(&self, mut archive: impl AsyncRead + Unpin, path: &Path) -> Result<(), Error> {
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut buf = vec![0; Self::UPLOAD_CHUNK];
+
+        loop {
+            let len = archive.read(&mut buf).await?;
+            if len == 0 {
+                // The last write may still be in flight until flushed.
+                file.flush().await?;
+                return Ok(());
+            }
+
+            if !self.disk.fits(len as u64) {
+                Err(Error::DiskFull)?;
+            }
+
+            file.write_all(&buf[..len]).await?;
+        }
+    }
+
+    async fn restore_file(&self, name: &str, path: &Path) -> Result<Restore, Error> {
+        let archive = path.to_path_buf();
+        let manifest = spawn_blocking(move || Snapshots::read_manifest(&archive))
+            .await
+            .unwrap()?;
+
+        if !self.disk.fits(manifest.info.bytes) {
+            Err(Error::DiskFull)?;
+        }
+
+        match self.stream(name) {
+            Ok(stream) => {
+                let definition = stream.definition();
+                if definition.fields != manifest.definition.fields
+                    || definition.time_field() != manifest.definition.time_field()
+                {
+                    Err(Error::InvalidArchive(format!(
+                        "fields or time field of the snapshot differ from those of {name}"
+                    )))?;
+                }
+            }
+            Err(_) => {
+                self.put_stream(name, manifest.definition)?;
+            }
+        }
+
+        self.stream(name)?.restore(path.to_path_buf()).await
+    }
+
+    pub fn keys(&self, name: &str) -> Result<Vec<KeyInfo>, Error> {
+        self.stream(name)?.keys()
+    }
+
+    pub fn rotate_key(&self, name: &str) -> Result<KeyInfo, Error> {
+        self.stream(name)?.rotate_key()
+    }
+
+    pub async fn shred(&self, name: &str) -> Result<Shred, Error> {
+        self.stream(name)?.shred().await
+    }
+
+    pub fn erase(
+        &self,
+        name: &str,
+        request: ErasureRequest,
+        origin: &Origin,
+    ) -> Result<Erasure, Error> {
+        self.stream(name)?.erase(request, origin, &self.tt)
+    }
+
+    pub fn erasures(&self, name: &str) -> Result<Vec<Erasure>, Error> {
+        Ok(self.stream(name)?.erasures())
+    }
+
+    pub fn erasure(&self, name: &str, id: Uuid) -> Result<Erasure, Error> {
+        self.stream(name)?.erasure(id)
+    }
+
+    pub async fn replay_dead_letter(&self, name: &str, origin: &Origin) -> Result<Replay, Error> {
+        self.stream(name)?.replay_dead_letter(origin).await
     }
 }
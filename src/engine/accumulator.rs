@@ -1,42 +1,55 @@
-use crate::schema::DomainField;
+use crate::engine::block;
+use crate::engine::block::Provenance;
+use crate::engine::catalog::Catalog;
+use crate::engine::decode::Decoder;
+use crate::engine::partition;
+use crate::engine::partition::Hour;
+use crate::engine::pipeline::Pipeline;
+use crate::engine::schema::DomainField;
+use crate::picodata::service::ServiceWarnings;
 use arrow::array::ArrayBuilder;
-use arrow::array::BooleanBuilder;
-use arrow::array::Float64Builder;
-use arrow::array::Int64Builder;
-use arrow::array::ListBuilder;
 use arrow::array::RecordBatch;
 use arrow::array::StringBuilder;
-use arrow::datatypes::DataType;
+use arrow::array::TimestampNanosecondBuilder;
 use arrow::datatypes::Schema;
 use arrow::datatypes::SchemaRef;
-use parquet::arrow::ArrowWriter;
-use parquet::basic::Compression;
+use arrow::error::ArrowError;
 use parquet::errors::ParquetError;
 use parquet::file::properties::WriterProperties;
-use parquet::format::FileMetaData;
+use serde::Serialize;
+use serde_json::value::RawValue;
 use serde_json::Value as JsonValue;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
 use std::time::Duration;
+use std::time::Instant;
 use thiserror::Error;
+use time::OffsetDateTime;
 use tokio::select;
+use tokio::spawn;
 use tokio::sync::mpsc::channel;
-use tokio::sync::mpsc::error;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Permit;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot;
+use tokio::sync::Notify;
 use tokio::task::spawn_blocking;
 use tokio::time::interval;
-use tokio::time::Interval;
-use tokio_util::sync::CancellationToken;
+use tokio::time::sleep;
 use tokio_util::task::TaskTracker;
 use uuid::Uuid;
 
+#[cfg(feature = "bench")]
+pub mod bench;
+
 type FieldName = String;
 type Builders = Vec<Box<dyn ArrayBuilder>>;
-type FailedRows = Vec<JsonValue>;
+pub type FailedRows = Vec<FailedRow>;
 type BlockId = Uuid;
 
 #[derive(Debug, Error)]
@@ -51,20 +64,106 @@ pub enum Error {
     TypeMissmatch(FieldName),
     #[error("parquet error: {0}")]
     Parquet(#[from] ParquetError),
+    #[error("arrow error: {0}")]
+    Arrow(#[from] ArrowError),
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("block error: {0}")]
+    Block(#[from] block::Error),
+    #[error("ingest queue is full")]
+    QueueFull(Duration),
+    #[error("memory budget is exceeded")]
+    MemoryBudgetExceeded(Duration),
+    #[error("accumulator is stopped")]
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedRow {
+    pub row: JsonValue,
+    pub reason: String,
+}
+
+/// Where and when a batch of rows was received.
+#[derive(Debug, Clone)]
+pub struct Origin {
+    /// Client certificate subject, or the network address without mTLS.
+    pub client: Option<String>,
+    /// Network address of the client.
+    pub peer: Option<String>,
+    pub received_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Inputs waiting for the worker.
+    pub queue_depth: usize,
+    /// Approximate bytes of queued and not yet flushed rows.
+    pub memory_budget: usize,
 }
 
 pub struct Input {
     rows: Rows,
+    origin: Origin,
+    size: usize,
     tx: oneshot::Sender<Result<FailedRows, Error>>,
 }
+/// Room taken in an accumulator for rows of a known size.
+pub struct Reserved<'a> {
+    permit: Option<Permit<'a, Input>>,
+    pressure: &'a Pressure,
+    size: usize,
+}
+
 pub enum Rows {
     Json(Vec<JsonValue>),
+    /// Rows decoded straight into the column builders when the stream has no
+    /// pipeline to run on them.
+    Raw(Vec<Box<RawValue>>),
 }
 
 pub struct Accumulator {
     tx: Sender<Input>,
+    limits: Limits,
+    pressure: Arc<Pressure>,
+}
+
+#[derive(Debug)]
+struct Pressure {
+    memory: Arc<MemoryBudget>,
+    queued: AtomicUsize,
+    buffered: AtomicUsize,
+    flushing: AtomicUsize,
+    last_flush: Mutex<Option<Instant>>,
+    /// Asks the worker to flush before its buffers are full.
+    flush: Notify,
+}
+
+/// Bytes of rows held by the accumulators of all streams. Past a threshold
+/// the largest buffer is flushed early, past the limit ingest is throttled.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    pressures: Mutex<Vec<Weak<Pressure>>>,
+}
+
+/// Where and how flushed blocks are written.
+#[derive(Clone)]
+pub struct Writer {
+    props: WriterProperties,
+    catalog: Arc<Catalog>,
+    provenance: Provenance,
+    /// Flush errors are reported as failures of this job.
+    job: &'static str,
+    sw: ServiceWarnings,
+}
+
+/// A finished batch on its way to disk with the bytes it holds of the
+/// memory budget.
+struct Flush {
+    batch: RecordBatch,
+    size: usize,
 }
 
 struct ParquetBuilder {
@@ -72,23 +171,203 @@ struct ParquetBuilder {
     fields: Vec<Box<dyn ArrayBuilder>>,
 }
 
-impl Accumulator {
-    const MAX_ROWS: usize = 8192;
+impl Writer {
+    pub fn new(
+        props: WriterProperties,
+        catalog: Arc<Catalog>,
+        job: &'static str,
+        sw: ServiceWarnings,
+    ) -> Self {
+        Self {
+            props,
+            catalog,
+            provenance: Provenance::default(),
+            job,
+            sw,
+        }
+    }
 
-    pub fn new(schema: Arc<Schema>, tt: &TaskTracker, dir: PathBuf) -> Self {
-        let (tx, rx) = channel(1);
-        tt.spawn(Self::worker(rx, schema, dir));
-        Self { tx }
+    /// Writes the rows of an hour as a block, sorted by time so that
+    /// compaction can merge blocks without reading them whole. A block that
+    /// could not be written is removed, so the rows can be written again.
+    fn write(&self, hour: Hour, batch: &RecordBatch) -> Result<(), Error> {
+        // Keeps retention from removing the partition underneath.
+        let _guard = self.catalog.lock_read();
+        let partition = hour.dir(self.catalog.dir());
+        std::fs::create_dir_all(&partition)?;
+        let path = block::path(&partition, Uuid::now_v7());
+
+        let result = partition::sort(batch)
+            .map_err(Error::from)
+            .and_then(|batch| {
+                block::write(
+                    &path,
+                    &batch,
+                    self.props.clone(),
+                    &self.provenance,
+                    self.catalog.keys(),
+                )?;
+                Ok(self.catalog.add(self.catalog.describe(&path)?)?)
+            });
+
+        if result.is_err() {
+            std::fs::remove_file(&path).ok();
+        }
+        result
     }
 
-    pub async fn add_rows(&self, rows: Rows) -> Result<FailedRows, Error> {
+    fn report(&self, error: Option<String>) {
+        self.sw
+            .set_job_error(self.job, self.catalog.stream(), error);
+    }
+}
+
+impl Reserved<'_> {
+    /// Queues the rows the room was taken for.
+    pub async fn add_rows(mut self, rows: Rows, origin: &Origin) -> Result<FailedRows, Error> {
         let (tx, rx) = oneshot::channel();
-        self.tx.send(Input { rows, tx }).await.unwrap();
-        rx.await.unwrap()
+        self.permit.take().unwrap().send(Input {
+            rows,
+            origin: origin.clone(),
+            size: self.size,
+            tx,
+        });
+
+        rx.await.map_err(|_| Error::Stopped)?
     }
+}
+
+impl Drop for Reserved<'_> {
+    fn drop(&mut self) {
+        if self.permit.is_some() {
+            self.pressure.release(self.size);
+        }
+    }
+}
+
+impl Rows {
+    /// Rough in-memory size, good enough for accounting.
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Json(values) => values.iter().map(json_size).sum(),
+            Self::Raw(values) => values.iter().map(|v| v.get().len()).sum(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Json(values) => values.len(),
+            Self::Raw(values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_json(self) -> Vec<JsonValue> {
+        match self {
+            Self::Json(values) => values,
+            Self::Raw(values) => values.iter().map(|v| raw_to_json(v)).collect(),
+        }
+    }
+}
+
+fn raw_to_json(value: &RawValue) -> JsonValue {
+    // Raw values are checked to be valid JSON when they are read.
+    serde_json::from_str(value.get()).unwrap()
+}
+
+fn json_size(value: &JsonValue) -> usize {
+    match value {
+        JsonValue::String(v) => v.len() + 8,
+        JsonValue::Array(v) => v.iter().map(json_size).sum::<usize>() + 8,
+        JsonValue::Object(v) => v.iter().map(|(k, v)| k.len() + json_size(v)).sum::<usize>() + 8,
+        _ => 8,
+    }
+}
+
+impl Accumulator {
+    pub const MAX_ROWS: usize = 8192;
+    pub const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+    /// Batches waiting to be written while rows go to fresh builders.
+    const FLUSH_QUEUE_DEPTH: usize = 2;
+    const RETRY_DELAY: Duration = Duration::from_millis(500);
+    const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+    const STOPPING_ATTEMPTS: usize = 3;
 
-    async fn worker(mut rx: Receiver<Input>, schema: Arc<Schema>, dir: PathBuf) {
-        let mut ticker = interval(Duration::from_secs(10));
+    pub fn new(
+        schema: Arc<Schema>,
+        pipeline: Pipeline,
+        limits: Limits,
+        instance: Arc<str>,
+        memory: Arc<MemoryBudget>,
+        mut writer: Writer,
+        tt: &TaskTracker,
+    ) -> Self {
+        let (tx, rx) = channel(limits.queue_depth.max(1));
+        let pressure = Pressure::new(memory);
+        writer.provenance = Provenance {
+            stream: writer.catalog.stream().to_string(),
+            instance: instance.to_string(),
+            pipeline: serde_json::to_string(&pipeline).unwrap(),
+        };
+        tt.spawn(Self::worker(
+            rx,
+            schema,
+            pipeline,
+            instance,
+            pressure.clone(),
+            writer,
+        ));
+        Self {
+            tx,
+            limits,
+            pressure,
+        }
+    }
+
+    /// Queues rows without waiting for room: a full queue or an exhausted
+    /// memory budget is reported back with an estimate of when to retry.
+    pub async fn add_rows(&self, rows: Rows, origin: &Origin) -> Result<FailedRows, Error> {
+        self.reserve(rows.size())?.add_rows(rows, origin).await
+    }
+
+    /// Takes room for rows of the size in the queue and the memory budget
+    /// without waiting, so that rows split over several accumulators are
+    /// queued in all of them or in none. Dropping the room gives it back.
+    pub fn reserve(&self, size: usize) -> Result<Reserved<'_>, Error> {
+        self.pressure.reserve(size, self.limits.memory_budget)?;
+
+        match self.tx.try_reserve() {
+            Ok(permit) => Ok(Reserved {
+                permit: Some(permit),
+                pressure: &self.pressure,
+                size,
+            }),
+            Err(e) => {
+                self.pressure.release(size);
+                match e {
+                    TrySendError::Full(_) => Err(Error::QueueFull(self.pressure.retry_after())),
+                    TrySendError::Closed(_) => Err(Error::Stopped),
+                }
+            }
+        }
+    }
+
+    async fn worker(
+        mut rx: Receiver<Input>,
+        schema: Arc<Schema>,
+        pipeline: Pipeline,
+        instance: Arc<str>,
+        pressure: Arc<Pressure>,
+        writer: Writer,
+    ) {
+        let (flush_tx, flush_rx) = channel(Self::FLUSH_QUEUE_DEPTH);
+        let reporter = writer.clone();
+        let flusher = spawn(Self::flusher(flush_rx, pressure.clone(), writer));
+        let decoder = Decoder::new(&schema);
+        let mut ticker = interval(Self::FLUSH_INTERVAL);
         let mut rows_count: usize = 0;
         let mut builders = schema
             .fields()
@@ -96,154 +375,729 @@ impl Accumulator {
             .map(|f| f.builder())
             .collect::<Builders>();
 
-        if rows_count >= Self::MAX_ROWS {
-            Self::flush(schema, &mut builders, &dir);
-            ticker.reset();
-            rows_count = 0;
-        }
-
         loop {
             select! {
                 _ = ticker.tick() => {
-                    Self::flush(schema.clone(), &mut builders, &dir).await;
+                    if rows_count > 0 {
+                        Self::hand_off(&schema, &mut builders, &pressure, &flush_tx, &reporter).await;
+                        rows_count = 0;
+                    } else {
+                        pressure.flushed(pressure.hand_off());
+                    }
+                }
+
+                _ = pressure.flush.notified() => {
+                    if rows_count > 0 {
+                        Self::hand_off(&schema, &mut builders, &pressure, &flush_tx, &reporter).await;
+                        ticker.reset();
+                        rows_count = 0;
+                    }
                 }
 
                 input = rx.recv() => {
-                    match input {
-                        None => return,
-                        Some(input) => Self::_add_rows(&mut builders, input)
+                    let Some(input) = input else {
+                        break;
+                    };
+
+                    Self::_add_rows(
+                        &decoder,
+                        &pipeline,
+                        &instance,
+                        &pressure,
+                        &mut builders,
+                        input,
+                        &mut rows_count,
+                    );
+
+                    if rows_count >= Self::MAX_ROWS {
+                        Self::hand_off(&schema, &mut builders, &pressure, &flush_tx, &reporter).await;
+                        ticker.reset();
+                        rows_count = 0;
                     }
                 }
             }
         }
+
+        if rows_count > 0 {
+            Self::hand_off(&schema, &mut builders, &pressure, &flush_tx, &reporter).await;
+        }
+
+        drop(flush_tx);
+        flusher.await.unwrap();
     }
 
-    async fn flush(schema: SchemaRef, builders: &mut Builders, dir: &PathBuf) -> Result<(), Error> {
-        let block_id = Uuid::now_v7();
-        let batch = Self::get_batch(schema, builders);
-        let file_path = dir.join(block_id.to_string());
-        let props = WriterProperties::builder()
-            .set_created_by(String::new())
-            .set_compression(Compression::LZ4_RAW)
-            .build();
-
-        spawn_blocking(move || {
-            let file = OpenOptions::new()
-                .create_new(true)
-                .write(true)
-                .open(file_path)?;
-            let mut writer = ArrowWriter::try_new(&file, batch.schema(), Some(props)).unwrap();
-            writer.write(&batch)?;
-            writer.close()?;
-            file.sync_all()
-        })
-        .await
-        .unwrap()?;
+    /// Finishes the builders, which leaves them empty for the next rows, and
+    /// queues the batch for writing. Waits only while the flush queue is full.
+    async fn hand_off(
+        schema: &SchemaRef,
+        builders: &mut Builders,
+        pressure: &Pressure,
+        tx: &Sender<Flush>,
+        writer: &Writer,
+    ) {
+        let batch = Self::get_batch(schema.clone(), builders);
+        let size = pressure.hand_off();
 
-        Ok(())
+        if let Err(SendError(flush)) = tx.send(Flush { batch, size }).await {
+            pressure.flushed(flush.size);
+            writer.report(Some(format!(
+                "flusher stopped, {} rows were not written",
+                flush.batch.num_rows()
+            )));
+        }
+    }
+
+    /// Writes queued batches one by one, so blocks keep the order of rows.
+    /// Failed writes are retried, their rows holding on to their memory so
+    /// that ingest slows down until the disk takes writes again.
+    async fn flusher(mut rx: Receiver<Flush>, pressure: Arc<Pressure>, writer: Writer) {
+        while let Some(Flush { batch, size }) = rx.recv().await {
+            let parts = match partition::split(&batch) {
+                Ok(v) => v,
+                Err(e) => {
+                    pressure.flushed(size);
+                    writer.report(Some(e.to_string()));
+                    continue;
+                }
+            };
+
+            let rows = batch.num_rows().max(1);
+            let mut left = size;
+            for (i, (hour, part)) in parts.iter().enumerate() {
+                let part_size = match i + 1 == parts.len() {
+                    true => left,
+                    false => size * part.num_rows() / rows,
+                };
+
+                Self::write_part(&writer, &rx, *hour, part).await;
+                pressure.flushed(part_size);
+                left -= part_size;
+            }
+        }
+    }
+
+    /// Writes the rows of an hour until it succeeds. Once the accumulator is
+    /// stopping, the rows are given up after a few attempts.
+    async fn write_part(writer: &Writer, rx: &Receiver<Flush>, hour: Hour, part: &RecordBatch) {
+        let mut delay = Self::RETRY_DELAY;
+        for attempt in 1.. {
+            let w = writer.clone();
+            let batch = part.clone();
+            let result = match spawn_blocking(move || w.write(hour, &batch)).await {
+                Ok(v) => v.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+
+            let Err(e) = result else {
+                writer.report(None);
+                return;
+            };
+
+            if rx.is_closed() && attempt >= Self::STOPPING_ATTEMPTS {
+                writer.report(Some(format!(
+                    "{e}, {} rows were not written",
+                    part.num_rows()
+                )));
+                return;
+            }
+
+            writer.report(Some(e));
+            sleep(delay).await;
+            delay = (delay * 2).min(Self::MAX_RETRY_DELAY);
+        }
     }
 
     fn get_batch(schema: SchemaRef, builders: &mut Builders) -> RecordBatch {
         RecordBatch::try_new(schema, builders.iter_mut().map(|v| v.finish()).collect()).unwrap()
     }
 
-    fn _add_rows(builders: &mut Builders, input: Input) {}
+    fn _add_rows(
+        decoder: &Decoder,
+        pipeline: &Pipeline,
+        instance: &str,
+        pressure: &Pressure,
+        builders: &mut Builders,
+        input: Input,
+        rows: &mut usize,
+    ) {
+        let system = System {
+            instance,
+            origin: &input.origin,
+        };
 
-    fn add_rows_json(builders: &mut Builders, values: Vec<JsonValue>, rows: &mut usize) {}
+        let buffered = *rows;
+        let failed = match input.rows {
+            Rows::Raw(values) if pipeline.is_empty() => {
+                Self::add_rows_raw(decoder, &system, builders, values, rows)
+            }
+            v => Self::add_rows_json(decoder, pipeline, &system, builders, v.into_json(), rows),
+        };
 
-    fn add_row_json(
-        schema: &Schema,
+        // No flush would release the bytes of an input with every row rejected.
+        match *rows > buffered {
+            true => pressure.buffer(input.size),
+            false => pressure.release(input.size),
+        }
+        input.tx.send(Ok(failed)).ok();
+    }
+
+    fn add_rows_json(
+        decoder: &Decoder,
+        pipeline: &Pipeline,
+        system: &System,
         builders: &mut Builders,
-        value: &JsonValue,
-    ) -> Result<(), Error> {
-        if !value.is_object() {
-            Err(Error::ObjectExpected)?;
+        values: Vec<JsonValue>,
+        rows: &mut usize,
+    ) -> FailedRows {
+        let mut failed = FailedRows::new();
+
+        for mut value in values {
+            let original = (!pipeline.is_empty()).then(|| value.clone());
+            pipeline.apply(&mut value);
+
+            match decoder.decode_value(&value, builders) {
+                Ok(()) => {
+                    system.append(builders);
+                    *rows += 1;
+                }
+                Err(e) => failed.push(FailedRow {
+                    row: original.unwrap_or(value),
+                    reason: e.to_string(),
+                }),
+            }
         }
 
-        for (f, b) in schema.fields().iter().zip(builders.iter_mut()) {
-            let v = match value.get(f.name()) {
-                Some(v) => v,
-                None if !f.is_nullable() => Err(Error::MissingField(f.name().clone()))?,
-                None => return Ok(f.append_null(b)),
-            };
+        failed
+    }
 
-            let b = b.as_any_mut();
+    fn add_rows_raw(
+        decoder: &Decoder,
+        system: &System,
+        builders: &mut Builders,
+        values: Vec<Box<RawValue>>,
+        rows: &mut usize,
+    ) -> FailedRows {
+        let mut failed = FailedRows::new();
 
-            match f.data_type() {
-                DataType::Utf8 => b.downcast_mut::<StringBuilder>().unwrap().append_value(
-                    v.as_str()
-                        .ok_or_else(|| Error::TypeMissmatch(f.name().clone()))?,
-                ),
+        for value in values {
+            match decoder.decode(&value, builders) {
+                Ok(()) => {
+                    system.append(builders);
+                    *rows += 1;
+                }
+                Err(e) => failed.push(FailedRow {
+                    row: raw_to_json(&value),
+                    reason: e.to_string(),
+                }),
+            }
+        }
 
-                DataType::Int64 => b.downcast_mut::<Int64Builder>().unwrap().append_value(
-                    v.as_i64()
-                        .ok_or_else(|| Error::TypeMissmatch(f.name().clone()))?,
-                ),
+        failed
+    }
+}
 
-                DataType::Float64 => b.downcast_mut::<Float64Builder>().unwrap().append_value(
-                    v.as_f64()
-                        .ok_or_else(|| Error::TypeMissmatch(f.name().clone()))?,
-                ),
+/// Values of the system fields, which lead every stream schema.
+struct System<'a> {
+    instance: &'a str,
+    origin: &'a Origin,
+}
 
-                DataType::Boolean => b.downcast_mut::<BooleanBuilder>().unwrap().append_value(
-                    v.as_bool()
-                        .ok_or_else(|| Error::TypeMissmatch(f.name().clone()))?,
-                ),
+impl System<'_> {
+    fn append(&self, builders: &mut Builders) {
+        let received_at = self.origin.received_at.unix_timestamp_nanos() as i64;
+        let [id, at, instance, client, peer, ..] = builders.as_mut_slice() else {
+            unreachable!();
+        };
 
-                DataType::List(nested) => {
-                    let array = v
-                        .as_array()
-                        .ok_or_else(|| Error::TypeMissmatch(f.name().clone()))?;
-
-                    match nested.data_type() {
-                        DataType::Utf8 => {
-                            if !array.iter().all(|v| v.is_string()) {
-                                Err(Error::HomogeneousArrayExpected(f.name().clone()))?;
-                            }
-
-                            b.downcast_mut::<ListBuilder<StringBuilder>>()
-                                .unwrap()
-                                .append_value(array.iter().map(|v| Some(v.as_str().unwrap())))
-                        }
-
-                        DataType::Int64 => {
-                            if !array.iter().all(|v| v.is_i64()) {
-                                Err(Error::HomogeneousArrayExpected(f.name().clone()))?;
-                            }
-
-                            b.downcast_mut::<ListBuilder<Int64Builder>>()
-                                .unwrap()
-                                .append_value(array.iter().map(|v| Some(v.as_i64().unwrap())))
-                        }
-
-                        DataType::Float64 => {
-                            if !array.iter().all(|v| v.is_f64()) {
-                                Err(Error::HomogeneousArrayExpected(f.name().clone()))?;
-                            }
-
-                            b.downcast_mut::<ListBuilder<Float64Builder>>()
-                                .unwrap()
-                                .append_value(array.iter().map(|v| Some(v.as_f64().unwrap())))
-                        }
-
-                        DataType::Boolean => {
-                            if !array.iter().all(|v| v.is_boolean()) {
-                                Err(Error::HomogeneousArrayExpected(f.name().clone()))?;
-                            }
-
-                            b.downcast_mut::<ListBuilder<BooleanBuilder>>()
-                                .unwrap()
-                                .append_value(array.iter().map(|v| Some(v.as_bool().unwrap())))
-                        }
-
-                        _ => unreachable!(),
-                    }
-                }
+        Self::string(id).append_value(Uuid::now_v7().to_string());
+        at.as_any_mut()
+            .downcast_mut::<TimestampNanosecondBuilder>()
+            .unwrap()
+            .append_value(received_at);
+        Self::string(instance).append_value(self.instance);
+        Self::string(client).append_option(self.origin.client.as_deref());
+        Self::string(peer).append_option(self.origin.peer.as_deref());
+    }
 
-                _ => unreachable!(),
-            }
+    fn string(builder: &mut Box<dyn ArrayBuilder>) -> &mut StringBuilder {
+        builder.as_any_mut().downcast_mut().unwrap()
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicUsize::new(0),
+            pressures: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn register(&self, pressure: &Arc<Pressure>) {
+        let mut pressures = self.pressures.lock().unwrap();
+        pressures.retain(|v| v.strong_count() > 0);
+        pressures.push(Arc::downgrade(pressure));
+    }
+
+    fn reserve(&self, size: usize) -> bool {
+        let used = self.used.fetch_add(size, Ordering::Relaxed);
+
+        if used + size > self.limit / 5 * 4 {
+            self.flush_largest();
+        }
+
+        // As with a stream's budget, a lone batch is always let through.
+        if used > 0 && used + size > self.limit {
+            self.release(size);
+            return false;
+        }
+
+        true
+    }
+
+    fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn flush_largest(&self) {
+        let largest = self
+            .pressures
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|v| (v.buffered.load(Ordering::Relaxed), v))
+            .filter(|(buffered, _)| *buffered > 0)
+            .max_by_key(|(buffered, _)| *buffered);
+
+        if let Some((_, pressure)) = largest {
+            pressure.flush.notify_one();
+        }
+    }
+}
+
+impl Pressure {
+    const MIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+    fn new(memory: Arc<MemoryBudget>) -> Arc<Self> {
+        let pressure = Arc::new(Self {
+            memory: memory.clone(),
+            queued: AtomicUsize::new(0),
+            buffered: AtomicUsize::new(0),
+            flushing: AtomicUsize::new(0),
+            last_flush: Mutex::new(None),
+            flush: Notify::new(),
+        });
+
+        memory.register(&pressure);
+        pressure
+    }
+
+    fn reserve(&self, size: usize, budget: usize) -> Result<(), Error> {
+        let queued = self.queued.fetch_add(size, Ordering::Relaxed);
+        let used =
+            queued + self.buffered.load(Ordering::Relaxed) + self.flushing.load(Ordering::Relaxed);
+
+        // A batch larger than the whole budget is still let through when
+        // nothing else is held, otherwise it could never be accepted.
+        if used > 0 && used + size > budget {
+            self.queued.fetch_sub(size, Ordering::Relaxed);
+            Err(Error::MemoryBudgetExceeded(self.retry_after()))?;
+        }
+
+        // The largest buffers are being flushed already, so room comes soon.
+        if !self.memory.reserve(size) {
+            self.queued.fetch_sub(size, Ordering::Relaxed);
+            Err(Error::MemoryBudgetExceeded(Self::MIN_RETRY_AFTER))?;
         }
 
         Ok(())
     }
+
+    /// Gives back the room of rows that were not queued.
+    fn release(&self, size: usize) {
+        self.queued.fetch_sub(size, Ordering::Relaxed);
+        self.memory.release(size);
+    }
+
+    fn buffer(&self, size: usize) {
+        self.queued.fetch_sub(size, Ordering::Relaxed);
+        self.buffered.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Moves the buffered bytes to the flush queue and returns them.
+    fn hand_off(&self) -> usize {
+        let size = self.buffered.swap(0, Ordering::Relaxed);
+        self.flushing.fetch_add(size, Ordering::Relaxed);
+        *self.last_flush.lock().unwrap() = Some(Instant::now());
+        size
+    }
+
+    fn flushed(&self, size: usize) {
+        self.flushing.fetch_sub(size, Ordering::Relaxed);
+        self.memory.release(size);
+    }
+
+    /// Time left until the next periodic flush frees the buffers.
+    fn retry_after(&self) -> Duration {
+        let elapsed = self
+            .last_flush
+            .lock()
+            .unwrap()
+            .map_or(Duration::ZERO, |v| v.elapsed());
+
+        Accumulator::FLUSH_INTERVAL
+            .saturating_sub(elapsed)
+            .max(Self::MIN_RETRY_AFTER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing::TempDir;
+    use crate::engine::partition::TimeRange;
+    use crate::engine::schema::build_schema;
+    use crate::engine::schema::FieldDefinition;
+    use crate::engine::schema::FieldType;
+    use crate::engine::schema::CLIENT;
+    use crate::engine::schema::INSTANCE;
+    use crate::engine::schema::PEER;
+    use crate::engine::schema::RECEIVED_AT;
+    use crate::engine::schema::RECORD_ID;
+    use futures::FutureExt;
+    use serde_json::json;
+
+    #[test]
+    fn pressure_throttles_past_the_budget() {
+        let memory = MemoryBudget::new(1000);
+        let pressure = Pressure::new(memory.clone());
+
+        // A lone batch is let through even when it exceeds the budget.
+        pressure.reserve(200, 100).unwrap();
+        assert!(matches!(
+            pressure.reserve(10, 100),
+            Err(Error::MemoryBudgetExceeded(v)) if v >= Pressure::MIN_RETRY_AFTER
+        ));
+
+        pressure.buffer(200);
+        let size = pressure.hand_off();
+        assert_eq!(size, 200);
+        pressure.flushed(size);
+        pressure.reserve(50, 100).unwrap();
+        pressure.release(50);
+        assert_eq!(memory.used.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn memory_budget_is_shared() {
+        let memory = MemoryBudget::new(100);
+        let a = Pressure::new(memory.clone());
+        let b = Pressure::new(memory.clone());
+
+        a.reserve(80, 1000).unwrap();
+        assert!(matches!(
+            b.reserve(30, 1000),
+            Err(Error::MemoryBudgetExceeded(_))
+        ));
+        a.release(80);
+        b.reserve(30, 1000).unwrap();
+    }
+
+    #[test]
+    fn largest_buffer_is_flushed_near_the_budget() {
+        let memory = MemoryBudget::new(100);
+        let a = Pressure::new(memory.clone());
+        let b = Pressure::new(memory.clone());
+        let c = Pressure::new(memory.clone());
+
+        a.reserve(50, 1000).unwrap();
+        a.buffer(50);
+        b.reserve(20, 1000).unwrap();
+        b.buffer(20);
+        assert!(a.flush.notified().now_or_never().is_none());
+
+        c.reserve(20, 1000).unwrap();
+        assert!(a.flush.notified().now_or_never().is_some());
+        assert!(b.flush.notified().now_or_never().is_none());
+
+        // Room comes back once the handed off rows are written.
+        let size = a.hand_off();
+        assert!(c.reserve(20, 1000).is_err());
+        a.flushed(size);
+        c.reserve(20, 1000).unwrap();
+    }
+
+    #[test]
+    fn failed_rows_keep_the_original_row() {
+        let fields = [FieldDefinition {
+            name: "n".into(),
+            kind: FieldType::I64,
+            nullable: false,
+        }];
+        let schema = build_schema(&fields, RECEIVED_AT, 1);
+        let pipeline: Pipeline = serde_json::from_value(json!([
+            {"step": "rename", "from": "m", "to": "n"},
+            {"step": "set", "field": "tag", "value": "x"}
+        ]))
+        .unwrap();
+        let origin = Origin {
+            client: None,
+            peer: None,
+            received_at: OffsetDateTime::now_utc(),
+        };
+        let system = System {
+            instance: "i1",
+            origin: &origin,
+        };
+        let mut builders = schema
+            .fields()
+            .iter()
+            .map(|f| f.builder())
+            .collect::<Builders>();
+        let mut rows = 0;
+
+        let failed = Accumulator::add_rows_json(
+            &Decoder::new(&schema),
+            &pipeline,
+            &system,
+            &mut builders,
+            vec![json!({"m": 1}), json!({"m": "a"})],
+            &mut rows,
+        );
+
+        assert_eq!(rows, 1);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].row, json!({"m": "a"}));
+    }
+
+    #[test]
+    fn system_fields_are_filled() {
+        let schema = build_schema(&[], RECEIVED_AT, 1);
+        let origin = Origin {
+            client: Some("CN=agent".into()),
+            peer: Some("10.0.0.1:5000".into()),
+            received_at: OffsetDateTime::now_utc(),
+        };
+        let system = System {
+            instance: "i1",
+            origin: &origin,
+        };
+        let mut builders = schema
+            .fields()
+            .iter()
+            .map(|f| f.builder())
+            .collect::<Builders>();
+        let mut rows = 0;
+
+        Accumulator::add_rows_json(
+            &Decoder::new(&schema),
+            &Pipeline::default(),
+            &system,
+            &mut builders,
+            vec![json!({})],
+            &mut rows,
+        );
+
+        let row = block::to_json_rows(&Accumulator::get_batch(schema, &mut builders)).remove(0);
+        assert_eq!(row[INSTANCE], "i1");
+        assert_eq!(row[CLIENT], "CN=agent");
+        assert_eq!(row[PEER], "10.0.0.1:5000");
+        assert!(row[RECORD_ID].is_string());
+    }
+
+    #[test]
+    fn raw_and_json_rows_decode_alike() {
+        let field = |name: &str, kind, nullable| FieldDefinition {
+            name: name.into(),
+            kind,
+            nullable,
+        };
+        let schema = build_schema(
+            &[
+                field("s", FieldType::String, false),
+                field("i", FieldType::I64, true),
+                field("f", FieldType::F64, true),
+                field("b", FieldType::Bool, true),
+                field("t", FieldType::Timestamp, true),
+                field("v", FieldType::VecI64, true),
+            ],
+            RECEIVED_AT,
+            1,
+        );
+        let rows = [
+            r#"{"s": "a", "i": 1, "f": 2, "b": true, "t": "2024-01-01T00:00:00Z", "v": [1, 2]}"#,
+            r#"{"s": "esc\"aped\u00e9", "f": 1.5, "x": {"nested": [1]}}"#,
+            r#"{"s": "first", "s": "last", "i": null}"#,
+            r#"{"s": "big", "i": 18446744073709551615}"#,
+            r#"{"s": "float", "i": 1.5}"#,
+            r#"{"s": "time", "t": "yesterday"}"#,
+            r#"{"s": "mixed", "v": [1, "2"]}"#,
+            r#"{"s": "scalar", "v": 1}"#,
+            r#"{"i": 1}"#,
+            r#"{"s": 1}"#,
+            r#"[1, 2]"#,
+            r#""row""#,
+        ];
+
+        let origin = Origin {
+            client: None,
+            peer: None,
+            received_at: OffsetDateTime::UNIX_EPOCH,
+        };
+        let system = System {
+            instance: "i1",
+            origin: &origin,
+        };
+        let decode = |raw: bool| {
+            let mut builders = schema
+                .fields()
+                .iter()
+                .map(|f| f.builder())
+                .collect::<Builders>();
+            let mut count = 0;
+            let failed = match raw {
+                true => Accumulator::add_rows_raw(
+                    &Decoder::new(&schema),
+                    &system,
+                    &mut builders,
+                    rows.iter()
+                        .map(|v| RawValue::from_string(v.to_string()).unwrap())
+                        .collect(),
+                    &mut count,
+                ),
+                false => Accumulator::add_rows_json(
+                    &Decoder::new(&schema),
+                    &Pipeline::default(),
+                    &system,
+                    &mut builders,
+                    rows.iter()
+                        .map(|v| serde_json::from_str(v).unwrap())
+                        .collect(),
+                    &mut count,
+                ),
+            };
+            let batch = Accumulator::get_batch(schema.clone(), &mut builders);
+            let failed = failed
+                .into_iter()
+                .map(|v| (v.row, v.reason))
+                .collect::<Vec<_>>();
+
+            (
+                count,
+                batch
+                    .project(&(1..batch.num_columns()).collect::<Vec<_>>())
+                    .unwrap(),
+                failed,
+            )
+        };
+
+        let (count, batch, failed) = decode(true);
+        assert_eq!(count, 3);
+        assert_eq!(failed.len(), rows.len() - 3);
+        assert_eq!((count, batch, failed), decode(false));
+    }
+
+    #[tokio::test]
+    async fn rejected_inputs_release_their_memory() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let writer = Writer::new(
+            block::properties().build(),
+            catalog,
+            "flush",
+            ServiceWarnings::default(),
+        );
+        let fields = [FieldDefinition {
+            name: "n".into(),
+            kind: FieldType::I64,
+            nullable: false,
+        }];
+        let limits = Limits {
+            queue_depth: 1,
+            memory_budget: 1000,
+        };
+        let memory = MemoryBudget::new(1000);
+        let tt = TaskTracker::new();
+        let accumulator = Accumulator::new(
+            build_schema(&fields, RECEIVED_AT, 1),
+            Pipeline::default(),
+            limits,
+            "i1".into(),
+            memory.clone(),
+            writer,
+            &tt,
+        );
+        let origin = Origin {
+            client: None,
+            peer: None,
+            received_at: OffsetDateTime::UNIX_EPOCH,
+        };
+
+        // More than the budget in total, so leaked bytes would throttle ingest.
+        for _ in 0..100 {
+            let rows = Rows::Json(vec![json!({"n": "a"}), json!({})]);
+            let failed = accumulator.add_rows(rows, &origin).await.unwrap();
+            assert_eq!(failed.len(), 2);
+            assert_eq!(memory.used.load(Ordering::Relaxed), 0);
+        }
+
+        drop(accumulator);
+        tt.close();
+        tt.wait().await;
+    }
+
+    #[tokio::test]
+    async fn failed_flushes_are_retried() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let writer = Writer::new(
+            block::properties().build(),
+            catalog.clone(),
+            "flush",
+            ServiceWarnings::default(),
+        );
+        let schema = build_schema(&[], RECEIVED_AT, 1);
+        let origin = Origin {
+            client: None,
+            peer: None,
+            received_at: OffsetDateTime::UNIX_EPOCH,
+        };
+        let mut builders = schema
+            .fields()
+            .iter()
+            .map(|f| f.builder())
+            .collect::<Builders>();
+        let system = System {
+            instance: "i1",
+            origin: &origin,
+        };
+        system.append(&mut builders);
+        let batch = Accumulator::get_batch(schema, &mut builders);
+
+        // A file in place of the partition directory fails the write.
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1970"), b"").unwrap();
+
+        let memory = MemoryBudget::new(1000);
+        let pressure = Pressure::new(memory.clone());
+        pressure.reserve(100, 1000).unwrap();
+        pressure.buffer(100);
+        let size = pressure.hand_off();
+
+        let (tx, rx) = channel(1);
+        let flusher = spawn(Accumulator::flusher(rx, pressure.clone(), writer));
+        tx.send(Flush { batch, size }).await.unwrap();
+
+        sleep(Accumulator::RETRY_DELAY * 2).await;
+        assert!(catalog.blocks(&TimeRange::default(), None).is_empty());
+        assert_eq!(memory.used.load(Ordering::Relaxed), 100);
+
+        std::fs::remove_file(dir.join("1970")).unwrap();
+        drop(tx);
+        flusher.await.unwrap();
+
+        assert_eq!(catalog.blocks(&TimeRange::default(), None).len(), 1);
+        assert_eq!(memory.used.load(Ordering::Relaxed), 0);
+    }
 }
@@ -0,0 +1,181 @@
+use crate::engine::accumulator::Accumulator;
+use crate::engine::accumulator::Builders;
+use crate::engine::accumulator::Limits;
+use crate::engine::accumulator::MemoryBudget;
+use crate::engine::accumulator::Origin;
+use crate::engine::accumulator::Rows;
+use crate::engine::accumulator::System;
+use crate::engine::accumulator::Writer;
+use crate::engine::catalog::Catalog;
+use crate::engine::decode::Decoder;
+use crate::engine::pipeline::Pipeline;
+use crate::engine::schema::build_schema;
+use crate::engine::schema::DomainField;
+use crate::engine::schema::FieldDefinition;
+use crate::engine::schema::RECEIVED_AT;
+use crate::engine::shard::Sharding;
+use crate::engine::shard::Shards;
+use crate::engine::storage::Storage;
+use crate::picodata::service::ServiceWarnings;
+use arrow::datatypes::SchemaRef;
+use serde_json::value::RawValue;
+use serde_json::Value as JsonValue;
+use std::path::PathBuf;
+use time::OffsetDateTime;
+use tokio::runtime::Builder;
+use tokio::runtime::Runtime;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+/// Row conversion of an accumulator without its worker, for benchmarks.
+pub struct Ingest {
+    schema: SchemaRef,
+    decoder: Decoder,
+    pipeline: Pipeline,
+    builders: Builders,
+    origin: Origin,
+}
+
+/// Shards of a stream with their workers on a multi-thread runtime, for
+/// benchmarks of ingest spread over shards. Blocks go to a temporary
+/// directory removed on drop.
+pub struct Sharded {
+    runtime: Runtime,
+    shards: Option<Shards>,
+    tt: TaskTracker,
+    dir: PathBuf,
+    origin: Origin,
+}
+
+impl Ingest {
+    /// Takes field definitions as they are given in a stream definition.
+    pub fn new(fields: JsonValue) -> Self {
+        let fields: Vec<FieldDefinition> = serde_json::from_value(fields).unwrap();
+        let schema = build_schema(&fields, RECEIVED_AT, 0);
+        Self {
+            decoder: Decoder::new(&schema),
+            pipeline: Pipeline::default(),
+            builders: schema.fields().iter().map(|f| f.builder()).collect(),
+            origin: Origin {
+                client: None,
+                peer: None,
+                received_at: OffsetDateTime::now_utc(),
+            },
+            schema,
+        }
+    }
+
+    /// Adds rows the way they are added without the raw decoding path,
+    /// returns the number of failed rows.
+    pub fn json(&mut self, values: Vec<JsonValue>) -> usize {
+        let mut rows = 0;
+        let system = System {
+            instance: "bench",
+            origin: &self.origin,
+        };
+
+        Accumulator::add_rows_json(
+            &self.decoder,
+            &self.pipeline,
+            &system,
+            &mut self.builders,
+            values,
+            &mut rows,
+        )
+        .len()
+    }
+
+    pub fn raw(&mut self, values: Vec<Box<RawValue>>) -> usize {
+        let mut rows = 0;
+        let system = System {
+            instance: "bench",
+            origin: &self.origin,
+        };
+
+        Accumulator::add_rows_raw(
+            &self.decoder,
+            &system,
+            &mut self.builders,
+            values,
+            &mut rows,
+        )
+        .len()
+    }
+
+    /// Finishes the builders into a batch, returns its number of rows.
+    pub fn finish(&mut self) -> usize {
+        Accumulator::get_batch(self.schema.clone(), &mut self.builders).num_rows()
+    }
+}
+
+impl Sharded {
+    /// Spreads rows over `shards` shards by the `key` field.
+    pub fn new(fields: JsonValue, shards: usize, key: &str) -> Self {
+        let fields: Vec<FieldDefinition> = serde_json::from_value(fields).unwrap();
+        let schema = build_schema(&fields, RECEIVED_AT, 0);
+        let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+        let dir = std::env::temp_dir().join(Uuid::now_v7().to_string());
+        let catalog = Catalog::open("bench", &dir, None, None).unwrap();
+        let memory = MemoryBudget::new(usize::MAX / 2);
+        let tt = TaskTracker::new();
+        let sharding = Sharding {
+            shards,
+            key: Some(key.into()),
+        };
+        let limits = sharding.limits(Limits {
+            queue_depth: 64,
+            memory_budget: usize::MAX / 2,
+        });
+
+        // Workers are spawned on the runtime.
+        let guard = runtime.enter();
+        let shards = Shards::new(&sharding, || {
+            Accumulator::new(
+                schema.clone(),
+                Pipeline::default(),
+                limits,
+                "bench".into(),
+                memory.clone(),
+                Writer::new(
+                    Storage::default().properties(Accumulator::MAX_ROWS),
+                    catalog.clone(),
+                    "flush",
+                    ServiceWarnings::default(),
+                ),
+                &tt,
+            )
+        });
+        drop(guard);
+
+        Self {
+            runtime,
+            shards: Some(shards),
+            tt,
+            dir,
+            origin: Origin {
+                client: None,
+                peer: None,
+                received_at: OffsetDateTime::now_utc(),
+            },
+        }
+    }
+
+    /// Adds rows through the shards and waits until every shard converted
+    /// its part, returns the number of failed rows.
+    pub fn json(&self, values: Vec<JsonValue>) -> usize {
+        let shards = self.shards.as_ref().unwrap();
+        self.runtime
+            .block_on(shards.add_rows(Rows::Json(values), &self.origin))
+            .unwrap()
+            .len()
+    }
+}
+
+impl Drop for Sharded {
+    fn drop(&mut self) {
+        self.shards.take();
+        self.tt.close();
+        self.runtime.block_on(self.tt.wait());
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
@@ -0,0 +1,600 @@
+use crate::engine::chain;
+use crate::engine::crypto;
+use crate::engine::crypto::Keyring;
+use crate::engine::object_store::hex;
+use crate::engine::partition;
+use crate::engine::partition::TimeRange;
+use crate::engine::schema::DomainField;
+use crate::engine::schema::SCHEMA_VERSION;
+use arrow::array::AsArray;
+use arrow::array::RecordBatch;
+use arrow::compute::max;
+use arrow::compute::min;
+use arrow::datatypes::Schema;
+use arrow::datatypes::SchemaRef;
+use arrow::datatypes::TimestampNanosecondType;
+use aws_lc_rs::digest;
+use bytes::Bytes;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReader;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::properties::WriterPropertiesBuilder;
+use parquet::file::reader::ChunkReader;
+use parquet::file::reader::Length;
+use parquet::format::KeyValue;
+use serde_json::Map;
+use serde_json::Value as JsonValue;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("parquet error: {0}")]
+    Parquet(#[from] ParquetError),
+    #[error("arrow error: {0}")]
+    Arrow(#[from] arrow::error::ArrowError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("encryption: {0}")]
+    Crypto(#[from] crypto::Error),
+    #[error("hash chain: {0}")]
+    Chain(#[from] chain::Error),
+}
+
+pub const EXTENSION: &str = "parquet";
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Batch size of Parquet readers unless set otherwise.
+const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// Footer metadata keys, so a block can be understood without the catalog.
+pub mod keys {
+    pub const PREFIX: &str = "picolms.";
+    pub const VERSION: &str = "picolms.version";
+    pub const STREAM: &str = "picolms.stream";
+    pub const SCHEMA_VERSION: &str = "picolms.schema_version";
+    pub const INSTANCE: &str = "picolms.instance";
+    pub const MIN_TIME: &str = "picolms.min_time";
+    pub const MAX_TIME: &str = "picolms.max_time";
+    pub const ROWS: &str = "picolms.rows";
+    pub const PIPELINE: &str = "picolms.pipeline";
+}
+
+/// Where the rows of a block come from.
+#[derive(Debug, Clone, Default)]
+pub struct Provenance {
+    pub stream: String,
+    pub instance: String,
+    /// The ingest pipeline as JSON.
+    pub pipeline: String,
+}
+
+/// A block file as Parquet readers see it, decrypted in memory if needed.
+enum Source {
+    File(File),
+    Decrypted(Bytes),
+}
+
+/// Lists block files of the partitions under `dir` that may hold rows of the
+/// range, oldest partition first. Block ids are UUIDv7, so within a partition
+/// ordering by name is ordering by creation time.
+pub fn list(dir: &Path, range: &TimeRange) -> Result<Vec<PathBuf>, Error> {
+    let mut blocks = Vec::new();
+
+    for partition in partition::list(dir, range)? {
+        blocks.extend(list_partition(&partition)?);
+    }
+
+    Ok(blocks)
+}
+
+/// Block files of one partition directory, oldest first.
+pub fn list_partition(partition: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut blocks = Vec::new();
+
+    for entry in std::fs::read_dir(partition)? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_block = entry.file_type()?.is_file()
+            && path.extension().is_some_and(|v| v == EXTENSION)
+            && path
+                .file_stem()
+                .and_then(|v| v.to_str())
+                .is_some_and(|v| Uuid::try_parse(v).is_ok());
+
+        if is_block {
+            blocks.push(path);
+        }
+    }
+
+    blocks.sort();
+    Ok(blocks)
+}
+
+/// SHA-256 of the contents of a block file.
+pub fn checksum(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+/// SHA-256 of a block file, read in chunks.
+pub fn checksum_file(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0; 1 << 16];
+
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hex(context.finish().as_ref())),
+            n => context.update(&buf[..n]),
+        }
+    }
+}
+
+/// Path of a new block in its partition directory.
+pub fn path(partition: &Path, id: Uuid) -> PathBuf {
+    partition.join(format!("{id}.{EXTENSION}"))
+}
+
+/// Removes a file, one removed meanwhile is not an error. Returns whether the
+/// file was there.
+pub fn remove_if_exists(path: &Path) -> Result<bool, std::io::Error> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn properties() -> WriterPropertiesBuilder {
+    WriterProperties::builder()
+        .set_created_by(format!("picolms version {VERSION}"))
+        .set_compression(Compression::LZ4_RAW)
+}
+
+/// Writes a batch to a new block file and syncs it to disk, encrypted with
+/// the current key of the keyring if there is one.
+pub fn write(
+    path: &Path,
+    batch: &RecordBatch,
+    props: WriterProperties,
+    provenance: &Provenance,
+    keys: Option<&Keyring>,
+) -> Result<(), Error> {
+    let mut writer = BlockWriter::create(path, batch.schema(), props, provenance, keys)?;
+    writer.write(batch)?;
+    writer.finish()
+}
+
+/// Writes a new block a batch at a time, holding only the row group being
+/// built. Encrypted blocks are encrypted whole, so their encoded data is kept
+/// in memory until the block is finished.
+pub struct BlockWriter<'a> {
+    file: File,
+    schema: SchemaRef,
+    writer: ArrowWriter<Sink>,
+    provenance: &'a Provenance,
+    keys: Option<&'a Keyring>,
+    min_time: Option<i64>,
+    max_time: Option<i64>,
+    rows: usize,
+}
+
+enum Sink {
+    File(File),
+    Buffer(Vec<u8>),
+}
+
+impl<'a> BlockWriter<'a> {
+    pub fn create(
+        path: &Path,
+        schema: SchemaRef,
+        props: WriterProperties,
+        provenance: &'a Provenance,
+        keys: Option<&'a Keyring>,
+    ) -> Result<Self, Error> {
+        let file = OpenOptions::new().create_new(true).write(true).open(path)?;
+        let sink = match keys {
+            Some(_) => Sink::Buffer(Vec::new()),
+            None => Sink::File(file.try_clone()?),
+        };
+
+        Ok(Self {
+            file,
+            writer: ArrowWriter::try_new(sink, schema.clone(), Some(props))?,
+            schema,
+            provenance,
+            keys,
+            min_time: None,
+            max_time: None,
+            rows: 0,
+        })
+    }
+
+    pub fn write(&mut self, batch: &RecordBatch) -> Result<(), Error> {
+        if let Some(times) = batch
+            .column_by_name(partition::time_field(&self.schema))
+            .and_then(|v| v.as_primitive_opt::<TimestampNanosecondType>())
+        {
+            self.min_time = self.min_time.into_iter().chain(min(times)).min();
+            self.max_time = self.max_time.into_iter().chain(max(times)).max();
+        }
+
+        self.rows += batch.num_rows();
+        self.writer.write(batch)?;
+        Ok(())
+    }
+
+    /// Writes the footer and syncs the block to disk.
+    pub fn finish(mut self) -> Result<(), Error> {
+        for (key, value) in
+            self.provenance
+                .footer(&self.schema, self.min_time, self.max_time, self.rows)
+        {
+            self.writer
+                .append_key_value_metadata(KeyValue::new(key.to_string(), value));
+        }
+
+        if let (Sink::Buffer(data), Some(keys)) = (self.writer.into_inner()?, self.keys) {
+            self.file.write_all(&keys.encrypt(data)?)?;
+        }
+
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::File(v) => v.write(buf),
+            Self::Buffer(v) => v.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::File(v) => v.flush(),
+            Self::Buffer(v) => v.flush(),
+        }
+    }
+}
+
+impl Provenance {
+    /// Reads the provenance from a block footer, blocks written before it
+    /// was recorded give empty values.
+    pub fn read(path: &Path, keys: Option<&Keyring>) -> Result<Self, Error> {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(Source::open(path, keys)?)?;
+        let metadata = reader.metadata().file_metadata().key_value_metadata();
+        let value = |key: &str| {
+            metadata
+                .into_iter()
+                .flatten()
+                .find(|v| v.key == key)
+                .and_then(|v| v.value.clone())
+                .unwrap_or_default()
+        };
+
+        Ok(Self {
+            stream: value(keys::STREAM),
+            instance: value(keys::INSTANCE),
+            pipeline: value(keys::PIPELINE),
+        })
+    }
+
+    fn footer(
+        &self,
+        schema: &Schema,
+        min_time: Option<i64>,
+        max_time: Option<i64>,
+        rows: usize,
+    ) -> Vec<(&'static str, String)> {
+        let format = |v: Option<i64>| {
+            v.and_then(|v| OffsetDateTime::from_unix_timestamp_nanos(v.into()).ok())
+                .and_then(|v| v.format(&Rfc3339).ok())
+                .unwrap_or_default()
+        };
+
+        vec![
+            (keys::VERSION, VERSION.to_string()),
+            (keys::STREAM, self.stream.clone()),
+            (
+                keys::SCHEMA_VERSION,
+                schema
+                    .metadata()
+                    .get(SCHEMA_VERSION)
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+            (keys::INSTANCE, self.instance.clone()),
+            (keys::MIN_TIME, format(min_time)),
+            (keys::MAX_TIME, format(max_time)),
+            (keys::ROWS, rows.to_string()),
+            (keys::PIPELINE, self.pipeline.clone()),
+        ]
+    }
+}
+
+/// Schema of a block without its footer metadata, which differs from block
+/// to block, so blocks of one definition compare equal.
+pub fn schema(path: &Path, keys: Option<&Keyring>) -> Result<SchemaRef, Error> {
+    let schema = ParquetRecordBatchReaderBuilder::try_new(Source::open(path, keys)?)?
+        .schema()
+        .clone();
+    let metadata = schema
+        .metadata()
+        .iter()
+        .filter(|(k, _)| !k.starts_with(keys::PREFIX))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    Ok(Arc::new(schema.as_ref().clone().with_metadata(metadata)))
+}
+
+pub fn read(path: &Path, keys: Option<&Keyring>) -> Result<Vec<RecordBatch>, Error> {
+    Ok(reader(path, keys, DEFAULT_BATCH_SIZE)?.collect::<Result<_, _>>()?)
+}
+
+/// Reads a block a batch of up to `batch_size` rows at a time.
+pub fn reader(
+    path: &Path,
+    keys: Option<&Keyring>,
+    batch_size: usize,
+) -> Result<ParquetRecordBatchReader, Error> {
+    Ok(
+        ParquetRecordBatchReaderBuilder::try_new(Source::open(path, keys)?)?
+            .with_batch_size(batch_size)
+            .build()?,
+    )
+}
+
+/// Opens a block file for a Parquet reader. Blocks written before
+/// encryption was configured are read as they are.
+pub fn open(path: &Path, keys: Option<&Keyring>) -> Result<impl ChunkReader, Error> {
+    Source::open(path, keys)
+}
+
+impl Source {
+    fn open(path: &Path, keys: Option<&Keyring>) -> Result<Self, Error> {
+        let mut file = File::open(path)?;
+        let mut magic = [0; crypto::MAGIC.len()];
+        let encrypted = file.read_exact(&mut magic).is_ok() && magic == *crypto::MAGIC;
+        file.seek(SeekFrom::Start(0))?;
+
+        if !encrypted {
+            return Ok(Self::File(file));
+        }
+
+        let keys = keys.ok_or(crypto::Error::NotConfigured)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Ok(Self::Decrypted(keys.decrypt(data)?.into()))
+    }
+}
+
+impl Length for Source {
+    fn len(&self) -> u64 {
+        match self {
+            Self::File(v) => v.len(),
+            Self::Decrypted(v) => v.len() as u64,
+        }
+    }
+}
+
+impl ChunkReader for Source {
+    type T = Box<dyn Read + Send>;
+
+    fn get_read(&self, start: u64) -> Result<Self::T, ParquetError> {
+        Ok(match self {
+            Self::File(v) => Box::new(v.get_read(start)?),
+            Self::Decrypted(v) => Box::new(v.get_read(start)?),
+        })
+    }
+
+    fn get_bytes(&self, start: u64, length: usize) -> Result<Bytes, ParquetError> {
+        match self {
+            Self::File(v) => v.get_bytes(start, length),
+            Self::Decrypted(v) => v.get_bytes(start, length),
+        }
+    }
+}
+
+pub fn to_json_rows(batch: &RecordBatch) -> Vec<JsonValue> {
+    let schema = batch.schema();
+
+    (0..batch.num_rows())
+        .map(|i| {
+            schema
+                .fields()
+                .iter()
+                .zip(batch.columns())
+                .map(|(f, c)| (f.name().clone(), f.to_json(c.as_ref(), i)))
+                .collect::<Map<_, _>>()
+                .into()
+        })
+        .collect()
+}
+
+/// Blocks and directories for tests.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::engine::partition::Hour;
+    use crate::engine::schema::build_schema;
+    use crate::engine::schema::RECEIVED_AT;
+    use arrow::array::StringArray;
+    use arrow::array::TimestampNanosecondArray;
+    use std::ops::Deref;
+
+    /// A fresh directory, removed with its contents when dropped.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new() -> Self {
+            let dir = std::env::temp_dir().join(Uuid::now_v7().to_string());
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Default for TempDir {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    /// Writes a block of a stream without fields of its own, a row per time,
+    /// into the partition of the first time under the directory.
+    pub fn write_block(dir: &Path, times: Vec<i64>) -> PathBuf {
+        write_block_with(dir, times, properties().build(), None)
+    }
+
+    pub fn write_block_with(
+        dir: &Path,
+        times: Vec<i64>,
+        props: WriterProperties,
+        keys: Option<&Keyring>,
+    ) -> PathBuf {
+        let values = ["a", "b"].into_iter().cycle().take(times.len());
+        let text = || Arc::new(StringArray::from_iter_values(values.clone())) as _;
+        let partition = Hour::of(times.first().copied().unwrap_or_default()).dir(dir);
+        let batch = RecordBatch::try_new(
+            build_schema(&[], RECEIVED_AT, 3),
+            vec![
+                text(),
+                Arc::new(TimestampNanosecondArray::from(times).with_timezone("UTC")),
+                text(),
+                text(),
+                text(),
+            ],
+        )
+        .unwrap();
+
+        std::fs::create_dir_all(&partition).unwrap();
+        let path = path(&partition, Uuid::now_v7());
+        write(&path, &batch, props, &Provenance::default(), keys).unwrap();
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::schema::build_schema;
+    use crate::engine::schema::RECEIVED_AT;
+    use arrow::array::StringArray;
+    use arrow::array::TimestampNanosecondArray;
+    use std::collections::HashMap;
+    use testing::TempDir;
+
+    fn batch(times: Vec<i64>) -> RecordBatch {
+        let rows = times.len();
+        let text = || Arc::new(StringArray::from(vec!["x"; rows])) as _;
+        RecordBatch::try_new(
+            build_schema(&[], RECEIVED_AT, 7),
+            vec![
+                text(),
+                Arc::new(TimestampNanosecondArray::from(times).with_timezone("UTC")),
+                text(),
+                text(),
+                text(),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn footer_describes_the_block() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = super::path(&dir, Uuid::now_v7());
+        let provenance = Provenance {
+            stream: "s".into(),
+            instance: "i1".into(),
+            pipeline: "[]".into(),
+        };
+
+        let mut writer = BlockWriter::create(
+            &path,
+            batch(vec![]).schema(),
+            properties().build(),
+            &provenance,
+            None,
+        )
+        .unwrap();
+        writer
+            .write(&batch(vec![3_000_000_000, 1_000_000_000]))
+            .unwrap();
+        writer.write(&batch(vec![2_000_000_000])).unwrap();
+        writer.finish().unwrap();
+
+        let stored = Provenance::read(&path, None).unwrap();
+        assert_eq!(
+            (stored.stream, stored.instance, stored.pipeline),
+            ("s".into(), "i1".into(), "[]".into())
+        );
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        let footer = reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap()
+            .iter()
+            .map(|v| (v.key.as_str(), v.value.clone().unwrap_or_default()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(footer[keys::SCHEMA_VERSION], "7");
+        assert_eq!(footer[keys::MIN_TIME], "1970-01-01T00:00:01Z");
+        assert_eq!(footer[keys::MAX_TIME], "1970-01-01T00:00:03Z");
+        assert_eq!(footer[keys::ROWS], "3");
+
+        // Footer keys differ from block to block and stay out of the schema.
+        let schema = schema(&path, None).unwrap();
+        assert!(schema
+            .metadata()
+            .keys()
+            .all(|v| !v.starts_with(keys::PREFIX)));
+        assert_eq!(schema.fields(), batch(vec![]).schema().fields());
+        assert_eq!(
+            read(&path, None)
+                .unwrap()
+                .iter()
+                .map(|v| v.num_rows())
+                .sum::<usize>(),
+            3
+        );
+    }
+}
@@ -0,0 +1,707 @@
+use crate::engine::block;
+use crate::engine::chain::BrokenLink;
+use crate::engine::chain::Chain;
+use crate::engine::chain::ChainReport;
+use crate::engine::chain::Event;
+use crate::engine::chain::ServerKey;
+use crate::engine::crypto::Keyring;
+use crate::engine::crypto::MasterKey;
+use crate::engine::filter::Filter;
+use crate::engine::partition::TimeRange;
+use crate::engine::partition::TIME_FIELD;
+use crate::engine::schema::DomainField;
+use crate::engine::schema::SCHEMA_VERSION;
+use crate::engine::value::Value;
+use arrow::array::Array;
+use arrow::array::ArrayRef;
+use arrow::array::AsArray;
+use arrow::datatypes::DataType;
+use arrow::datatypes::Field;
+use arrow::datatypes::TimestampNanosecondType;
+use parquet::arrow::arrow_reader::statistics::StatisticsConverter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+/// A flushed block with statistics from its Parquet footer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockEntry {
+    pub id: Uuid,
+    pub stream: String,
+    /// Version of the stream definition the block was written under.
+    pub version: u64,
+    /// Partition directory relative to the stream directory.
+    pub partition: PathBuf,
+    pub rows: u64,
+    pub bytes: u64,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub min_time: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub max_time: Option<OffsetDateTime>,
+    pub columns: BTreeMap<String, ColumnStats>,
+    /// Object store key of a block offloaded from local disk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// Instance that flushed a block copied from another replica.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// Instances holding a copy of a block flushed here.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<String>,
+    /// SHA-256 of the block file, recorded when the block is written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Why the last scrub found the block file damaged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub damaged: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnStats {
+    pub min: JsonValue,
+    pub max: JsonValue,
+    pub nulls: u64,
+    /// Whether the column holds timestamps, whose statistics are ordered
+    /// by time. Not recorded by earlier versions.
+    #[serde(default)]
+    pub timestamp: bool,
+}
+
+/// Blocks of a stream directory. Changes are appended to a log, which is
+/// checked against the files on disk when the catalog is opened, so entries
+/// lost in a crash are rebuilt from the block footers. Entries of offloaded
+/// blocks are kept without a local file. Removed blocks are remembered until
+/// the replicas holding their copies drop them. With encryption configured,
+/// the catalog holds the keyring of its blocks, with a server key the hash
+/// chain over them.
+pub struct Catalog {
+    stream: String,
+    dir: PathBuf,
+    keys: Option<Arc<Keyring>>,
+    chain: Option<Chain>,
+    blocks: Mutex<BTreeMap<Uuid, BlockEntry>>,
+    removed: Mutex<Removed>,
+    log: Mutex<Log>,
+    files: RwLock<()>,
+    rewrites: Mutex<()>,
+}
+
+/// The catalog log file. It is rewritten from the entries once it holds
+/// many more records than there are entries.
+struct Log {
+    file: File,
+    records: usize,
+}
+
+/// Replicas that may still hold a copy of each removed block.
+type Removed = BTreeMap<Uuid, Vec<String>>;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Add(Box<BlockEntry>),
+    Remove(Uuid),
+    Copies(Uuid, Vec<String>),
+}
+
+impl BlockEntry {
+    pub fn path(&self, dir: &Path) -> PathBuf {
+        block::path(&dir.join(&self.partition), self.id)
+    }
+
+    /// Whether the statistics of the block do not rule out rows in the
+    /// range matching the filter.
+    pub fn may_match(&self, range: &TimeRange, filter: Option<&Filter>) -> bool {
+        self.overlaps(range) && filter.is_none_or(|f| f.may_match(&self.columns))
+    }
+
+    fn overlaps(&self, range: &TimeRange) -> bool {
+        range
+            .from
+            .is_none_or(|v| self.max_time.is_none_or(|t| t >= v))
+            && range.to.is_none_or(|v| self.min_time.is_none_or(|t| t < v))
+    }
+}
+
+impl Catalog {
+    const LOG: &'static str = "catalog.jsonl";
+
+    pub fn open(
+        stream: &str,
+        dir: &Path,
+        master: Option<&Arc<MasterKey>>,
+        server_key: Option<&Arc<ServerKey>>,
+    ) -> Result<Arc<Self>, block::Error> {
+        std::fs::create_dir_all(dir)?;
+        let keys = master.map(|v| Keyring::open(dir, v.clone())).transpose()?;
+
+        let log_path = dir.join(Self::LOG);
+        let mut blocks = BTreeMap::new();
+        let mut removed = Removed::new();
+        // Removed blocks, a crash may have left their files behind.
+        let mut forgotten = HashSet::new();
+
+        match std::fs::read_to_string(&log_path) {
+            Ok(data) => {
+                // A torn last line is left from a crash, the files tell the rest.
+                for record in data.lines().map_while(|v| serde_json::from_str(v).ok()) {
+                    match record {
+                        Record::Add(entry) => {
+                            forgotten.remove(&entry.id);
+                            blocks.insert(entry.id, *entry);
+                        }
+                        Record::Remove(id) => {
+                            forgotten.insert(id);
+                            remove(&mut blocks, &mut removed, id);
+                        }
+                        Record::Copies(id, replicas) if replicas.is_empty() => {
+                            removed.remove(&id);
+                        }
+                        Record::Copies(id, replicas) => {
+                            removed.insert(id, replicas);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => Err(e)?,
+        }
+
+        let paths = block::list(dir, &TimeRange::default())?;
+        let present = paths
+            .iter()
+            .filter_map(|v| block_id(v))
+            .collect::<HashSet<_>>();
+        let lost = blocks
+            .values()
+            .filter(|v| v.remote.is_none() && !present.contains(&v.id))
+            .map(|v| v.id)
+            .collect::<Vec<_>>();
+        for id in lost {
+            remove(&mut blocks, &mut removed, id);
+        }
+
+        for path in paths {
+            // The removal was cut short by a crash, it is finished here.
+            if block_id(&path).is_some_and(|v| forgotten.contains(&v)) {
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+
+            let missing = block_id(&path).is_some_and(|v| !blocks.contains_key(&v));
+            // Blocks that cannot be read stay out of the catalog.
+            if let Some(entry) = missing
+                .then(|| describe(stream, dir, &path, keys.as_deref()).ok())
+                .flatten()
+            {
+                blocks.insert(entry.id, entry);
+            }
+        }
+
+        let log = Log::write(dir, &blocks, &removed)?;
+        let chain = server_key
+            .map(|v| Chain::open(dir, v.clone(), &blocks))
+            .transpose()?;
+
+        Ok(Arc::new(Self {
+            stream: stream.to_string(),
+            dir: dir.to_path_buf(),
+            keys,
+            chain,
+            blocks: Mutex::new(blocks),
+            removed: Mutex::new(removed),
+            log: Mutex::new(log),
+            files: RwLock::new(()),
+            rewrites: Mutex::new(()),
+        }))
+    }
+
+    pub fn stream(&self) -> &str {
+        &self.stream
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn keys(&self) -> Option<&Keyring> {
+        self.keys.as_deref()
+    }
+
+    /// Held while block files are read, so they are not swapped underneath.
+    pub fn lock_read(&self) -> RwLockReadGuard<'_, ()> {
+        self.files.read().unwrap()
+    }
+
+    /// Held while block files are replaced or removed.
+    pub fn lock_write(&self) -> RwLockWriteGuard<'_, ()> {
+        self.files.write().unwrap()
+    }
+
+    /// Held from reading blocks to swapping in their rewrite, so two rewrites
+    /// never start from the same blocks.
+    pub fn lock_rewrite(&self) -> MutexGuard<'_, ()> {
+        self.rewrites.lock().unwrap()
+    }
+
+    /// Reads the statistics of a block file of this catalog.
+    pub fn describe(&self, path: &Path) -> Result<BlockEntry, block::Error> {
+        describe(&self.stream, &self.dir, path, self.keys())
+    }
+
+    pub fn add(&self, entry: BlockEntry) -> Result<(), block::Error> {
+        let mut blocks = self.blocks.lock().unwrap();
+        if let Some(chain) = &self.chain {
+            let checksum = match &entry.checksum {
+                Some(v) => v.clone(),
+                None => block::checksum_file(&entry.path(&self.dir))?,
+            };
+
+            chain.append(Event::Add {
+                block: entry.id,
+                checksum,
+            })?;
+        }
+
+        self.append(&blocks, &Record::Add(Box::new(entry.clone())))?;
+        blocks.insert(entry.id, entry);
+        Ok(())
+    }
+
+    /// Changes an entry in place, false if it is gone.
+    pub fn update(&self, id: Uuid, f: impl FnOnce(&mut BlockEntry)) -> Result<bool, block::Error> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let Some(entry) = blocks.get_mut(&id) else {
+            return Ok(false);
+        };
+
+        f(entry);
+        let record = Record::Add(Box::new(entry.clone()));
+        self.append(&blocks, &record)?;
+        Ok(true)
+    }
+
+    /// Takes blocks out of the catalog before their files are deleted, so
+    /// the chain records the removal even when a crash cuts it short.
+    pub fn remove(&self, ids: &[Uuid]) -> Result<(), block::Error> {
+        let mut blocks = self.blocks.lock().unwrap();
+        for id in ids {
+            if let Some(chain) = self.chain.as_ref().filter(|_| blocks.contains_key(id)) {
+                chain.append(Event::Remove { block: *id })?;
+            }
+
+            self.append(&blocks, &Record::Remove(*id))?;
+            remove(&mut blocks, &mut self.removed.lock().unwrap(), *id);
+        }
+
+        Ok(())
+    }
+
+    /// Removed blocks with the replicas that may still hold their copies.
+    pub fn removed(&self) -> Removed {
+        self.removed.lock().unwrap().clone()
+    }
+
+    /// The replica dropped its copies of the removed blocks.
+    pub fn released(&self, replica: &str, ids: &[Uuid]) -> Result<(), block::Error> {
+        let blocks = self.blocks.lock().unwrap();
+        for id in ids {
+            let Some(mut replicas) = self.removed.lock().unwrap().get(id).cloned() else {
+                continue;
+            };
+
+            replicas.retain(|v| v != replica);
+            self.append(&blocks, &Record::Copies(*id, replicas.clone()))?;
+
+            let mut removed = self.removed.lock().unwrap();
+            match replicas.is_empty() {
+                true => removed.remove(id),
+                false => removed.insert(*id, replicas),
+            };
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<BlockEntry> {
+        self.blocks.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Walks the hash chain and checks the local blocks it says exist
+    /// against their checksums, none without a chain.
+    pub fn verify_chain(&self) -> Result<Option<ChainReport>, block::Error> {
+        let Some(chain) = &self.chain else {
+            return Ok(None);
+        };
+
+        // Read together, so no block is added or removed in between.
+        let (data, blocks) = {
+            let blocks = self.blocks.lock().unwrap();
+            (chain.read()?, blocks.clone())
+        };
+
+        let (mut report, live) = chain.walk(&data);
+        for (id, (seq, checksum)) in live {
+            let mut broken = |reason: &str| {
+                report.broken.push(BrokenLink {
+                    seq,
+                    block: Some(id),
+                    reason: reason.to_string(),
+                });
+            };
+
+            let Some(entry) = blocks.get(&id) else {
+                broken("block is missing");
+                continue;
+            };
+
+            if entry.remote.is_some() {
+                report.offloaded_blocks += 1;
+                continue;
+            }
+
+            let _guard = self.lock_read();
+            // Removed by compaction or retention meanwhile.
+            if self.get(id).is_none() {
+                continue;
+            }
+
+            match block::checksum_file(&entry.path(&self.dir)) {
+                Ok(v) if v == checksum => {}
+                Ok(_) => broken("block was modified"),
+                Err(e) => broken(&e.to_string()),
+            }
+            report.checked_blocks += 1;
+        }
+
+        Ok(Some(report))
+    }
+
+    /// Blocks that may hold rows of the range matching the filter, in event
+    /// time order of their partitions.
+    pub fn blocks(&self, range: &TimeRange, filter: Option<&Filter>) -> Vec<BlockEntry> {
+        let mut blocks = self
+            .blocks
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|v| v.may_match(range, filter))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        blocks.sort_by(|a, b| (&a.partition, a.id).cmp(&(&b.partition, b.id)));
+        blocks
+    }
+
+    /// Rewrites the log from the entries, so records of removed blocks and
+    /// the statistics they held are gone from it.
+    pub fn compact_log(&self) -> Result<(), block::Error> {
+        let blocks = self.blocks.lock().unwrap();
+        let mut log = self.log.lock().unwrap();
+        let removed = self.removed.lock().unwrap();
+        *log = Log::write(&self.dir, &blocks, &removed)?;
+        Ok(())
+    }
+
+    /// Appends a record and syncs it. Past the record limit the log is
+    /// rewritten from `blocks` first.
+    fn append(
+        &self,
+        blocks: &BTreeMap<Uuid, BlockEntry>,
+        record: &Record,
+    ) -> Result<(), block::Error> {
+        let mut log = self.log.lock().unwrap();
+        let removed = self.removed.lock().unwrap();
+        if log.records > Log::MIN_RECORDS.max((blocks.len() + removed.len()) * 2) {
+            *log = Log::write(&self.dir, blocks, &removed)?;
+        }
+
+        let mut line = serde_json::to_vec(record).unwrap();
+        line.push(b'\n');
+        log.file.write_all(&line)?;
+        log.file.sync_data()?;
+        log.records += 1;
+        Ok(())
+    }
+}
+
+impl Log {
+    const MIN_RECORDS: usize = 1024;
+
+    /// Replaces the log with a record per entry and removed block.
+    fn write(
+        dir: &Path,
+        blocks: &BTreeMap<Uuid, BlockEntry>,
+        removed: &Removed,
+    ) -> Result<Self, std::io::Error> {
+        let path = dir.join(Catalog::LOG);
+        let tmp_path = dir.join(format!("{}.tmp", Catalog::LOG));
+        let records = blocks
+            .values()
+            .map(|v| Record::Add(Box::new(v.clone())))
+            .chain(removed.iter().map(|(id, v)| Record::Copies(*id, v.clone())));
+
+        let mut data = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut data, &record).unwrap();
+            data.push(b'\n');
+        }
+
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&data)?;
+        tmp.sync_all()?;
+        std::fs::rename(tmp_path, &path)?;
+        File::open(dir)?.sync_all()?;
+
+        Ok(Self {
+            file: File::options().append(true).open(path)?,
+            records: blocks.len() + removed.len(),
+        })
+    }
+}
+
+/// Removes an entry, remembering the replicas of a block copied elsewhere.
+fn remove(blocks: &mut BTreeMap<Uuid, BlockEntry>, removed: &mut Removed, id: Uuid) {
+    if let Some(entry) = blocks.remove(&id).filter(|v| !v.replicas.is_empty()) {
+        removed.insert(id, entry.replicas);
+    }
+}
+
+pub fn block_id(path: &Path) -> Option<Uuid> {
+    Uuid::try_parse(path.file_stem()?.to_str()?).ok()
+}
+
+fn describe(
+    stream: &str,
+    dir: &Path,
+    path: &Path,
+    keys: Option<&Keyring>,
+) -> Result<BlockEntry, block::Error> {
+    let bytes = std::fs::metadata(path)?.len();
+    let reader = ParquetRecordBatchReaderBuilder::try_new(block::open(path, keys)?)?;
+    let schema = reader.schema();
+    let row_groups = reader.metadata().row_groups();
+    let mut columns = BTreeMap::new();
+
+    for field in schema.fields() {
+        if matches!(field.data_type(), DataType::List(_)) {
+            continue;
+        }
+
+        let converter =
+            StatisticsConverter::try_new(field.name(), schema, reader.parquet_schema())?;
+        let mins = converter.row_group_mins(row_groups)?;
+        let maxes = converter.row_group_maxes(row_groups)?;
+        let nulls = converter.row_group_null_counts(row_groups)?;
+
+        columns.insert(
+            field.name().clone(),
+            ColumnStats {
+                min: extreme(field, &mins, Ordering::Less),
+                max: extreme(field, &maxes, Ordering::Greater),
+                nulls: nulls.iter().flatten().sum(),
+                timestamp: matches!(field.data_type(), DataType::Timestamp(..)),
+            },
+        );
+    }
+
+    let time = |v: fn(&ColumnStats) -> &JsonValue| {
+        let stats = columns.get(schema.metadata().get(TIME_FIELD)?)?;
+        OffsetDateTime::parse(v(stats).as_str()?, &Rfc3339).ok()
+    };
+
+    Ok(BlockEntry {
+        id: block_id(path).unwrap_or_default(),
+        stream: stream.to_string(),
+        version: schema
+            .metadata()
+            .get(SCHEMA_VERSION)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default(),
+        partition: path
+            .parent()
+            .and_then(|v| v.strip_prefix(dir).ok())
+            .unwrap_or(Path::new(""))
+            .to_path_buf(),
+        rows: reader.metadata().file_metadata().num_rows() as u64,
+        bytes,
+        min_time: time(|v| &v.min),
+        max_time: time(|v| &v.max),
+        columns,
+        remote: None,
+        origin: None,
+        replicas: Vec::new(),
+        checksum: Some(block::checksum_file(path)?),
+        damaged: None,
+    })
+}
+
+/// Smallest or largest of the per row group statistics. Timestamps are
+/// compared as numbers, their RFC3339 text does not sort by time.
+fn extreme(field: &Field, values: &ArrayRef, order: Ordering) -> JsonValue {
+    let times = values.as_primitive_opt::<TimestampNanosecondType>();
+    let compare = |a: usize, b: usize| match times {
+        Some(v) => Some(v.value(a).cmp(&v.value(b))),
+        None => Value::from(&field.to_json(values.as_ref(), a))
+            .compare(&Value::from(&field.to_json(values.as_ref(), b))),
+    };
+
+    (0..values.len())
+        .filter(|i| values.is_valid(*i))
+        .reduce(|a, b| match compare(b, a) {
+            Some(v) if v == order => b,
+            _ => a,
+        })
+        .map_or(JsonValue::Null, |i| field.to_json(values.as_ref(), i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing;
+    use crate::engine::block::testing::TempDir;
+    use crate::engine::schema::RECEIVED_AT;
+
+    fn write_block(dir: &Path) -> PathBuf {
+        testing::write_block(dir, vec![2_000_000_000, 1_000_000_000])
+    }
+
+    #[test]
+    fn describe_reads_footer_statistics() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let entry = catalog.describe(&write_block(&dir)).unwrap();
+
+        assert_eq!(entry.version, 3);
+        assert_eq!(entry.rows, 2);
+        assert_eq!(entry.partition, Path::new("1970/01/01/00"));
+        assert_eq!(entry.min_time.unwrap().unix_timestamp(), 1);
+        assert_eq!(entry.max_time.unwrap().unix_timestamp(), 2);
+        assert_eq!(entry.columns["_id"].min, "a");
+        assert_eq!(entry.columns["_id"].max, "b");
+    }
+
+    #[test]
+    fn describe_orders_row_groups_by_time() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let props = block::properties().set_max_row_group_size(1).build();
+        let times = vec![500_000_000, 0, 150_000_000, 100_000_000];
+        let path = testing::write_block_with(&dir, times, props, None);
+        let entry = catalog.describe(&path).unwrap();
+
+        // As text `...:00Z` sorts after `...:00.5Z` and `.1Z` after `.15Z`.
+        assert_eq!(entry.min_time.unwrap().unix_timestamp_nanos(), 0);
+        assert_eq!(entry.max_time.unwrap().unix_timestamp_nanos(), 500_000_000);
+        assert!(entry.columns[RECEIVED_AT].timestamp);
+        assert!(!entry.columns["_id"].timestamp);
+    }
+
+    #[test]
+    fn log_is_compacted_and_replayed() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let entry = catalog.describe(&write_block(&dir)).unwrap();
+        let id = entry.id;
+        catalog.add(entry).unwrap();
+
+        for i in 0..Log::MIN_RECORDS * 2 {
+            catalog
+                .update(id, |v| v.replicas = vec![i.to_string()])
+                .unwrap();
+        }
+
+        let log = std::fs::read_to_string(dir.join(Catalog::LOG)).unwrap();
+        assert!(log.lines().count() <= Log::MIN_RECORDS + 1);
+        drop(catalog);
+
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let entry = catalog.get(id).unwrap();
+        assert_eq!(entry.replicas, [(Log::MIN_RECORDS * 2 - 1).to_string()]);
+    }
+
+    #[test]
+    fn removals_cut_short_are_finished_on_open() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let path = write_block(&dir);
+        let entry = catalog.describe(&path).unwrap();
+        let id = entry.id;
+        catalog.add(entry).unwrap();
+
+        // A crash between leaving the catalog and deleting the file.
+        catalog.remove(&[id]).unwrap();
+        drop(catalog);
+
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        assert!(catalog.get(id).is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn removed_blocks_are_kept_until_released() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let path = write_block(&dir);
+        let entry = catalog.describe(&path).unwrap();
+        let id = entry.id;
+        catalog
+            .add(BlockEntry {
+                replicas: vec!["i2".into(), "i3".into()],
+                ..entry
+            })
+            .unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        catalog.remove(&[id]).unwrap();
+        catalog.released("i2", &[id]).unwrap();
+        drop(catalog);
+
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        assert!(catalog.get(id).is_none());
+        assert_eq!(catalog.removed()[&id], ["i3"]);
+
+        catalog.released("i3", &[id]).unwrap();
+        drop(catalog);
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        assert!(catalog.removed().is_empty());
+    }
+
+    #[test]
+    fn lost_blocks_are_remembered_for_their_replicas() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let path = write_block(&dir);
+        let entry = catalog.describe(&path).unwrap();
+        let id = entry.id;
+        catalog
+            .add(BlockEntry {
+                replicas: vec!["i2".into()],
+                ..entry
+            })
+            .unwrap();
+        drop(catalog);
+
+        std::fs::remove_file(&path).unwrap();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        assert!(catalog.get(id).is_none());
+        assert_eq!(catalog.removed()[&id], ["i2"]);
+    }
+}
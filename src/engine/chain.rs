@@ -0,0 +1,598 @@
+use crate::engine::block;
+use crate::engine::catalog::BlockEntry;
+use crate::engine::crypto::unhex;
+use crate::engine::object_store::hex;
+use aws_lc_rs::digest;
+use aws_lc_rs::signature;
+use aws_lc_rs::signature::UnparsedPublicKey;
+use aws_lc_rs::signature::VerificationAlgorithm;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::sign::Signer;
+use tokio_rustls::rustls::SignatureScheme;
+use uuid::Uuid;
+use x509_parser::oid_registry::OID_KEY_TYPE_EC_PUBLIC_KEY;
+use x509_parser::oid_registry::OID_PKCS1_RSAENCRYPTION;
+use x509_parser::oid_registry::OID_SIG_ED25519;
+use x509_parser::prelude::FromDer;
+use x509_parser::prelude::X509Certificate;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid server key: {0}")]
+    InvalidKey(String),
+    #[error("tls: {0}")]
+    Tls(#[from] rustls::Error),
+}
+
+/// Schemes links are signed with, the server key picks the one it supports.
+const SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ED25519,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::RSA_PSS_SHA256,
+];
+
+/// The private key of the API server, which signs the links of the hash
+/// chains, and the public key of its certificate, which verifies them.
+pub struct ServerKey {
+    signer: Box<dyn Signer>,
+    certificate: Certificate,
+    /// Certificates the server had before, whose links are still trusted.
+    previous: Vec<Certificate>,
+}
+
+/// The public key of a certificate, which verifies the links it signed.
+struct Certificate {
+    public_key: Vec<u8>,
+    algorithm: &'static dyn VerificationAlgorithm,
+    /// SHA-256 of the certificate's public key, recorded in every link.
+    fingerprint: String,
+}
+
+/// A change of the blocks of a stream. Every link holds the hash of the
+/// previous one, so no link can be altered, dropped or reordered without
+/// breaking the links after it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Link {
+    pub seq: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+    #[serde(flatten)]
+    pub event: Event,
+    /// Hash of the previous link, empty for the first one.
+    pub prev: String,
+    pub hash: String,
+    pub signer: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Add {
+        block: Uuid,
+        checksum: String,
+    },
+    /// A block file found without a link when the catalog was opened,
+    /// flushed right before a crash or before the chain was kept.
+    Found {
+        block: Uuid,
+        checksum: String,
+    },
+    Remove {
+        block: Uuid,
+    },
+}
+
+/// The hashed part of a link.
+#[derive(Serialize)]
+struct Body<'a> {
+    seq: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    at: OffsetDateTime,
+    #[serde(flatten)]
+    event: &'a Event,
+    prev: &'a str,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChainReport {
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub verified_at: Option<OffsetDateTime>,
+    pub links: u64,
+    /// Hash of the last link, to be kept aside as proof of the chain so far.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head: Option<String>,
+    /// Links verified with a previous certificate of the server. Links of
+    /// any other certificate are broken.
+    pub previous_signers: u64,
+    /// Local blocks checked against their links.
+    pub checked_blocks: usize,
+    /// Offloaded blocks, which are not read back to be checked.
+    pub offloaded_blocks: usize,
+    pub broken: Vec<BrokenLink>,
+    /// Links of blocks that were found in the catalog without one, e.g.
+    /// flushed right before a crash, or placed there by hand. Listed while
+    /// the blocks exist.
+    pub found: Vec<FoundLink>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BrokenLink {
+    pub seq: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block: Option<Uuid>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FoundLink {
+    pub seq: u64,
+    pub block: Uuid,
+}
+
+/// Hash chain over the blocks of a catalog, appended to whenever a block is
+/// added or removed.
+pub struct Chain {
+    path: PathBuf,
+    key: Arc<ServerKey>,
+    head: Mutex<Head>,
+}
+
+struct Head {
+    file: File,
+    seq: u64,
+    hash: String,
+}
+
+impl ServerKey {
+    /// Loads the PEM private key and the certificate of the API server, and
+    /// the certificates it had before.
+    pub fn load(key: &Path, crt: &Path, previous: &[PathBuf]) -> Result<Arc<Self>, Error> {
+        let invalid = |e: &dyn fmt::Display| Error::InvalidKey(format!("{}: {e}", key.display()));
+        let der = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+            .ok_or_else(|| invalid(&"no private key found"))?;
+        let signer = rustls::crypto::aws_lc_rs::default_provider()
+            .key_provider
+            .load_private_key(der)?
+            .choose_scheme(SCHEMES)
+            .ok_or_else(|| invalid(&"unsupported key type"))?;
+
+        Ok(Arc::new(Self {
+            signer,
+            certificate: Certificate::load(crt)?,
+            previous: previous
+                .iter()
+                .map(|v| Certificate::load(v))
+                .collect::<Result<_, _>>()?,
+        }))
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<String, Error> {
+        Ok(hex(&self.signer.sign(message)?))
+    }
+
+    /// The certificate with the fingerprint, none if it is not trusted.
+    fn certificate(&self, fingerprint: &str) -> Option<&Certificate> {
+        [&self.certificate]
+            .into_iter()
+            .chain(&self.previous)
+            .find(|v| v.fingerprint == fingerprint)
+    }
+}
+
+impl fmt::Debug for ServerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerKey")
+            .field("fingerprint", &self.certificate.fingerprint)
+            .finish()
+    }
+}
+
+impl Certificate {
+    /// Reads the first certificate of a PEM file.
+    fn load(path: &Path) -> Result<Self, Error> {
+        let invalid = |e: &dyn fmt::Display| Error::InvalidKey(format!("{}: {e}", path.display()));
+        let cert = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+            .next()
+            .ok_or_else(|| invalid(&"no certificate found"))??;
+        let (_, cert) = X509Certificate::from_der(&cert).map_err(|e| invalid(&e))?;
+        let spki = cert.public_key();
+        let public_key = spki.subject_public_key.data.to_vec();
+
+        // The schemes of `SCHEMES`, EC curves told apart by the point size.
+        let oid = &spki.algorithm.algorithm;
+        let algorithm: &'static dyn VerificationAlgorithm = match public_key.len() {
+            _ if *oid == OID_SIG_ED25519 => &signature::ED25519,
+            65 if *oid == OID_KEY_TYPE_EC_PUBLIC_KEY => &signature::ECDSA_P256_SHA256_ASN1,
+            97 if *oid == OID_KEY_TYPE_EC_PUBLIC_KEY => &signature::ECDSA_P384_SHA384_ASN1,
+            _ if *oid == OID_PKCS1_RSAENCRYPTION => &signature::RSA_PSS_2048_8192_SHA256,
+            _ => Err(invalid(&"unsupported key type"))?,
+        };
+
+        Ok(Self {
+            public_key,
+            algorithm,
+            fingerprint: hex(digest::digest(&digest::SHA256, spki.raw).as_ref()),
+        })
+    }
+
+    fn verify(&self, message: &[u8], signature: &str) -> bool {
+        unhex(signature).is_some_and(|v| {
+            UnparsedPublicKey::new(self.algorithm, &self.public_key)
+                .verify(message, &v)
+                .is_ok()
+        })
+    }
+}
+
+impl Link {
+    fn digest(&self) -> String {
+        let body = Body {
+            seq: self.seq,
+            at: self.at,
+            event: &self.event,
+            prev: &self.prev,
+        };
+
+        block::checksum(&serde_json::to_vec(&body).unwrap())
+    }
+}
+
+impl Event {
+    fn block(&self) -> Uuid {
+        match self {
+            Self::Add { block, .. } | Self::Found { block, .. } | Self::Remove { block } => *block,
+        }
+    }
+}
+
+impl ChainReport {
+    /// The service warning for the report, none if the chain is intact and
+    /// every block was linked when it was written.
+    pub fn warning(&self) -> Option<String> {
+        let mut warnings = Vec::new();
+        if !self.broken.is_empty() {
+            warnings.push(format!("{} broken links", self.broken.len()));
+        }
+        if !self.found.is_empty() {
+            warnings.push(format!(
+                "{} blocks linked only when found in the catalog",
+                self.found.len()
+            ));
+        }
+
+        (!warnings.is_empty()).then(|| warnings.join(", "))
+    }
+}
+
+impl Chain {
+    pub const FILE_NAME: &'static str = "chain.jsonl";
+
+    /// Opens the chain of a catalog directory and links the blocks of the
+    /// catalog that have no link yet, which verification reports as found.
+    pub fn open(
+        dir: &Path,
+        key: Arc<ServerKey>,
+        blocks: &BTreeMap<Uuid, BlockEntry>,
+    ) -> Result<Self, Error> {
+        let path = dir.join(Self::FILE_NAME);
+        let mut data = match std::fs::read(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => Err(e)?,
+        };
+
+        // A torn last line is left from a crash. Lines broken elsewhere are
+        // kept for the verification to report.
+        let end = data.iter().rposition(|v| *v == b'\n').map_or(0, |v| v + 1);
+        if end < data.len() {
+            data.truncate(end);
+            std::fs::write(&path, &data)?;
+        }
+
+        let links = data
+            .split(|v| *v == b'\n')
+            .filter_map(|v| serde_json::from_slice::<Link>(v).ok())
+            .collect::<Vec<_>>();
+        let mut linked = HashSet::new();
+        for link in &links {
+            match &link.event {
+                Event::Add { block, .. } | Event::Found { block, .. } => linked.insert(*block),
+                Event::Remove { block } => linked.remove(block),
+            };
+        }
+
+        let chain = Self {
+            head: Mutex::new(Head {
+                file: File::options().create(true).append(true).open(&path)?,
+                seq: links.last().map_or(0, |v| v.seq + 1),
+                hash: links.last().map(|v| v.hash.clone()).unwrap_or_default(),
+            }),
+            path,
+            key,
+        };
+
+        for entry in blocks.values().filter(|v| !linked.contains(&v.id)) {
+            // Blocks offloaded before their checksum was recorded are left out.
+            let checksum = match (&entry.checksum, &entry.remote) {
+                (Some(v), _) => v.clone(),
+                (None, None) => block::checksum(&std::fs::read(entry.path(dir))?),
+                (None, Some(_)) => continue,
+            };
+
+            chain.append(Event::Found {
+                block: entry.id,
+                checksum,
+            })?;
+        }
+
+        Ok(chain)
+    }
+
+    pub fn append(&self, event: Event) -> Result<(), Error> {
+        let mut head = self.head.lock().unwrap();
+        let mut link = Link {
+            seq: head.seq,
+            at: OffsetDateTime::now_utc(),
+            event,
+            prev: head.hash.clone(),
+            hash: String::new(),
+            signer: self.key.certificate.fingerprint.clone(),
+            signature: String::new(),
+        };
+        link.hash = link.digest();
+        link.signature = self.key.sign(link.hash.as_bytes())?;
+
+        let mut line = serde_json::to_vec(&link).unwrap();
+        line.push(b'\n');
+        head.file.write_all(&line)?;
+        head.file.sync_data()?;
+        head.seq += 1;
+        head.hash = link.hash;
+        Ok(())
+    }
+
+    /// Contents of the chain file, for [`Chain::walk`].
+    pub fn read(&self) -> Result<Vec<u8>, Error> {
+        Ok(std::fs::read(&self.path)?)
+    }
+
+    /// Checks every link against the one before it and its signature, which
+    /// must be of the current or a previous certificate of the server.
+    /// Returns the report with the blocks the chain says exist, with the
+    /// sequence number and checksum of the link that added them.
+    pub fn walk(&self, data: &[u8]) -> (ChainReport, BTreeMap<Uuid, (u64, String)>) {
+        let mut report = ChainReport {
+            verified_at: Some(OffsetDateTime::now_utc()),
+            ..Default::default()
+        };
+        let mut live = BTreeMap::new();
+        let mut found = BTreeMap::new();
+        let mut seq = 0;
+        let mut prev = String::new();
+
+        for line in data.split(|v| *v == b'\n').filter(|v| !v.is_empty()) {
+            let mut broken = |block, reason: String| {
+                report.broken.push(BrokenLink { seq, block, reason });
+            };
+
+            let link = match serde_json::from_slice::<Link>(line) {
+                Ok(v) => v,
+                Err(e) => {
+                    broken(None, format!("unreadable link: {e}"));
+                    seq += 1;
+                    continue;
+                }
+            };
+
+            let block = Some(link.event.block());
+            if link.seq != seq {
+                broken(block, format!("sequence number {} out of order", link.seq));
+            }
+            if link.prev != prev {
+                broken(block, "does not follow the previous link".to_string());
+            }
+
+            let certificate = self.key.certificate(&link.signer);
+            if link.digest() != link.hash {
+                broken(block, "hash mismatch".to_string());
+            } else if certificate.is_none() {
+                broken(
+                    block,
+                    format!("signed by unknown certificate {}", link.signer),
+                );
+            } else if !certificate.is_some_and(|v| v.verify(link.hash.as_bytes(), &link.signature))
+            {
+                broken(block, "invalid signature".to_string());
+            } else if link.signer != self.key.certificate.fingerprint {
+                report.previous_signers += 1;
+            }
+
+            match link.event {
+                Event::Add { block, checksum } => {
+                    found.remove(&block);
+                    live.insert(block, (link.seq, checksum));
+                }
+                Event::Found { block, checksum } => {
+                    found.insert(block, link.seq);
+                    live.insert(block, (link.seq, checksum));
+                }
+                Event::Remove { block } => {
+                    found.remove(&block);
+                    live.remove(&block);
+                }
+            }
+
+            report.links += 1;
+            seq = link.seq + 1;
+            prev = link.hash;
+        }
+
+        report.head = (!prev.is_empty()).then_some(prev);
+        report.found = found
+            .into_iter()
+            .map(|(block, seq)| FoundLink { seq, block })
+            .collect();
+        (report, live)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing::TempDir;
+
+    /// Keys of the certificates the plugin is shipped with.
+    fn files(name: &str) -> (PathBuf, PathBuf) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("plugin");
+        (
+            dir.join(format!("{name}.key")),
+            dir.join(format!("{name}.crt")),
+        )
+    }
+
+    fn server_key(name: &str, previous: &[&str]) -> Arc<ServerKey> {
+        let (key, crt) = files(name);
+        let previous = previous.iter().map(|v| files(v).1).collect::<Vec<_>>();
+        ServerKey::load(&key, &crt, &previous).unwrap()
+    }
+
+    fn add(block: Uuid) -> Event {
+        Event::Add {
+            block,
+            checksum: "00".to_string(),
+        }
+    }
+
+    fn walk(dir: &Path, key: Arc<ServerKey>) -> ChainReport {
+        let chain = Chain::open(dir, key, &BTreeMap::new()).unwrap();
+        chain.walk(&chain.read().unwrap()).0
+    }
+
+    #[test]
+    fn links_are_chained() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = server_key("api-server", &[]);
+        let chain = Chain::open(&dir, key.clone(), &BTreeMap::new()).unwrap();
+        let (first, second) = (Uuid::now_v7(), Uuid::now_v7());
+        chain.append(add(first)).unwrap();
+        chain.append(add(second)).unwrap();
+        chain.append(Event::Remove { block: first }).unwrap();
+
+        let (report, live) = chain.walk(&chain.read().unwrap());
+        assert_eq!(report.links, 3);
+        assert!(report.broken.is_empty());
+        assert!(report.warning().is_none());
+        assert_eq!(live.keys().collect::<Vec<_>>(), [&second]);
+
+        // An altered link breaks, a dropped one breaks the link after it.
+        let data = String::from_utf8(chain.read().unwrap()).unwrap();
+        let lines = data.lines().collect::<Vec<_>>();
+        let altered = data.replacen(&first.to_string(), &Uuid::now_v7().to_string(), 1);
+        let report = chain.walk(altered.as_bytes()).0;
+        assert_eq!(report.broken.len(), 1);
+        assert_eq!(report.broken[0].reason, "hash mismatch");
+
+        let dropped = format!("{}\n{}\n", lines[0], lines[2]);
+        let report = chain.walk(dropped.as_bytes()).0;
+        assert!(!report.broken.is_empty());
+        assert_eq!(report.broken[0].seq, 1);
+        assert_eq!(
+            report.warning().unwrap(),
+            format!("{} broken links", report.broken.len())
+        );
+    }
+
+    #[test]
+    fn links_of_unknown_certificates_are_broken() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(&dir).unwrap();
+        let chain = Chain::open(&dir, server_key("api-server", &[]), &BTreeMap::new()).unwrap();
+        chain.append(add(Uuid::now_v7())).unwrap();
+        drop(chain);
+
+        let report = walk(&dir, server_key("api-ca", &[]));
+        assert_eq!(report.broken.len(), 1);
+        assert!(report.broken[0]
+            .reason
+            .starts_with("signed by unknown certificate"));
+
+        // Trusted once the certificate is configured as a previous one.
+        let report = walk(&dir, server_key("api-ca", &["api-server"]));
+        assert!(report.broken.is_empty());
+        assert_eq!(report.previous_signers, 1);
+
+        // A signature of another key is not taken for the certificate's.
+        let data = std::fs::read_to_string(dir.join(Chain::FILE_NAME)).unwrap();
+        let mut link = serde_json::from_str::<Link>(data.trim()).unwrap();
+        let other = server_key("api-ca", &[]);
+        link.signature = other.sign(link.hash.as_bytes()).unwrap();
+        let forged = format!("{}\n", serde_json::to_string(&link).unwrap());
+        let chain = Chain::open(
+            &dir,
+            server_key("api-ca", &["api-server"]),
+            &BTreeMap::new(),
+        )
+        .unwrap();
+        let report = chain.walk(forged.as_bytes()).0;
+        assert_eq!(report.broken.len(), 1);
+        assert_eq!(report.broken[0].reason, "invalid signature");
+    }
+
+    #[test]
+    fn found_blocks_are_reported_while_they_exist() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = server_key("api-server", &[]);
+        let id = Uuid::now_v7();
+        let entry = BlockEntry {
+            id,
+            stream: "s".to_string(),
+            version: 1,
+            partition: PathBuf::from("1970/01/01/00"),
+            rows: 1,
+            bytes: 1,
+            min_time: None,
+            max_time: None,
+            columns: BTreeMap::new(),
+            remote: None,
+            origin: None,
+            replicas: Vec::new(),
+            checksum: Some("00".to_string()),
+            damaged: None,
+        };
+
+        let chain = Chain::open(&dir, key.clone(), &BTreeMap::from([(id, entry)])).unwrap();
+        let report = chain.walk(&chain.read().unwrap()).0;
+        assert!(report.broken.is_empty());
+        assert_eq!(report.found.len(), 1);
+        assert_eq!((report.found[0].seq, report.found[0].block), (0, id));
+        assert_eq!(
+            report.warning().unwrap(),
+            "1 blocks linked only when found in the catalog"
+        );
+
+        chain.append(Event::Remove { block: id }).unwrap();
+        let report = chain.walk(&chain.read().unwrap()).0;
+        assert!(report.found.is_empty());
+        assert!(report.warning().is_none());
+    }
+}
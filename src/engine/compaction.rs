@@ -0,0 +1,574 @@
+use crate::engine::block;
+use crate::engine::block::BlockWriter;
+use crate::engine::block::Provenance;
+use crate::engine::catalog::block_id;
+use crate::engine::catalog::Catalog;
+use crate::engine::crypto::Keyring;
+use crate::engine::partition;
+use crate::engine::partition::TimeRange;
+use crate::picodata::service::ServiceWarnings;
+use arrow::array::AsArray;
+use arrow::array::RecordBatch;
+use arrow::compute::interleave;
+use arrow::datatypes::SchemaRef;
+use arrow::datatypes::TimestampNanosecondType;
+use arrow::error::ArrowError;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReader;
+use parquet::file::properties::WriterProperties;
+use serde::Deserialize;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::select;
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tokio_util::sync::DropGuard;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("block error: {0}")]
+    Block(#[from] block::Error),
+    #[error("arrow error: {0}")]
+    Arrow(#[from] ArrowError),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionConfig {
+    pub interval: Duration,
+    /// Blocks smaller than this are merged into blocks of about this size.
+    pub block_size: u64,
+    /// Bytes per second a stream's compaction may read and write.
+    pub throughput: u64,
+}
+
+/// Merges small blocks of a stream's partitions into large blocks sorted by
+/// the time field.
+pub struct Compactor {
+    stream: String,
+    catalog: Arc<Catalog>,
+    config: CompactionConfig,
+    rewriter: Rewriter,
+    sw: ServiceWarnings,
+}
+
+/// Swaps blocks of a partition for a block written in their place, so that
+/// readers see either all of the old blocks or the new one. Compaction and
+/// the jobs that change stored rows rewrite blocks through it.
+pub struct Rewriter {
+    catalog: Arc<Catalog>,
+    props: WriterProperties,
+}
+
+/// Written before a rewritten block is swapped in, so an interrupted swap
+/// can be finished on the next run.
+#[derive(Serialize, Deserialize)]
+struct Journal {
+    output: Uuid,
+    inputs: Vec<Uuid>,
+}
+
+/// Blocks of a group read side by side and merged by time.
+struct Merge {
+    schema: SchemaRef,
+    time: usize,
+    runs: Vec<Run>,
+    heap: BinaryHeap<Reverse<(i64, usize)>>,
+}
+
+struct Run {
+    reader: ParquetRecordBatchReader,
+    batch: RecordBatch,
+    row: usize,
+}
+
+struct Group {
+    schema: SchemaRef,
+    inputs: Vec<PathBuf>,
+    size: u64,
+}
+
+impl Compactor {
+    /// Starts compacting the catalog's blocks until the returned guard is
+    /// dropped.
+    pub fn spawn(
+        stream: String,
+        catalog: Arc<Catalog>,
+        config: CompactionConfig,
+        props: WriterProperties,
+        sw: ServiceWarnings,
+        tt: &TaskTracker,
+    ) -> DropGuard {
+        let ct = CancellationToken::new();
+        let compactor = Arc::new(Self {
+            stream,
+            rewriter: Rewriter::new(catalog.clone(), props),
+            catalog,
+            config,
+            sw,
+        });
+
+        tt.spawn(compactor.run(ct.clone()));
+        ct.drop_guard()
+    }
+
+    async fn run(self: Arc<Self>, ct: CancellationToken) {
+        let mut ticker = interval(self.config.interval);
+        ticker.tick().await;
+
+        loop {
+            select! {
+                _ = ct.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+
+            let result = select! {
+                _ = ct.cancelled() => return,
+                v = self.clone().compact_all() => v,
+            };
+
+            self.sw.set_job_error(
+                "compaction",
+                &self.stream,
+                result.err().map(|e| e.to_string()),
+            );
+        }
+    }
+
+    async fn compact_all(self: Arc<Self>) -> Result<(), Error> {
+        let this = self.clone();
+        let partitions =
+            spawn_blocking(move || partition::list(this.catalog.dir(), &TimeRange::default()))
+                .await
+                .unwrap()?;
+
+        for partition in partitions {
+            let this = self.clone();
+            let written = spawn_blocking(move || this.compact(&partition))
+                .await
+                .unwrap()?;
+
+            // Spread the work out so ingest keeps most of the disk.
+            let throughput = self.config.throughput.max(1) as f64;
+            sleep(Duration::from_secs_f64(written as f64 / throughput)).await;
+        }
+
+        Ok(())
+    }
+
+    /// Merges small blocks of a partition, returns the bytes read and written.
+    fn compact(&self, partition: &Path) -> Result<u64, Error> {
+        let _guard = self.catalog.lock_rewrite();
+        self.rewriter.recover(partition)?;
+
+        let mut groups = Vec::<Group>::new();
+        let mut full = Vec::new();
+        // Blocks are taken from the catalog, which has those being flushed
+        // only once they are fully written. Offloaded blocks have no local
+        // file, damaged ones are left alone until the scrub repairs them.
+        let relative = partition
+            .strip_prefix(self.catalog.dir())
+            .unwrap_or(partition);
+        let blocks = self
+            .catalog
+            .blocks(&TimeRange::default(), None)
+            .into_iter()
+            .filter(|v| v.partition == relative && v.remote.is_none() && v.damaged.is_none());
+
+        for entry in blocks {
+            let path = entry.path(self.catalog.dir());
+            let size = entry.bytes;
+            if size >= self.config.block_size {
+                continue;
+            }
+
+            // Blocks written under different stream definitions are kept apart.
+            let schema = block::schema(&path, self.catalog.keys())?;
+            let group = match groups.iter().position(|g| g.schema == schema) {
+                Some(i) if groups[i].size + size > self.config.block_size => {
+                    full.push(groups.swap_remove(i));
+                    groups.push(Group::new(schema));
+                    groups.last_mut().unwrap()
+                }
+                Some(i) => &mut groups[i],
+                None => {
+                    groups.push(Group::new(schema));
+                    groups.last_mut().unwrap()
+                }
+            };
+
+            group.inputs.push(path);
+            group.size += size;
+        }
+
+        let mut written = 0;
+        for group in full.into_iter().chain(groups) {
+            if group.inputs.len() > 1 {
+                written += self.merge(partition, &group)?;
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Merges the blocks of a group, which are sorted by time, a batch at a
+    /// time so that only a batch per input and the row group being written
+    /// are held in memory.
+    fn merge(&self, partition: &Path, group: &Group) -> Result<u64, Error> {
+        let mut merge = Merge::new(&group.inputs, &group.schema, self.catalog.keys())?;
+        // Inputs of a group share a definition, so any of them tells the origin.
+        let provenance = Provenance::read(&group.inputs[0], self.catalog.keys())?;
+        let inputs = group
+            .inputs
+            .iter()
+            .filter_map(|v| block_id(v))
+            .collect::<Vec<_>>();
+
+        let written = self.rewriter.replace_with(
+            partition,
+            &inputs,
+            group.schema.clone(),
+            &provenance,
+            |writer| {
+                while let Some(batch) = merge.next_batch()? {
+                    writer.write(&batch)?;
+                }
+                Ok(())
+            },
+        )?;
+        Ok(group.size + written.unwrap_or_default())
+    }
+}
+
+impl Rewriter {
+    const JOURNAL: &'static str = "compaction.json";
+
+    pub fn new(catalog: Arc<Catalog>, props: WriterProperties) -> Self {
+        Self { catalog, props }
+    }
+
+    pub fn catalog(&self) -> &Arc<Catalog> {
+        &self.catalog
+    }
+
+    pub fn props(&self) -> WriterProperties {
+        self.props.clone()
+    }
+
+    /// Replaces blocks of a partition with a block of the batch, or removes
+    /// them if there is none. Returns the bytes written, none if one of the
+    /// blocks was removed meanwhile and nothing was swapped.
+    pub fn replace(
+        &self,
+        partition: &Path,
+        inputs: &[Uuid],
+        batch: Option<&RecordBatch>,
+        provenance: &Provenance,
+    ) -> Result<Option<u64>, Error> {
+        let Some(batch) = batch else {
+            let _guard = self.catalog.lock_write();
+            if !inputs.iter().all(|v| self.catalog.get(*v).is_some()) {
+                return Ok(None);
+            }
+
+            self.catalog.remove(inputs)?;
+            for id in inputs {
+                block::remove_if_exists(&block::path(partition, *id))?;
+            }
+            return Ok(Some(0));
+        };
+
+        self.replace_with(partition, inputs, batch.schema(), provenance, |writer| {
+            Ok(writer.write(batch)?)
+        })
+    }
+
+    /// Like [`Self::replace`], with the new block written a batch at a time.
+    pub fn replace_with(
+        &self,
+        partition: &Path,
+        inputs: &[Uuid],
+        schema: SchemaRef,
+        provenance: &Provenance,
+        write: impl FnOnce(&mut BlockWriter) -> Result<(), Error>,
+    ) -> Result<Option<u64>, Error> {
+        let journal = Journal {
+            output: Uuid::now_v7(),
+            inputs: inputs.to_vec(),
+        };
+
+        let output = block::path(partition, journal.output);
+        let tmp = Self::tmp_path(&output);
+        let mut writer = BlockWriter::create(
+            &tmp,
+            schema,
+            self.props.clone(),
+            provenance,
+            self.catalog.keys(),
+        )?;
+        if let Err(e) = write(&mut writer).and_then(|()| Ok(writer.finish()?)) {
+            block::remove_if_exists(&tmp)?;
+            Err(e)?;
+        }
+        let size = std::fs::metadata(&tmp)?.len();
+        let entry = self.catalog.describe(&tmp)?;
+
+        let journal_path = partition.join(Self::JOURNAL);
+        std::fs::write(&journal_path, serde_json::to_vec(&journal)?)?;
+
+        let swapped = {
+            let _guard = self.catalog.lock_write();
+
+            // Inputs removed by retention meanwhile must not come back.
+            let swapped = inputs.iter().all(|v| self.catalog.get(*v).is_some());
+            if swapped {
+                std::fs::rename(&tmp, &output)?;
+                self.catalog.add(entry)?;
+                self.catalog.remove(inputs)?;
+                for id in inputs {
+                    block::remove_if_exists(&block::path(partition, *id))?;
+                }
+            } else {
+                std::fs::remove_file(&tmp)?;
+            }
+            swapped
+        };
+
+        std::fs::remove_file(journal_path)?;
+        Ok(swapped.then_some(size))
+    }
+
+    /// Finishes or rolls back a swap interrupted by a crash.
+    pub fn recover(&self, partition: &Path) -> Result<(), Error> {
+        let journal_path = partition.join(Self::JOURNAL);
+        let journal: Journal = match std::fs::read(&journal_path) {
+            Ok(v) => serde_json::from_slice(&v)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => Err(e)?,
+        };
+
+        let output = block::path(partition, journal.output);
+        match output.exists() {
+            true => {
+                let _guard = self.catalog.lock_write();
+                self.catalog.add(self.catalog.describe(&output)?)?;
+                self.catalog.remove(&journal.inputs)?;
+                for id in &journal.inputs {
+                    block::remove_if_exists(&block::path(partition, *id))?;
+                }
+            }
+            false => {
+                block::remove_if_exists(&Self::tmp_path(&output))?;
+            }
+        }
+
+        std::fs::remove_file(journal_path)?;
+        Ok(())
+    }
+
+    fn tmp_path(output: &Path) -> PathBuf {
+        output.with_extension("tmp")
+    }
+}
+
+impl Group {
+    fn new(schema: SchemaRef) -> Self {
+        Self {
+            schema,
+            inputs: Vec::new(),
+            size: 0,
+        }
+    }
+}
+
+impl Merge {
+    const BATCH_ROWS: usize = 8192;
+
+    fn new(inputs: &[PathBuf], schema: &SchemaRef, keys: Option<&Keyring>) -> Result<Self, Error> {
+        let time = schema.index_of(partition::time_field(schema))?;
+        let mut merge = Self {
+            schema: schema.clone(),
+            time,
+            runs: Vec::new(),
+            heap: BinaryHeap::new(),
+        };
+
+        for path in inputs {
+            let mut run = Run {
+                reader: block::reader(path, keys, Self::BATCH_ROWS)?,
+                batch: RecordBatch::new_empty(schema.clone()),
+                row: 0,
+            };
+            if run.advance()? {
+                merge.heap.push(Reverse((run.time(time), merge.runs.len())));
+            }
+            merge.runs.push(run);
+        }
+
+        Ok(merge)
+    }
+
+    /// Next rows in time order. A batch ends early when one of the inputs
+    /// needs its next batch, so that the rows taken from it can be copied.
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>, Error> {
+        let mut indices = Vec::new();
+
+        while let Some(Reverse((_, i))) = self.heap.pop() {
+            let run = &mut self.runs[i];
+            indices.push((i, run.row));
+            run.row += 1;
+
+            if run.row < run.batch.num_rows() {
+                self.heap.push(Reverse((run.time(self.time), i)));
+                if indices.len() < Self::BATCH_ROWS {
+                    continue;
+                }
+                return Ok(Some(self.take(&indices)?));
+            }
+
+            let batch = self.take(&indices)?;
+            let run = &mut self.runs[i];
+            if run.advance()? {
+                self.heap.push(Reverse((run.time(self.time), i)));
+            }
+            return Ok(Some(batch));
+        }
+
+        match indices.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(self.take(&indices)?)),
+        }
+    }
+
+    fn take(&self, indices: &[(usize, usize)]) -> Result<RecordBatch, ArrowError> {
+        let columns = (0..self.schema.fields().len())
+            .map(|c| {
+                let arrays = self
+                    .runs
+                    .iter()
+                    .map(|v| v.batch.column(c).as_ref())
+                    .collect::<Vec<_>>();
+                interleave(&arrays, indices)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        RecordBatch::try_new(self.schema.clone(), columns)
+    }
+}
+
+impl Run {
+    /// Moves to the next non-empty batch, false at the end of the block.
+    fn advance(&mut self) -> Result<bool, ArrowError> {
+        for batch in self.reader.by_ref() {
+            let batch = batch?;
+            if batch.num_rows() > 0 {
+                self.batch = batch;
+                self.row = 0;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn time(&self, column: usize) -> i64 {
+        self.batch
+            .column(column)
+            .as_primitive::<TimestampNanosecondType>()
+            .value(self.row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing::TempDir;
+    use crate::engine::schema::build_schema;
+    use crate::engine::schema::FieldDefinition;
+    use crate::engine::schema::FieldType;
+    use arrow::array::Int64Array;
+    use arrow::array::StringArray;
+    use arrow::array::TimestampNanosecondArray;
+    use arrow::datatypes::Int64Type;
+
+    fn batch(schema: &SchemaRef, times: Vec<i64>) -> RecordBatch {
+        let rows = times.len();
+        let text = |v: &str| Arc::new(StringArray::from(vec![v; rows])) as _;
+        RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                text("id"),
+                Arc::new(TimestampNanosecondArray::from(vec![0; rows]).with_timezone("UTC")),
+                text("i1"),
+                text("c"),
+                text("p"),
+                Arc::new(TimestampNanosecondArray::from(times.clone()).with_timezone("UTC")),
+                Arc::new(Int64Array::from(times)),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn merge_interleaves_sorted_blocks_by_time() {
+        let fields = [
+            FieldDefinition {
+                name: "ts".into(),
+                kind: FieldType::Timestamp,
+                nullable: false,
+            },
+            FieldDefinition {
+                name: "n".into(),
+                kind: FieldType::I64,
+                nullable: false,
+            },
+        ];
+        let schema = build_schema(&fields, "ts", 1);
+        let dir = TempDir::new();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let runs = [
+            (0..10_000).map(|v| v * 3).collect::<Vec<_>>(),
+            (0..100).map(|v| v * 7 + 1).collect(),
+            vec![],
+            vec![5, 5, 29_998],
+        ];
+        let inputs = runs
+            .iter()
+            .map(|times| {
+                let path = block::path(&dir, Uuid::now_v7());
+                let props = block::properties().build();
+                let batch = batch(&schema, times.clone());
+                block::write(&path, &batch, props, &Provenance::default(), None).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+
+        let mut merge = Merge::new(&inputs, &schema, None).unwrap();
+        let mut merged = Vec::new();
+        while let Some(batch) = merge.next_batch().unwrap() {
+            assert!(batch.num_rows() <= Merge::BATCH_ROWS);
+            let n = batch
+                .column_by_name("n")
+                .unwrap()
+                .as_primitive::<Int64Type>();
+            merged.extend(n.values().iter().copied());
+        }
+
+        let mut expected = runs.concat();
+        expected.sort();
+        assert_eq!(merged, expected);
+    }
+}
@@ -0,0 +1,549 @@
+use crate::engine::object_store::hex;
+use aws_lc_rs::aead::Aad;
+use aws_lc_rs::aead::LessSafeKey;
+use aws_lc_rs::aead::Nonce;
+use aws_lc_rs::aead::UnboundKey;
+use aws_lc_rs::aead::AES_256_GCM;
+use aws_lc_rs::aead::NONCE_LEN;
+use aws_lc_rs::rand;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use thiserror::Error;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid master key: {0}")]
+    InvalidMasterKey(String),
+    #[error("data key {0} cannot be unwrapped with the master key")]
+    Unwrap(Uuid),
+    #[error("data key {0} is destroyed")]
+    KeyDestroyed(Uuid),
+    #[error("block is encrypted but encryption is not configured")]
+    NotConfigured,
+    #[error("block is malformed or was tampered with")]
+    Malformed,
+}
+
+/// Marks an encrypted block file. It is followed by the id of the data key,
+/// the nonce and the sealed Parquet file.
+pub const MAGIC: &[u8; 8] = b"PLMSENC1";
+const HEADER_LEN: usize = MAGIC.len() + 16 + NONCE_LEN;
+const KEY_LEN: usize = 32;
+
+/// The key data keys are wrapped with. The previous key, if any, still
+/// unwraps keys wrapped before a rotation of the master key; they are
+/// rewrapped with the current one when a keyring is opened.
+pub struct MasterKey {
+    current: LessSafeKey,
+    previous: Option<LessSafeKey>,
+}
+
+/// A data key as stored, sealed with the master key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub id: Uuid,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    /// Hex of the nonce and the sealed key.
+    sealed: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyInfo {
+    pub id: Uuid,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    /// New blocks are encrypted with the current key.
+    pub current: bool,
+}
+
+/// Data keys of a catalog directory. Keys are created when the first block
+/// is encrypted and kept after a rotation, so older blocks stay readable.
+pub struct Keyring {
+    path: PathBuf,
+    master: Arc<MasterKey>,
+    state: Mutex<State>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Stored {
+    current: Option<Uuid>,
+    keys: Vec<WrappedKey>,
+}
+
+#[derive(Default)]
+struct State {
+    stored: Stored,
+    keys: HashMap<Uuid, Arc<LessSafeKey>>,
+}
+
+impl MasterKey {
+    /// A key file holds 32 bytes, raw or as hex.
+    pub fn load(path: &Path, previous: Option<&Path>) -> Result<Arc<Self>, Error> {
+        Ok(Arc::new(Self {
+            current: Self::read(path)?,
+            previous: previous.map(Self::read).transpose()?,
+        }))
+    }
+
+    fn read(path: &Path) -> Result<LessSafeKey, Error> {
+        let data = std::fs::read(path)?;
+        let key = match std::str::from_utf8(&data).ok().map(str::trim) {
+            Some(v) if v.len() == KEY_LEN * 2 => unhex(v),
+            _ => Some(data),
+        };
+
+        key.filter(|v| v.len() == KEY_LEN)
+            .and_then(|v| UnboundKey::new(&AES_256_GCM, &v).ok())
+            .map(LessSafeKey::new)
+            .ok_or_else(|| Error::InvalidMasterKey(path.display().to_string()))
+    }
+
+    fn wrap(&self, id: Uuid, key: &[u8]) -> WrappedKey {
+        WrappedKey {
+            id,
+            created_at: OffsetDateTime::now_utc(),
+            sealed: hex(&seal(&self.current, id.as_bytes(), key.to_vec())),
+        }
+    }
+
+    /// The key and whether it was wrapped with the previous master key.
+    fn unwrap(&self, wrapped: &WrappedKey) -> Result<(Vec<u8>, bool), Error> {
+        let sealed = unhex(&wrapped.sealed).ok_or(Error::Unwrap(wrapped.id))?;
+        let aad = wrapped.id.as_bytes();
+
+        if let Some(key) = open(&self.current, aad, sealed.clone()) {
+            return Ok((key, false));
+        }
+
+        self.previous
+            .as_ref()
+            .and_then(|v| open(v, aad, sealed))
+            .map(|v| (v, true))
+            .ok_or(Error::Unwrap(wrapped.id))
+    }
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MasterKey")
+    }
+}
+
+impl WrappedKey {
+    fn info(&self, current: Option<Uuid>) -> KeyInfo {
+        KeyInfo {
+            id: self.id,
+            created_at: self.created_at,
+            current: current == Some(self.id),
+        }
+    }
+}
+
+impl Keyring {
+    const FILE_NAME: &'static str = "keys.json";
+
+    pub fn open(dir: &Path, master: Arc<MasterKey>) -> Result<Arc<Self>, Error> {
+        let keyring = Self {
+            path: dir.join(Self::FILE_NAME),
+            master,
+            state: Default::default(),
+        };
+
+        let stored = match std::fs::read(&keyring.path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Stored::default(),
+            Err(e) => Err(e)?,
+        };
+
+        {
+            let mut state = keyring.state.lock().unwrap();
+            state.stored.current = stored.current;
+            let rewrapped = keyring.import_all(&mut state, stored.keys)?;
+
+            if rewrapped {
+                keyring.save(&state.stored)?;
+            }
+        }
+
+        Ok(Arc::new(keyring))
+    }
+
+    pub fn list(&self) -> Vec<KeyInfo> {
+        let state = self.state.lock().unwrap();
+        state
+            .stored
+            .keys
+            .iter()
+            .map(|v| v.info(state.stored.current))
+            .collect()
+    }
+
+    /// Makes a new key current, the old ones only decrypt.
+    pub fn rotate(&self) -> Result<KeyInfo, Error> {
+        let mut state = self.state.lock().unwrap();
+        let id = self.generate(&mut state)?;
+        Ok(state.stored.keys.last().unwrap().info(Some(id)))
+    }
+
+    /// Destroys all keys, which makes the blocks encrypted with them
+    /// unreadable wherever their copies are. Returns the destroyed ids.
+    pub fn shred(&self) -> Result<Vec<Uuid>, Error> {
+        let mut state = self.state.lock().unwrap();
+        let ids = state.stored.keys.iter().map(|v| v.id).collect();
+        *state = State::default();
+
+        // Overwritten before it is removed. The keys stay wrapped with the
+        // master key wherever the file system keeps old copies.
+        match File::options().write(true).open(&self.path) {
+            Ok(mut file) => {
+                let len = file.metadata()?.len();
+                file.write_all(&vec![0; len as usize])?;
+                file.sync_all()?;
+                std::fs::remove_file(&self.path)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => Err(e)?,
+        }
+
+        Ok(ids)
+    }
+
+    /// Destroys the keys other than `ids`, as their owner did.
+    pub fn retain(&self, ids: &HashSet<Uuid>) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.keys.keys().all(|v| ids.contains(v)) {
+            return Ok(());
+        }
+
+        state.keys.retain(|id, _| ids.contains(id));
+        state.stored.keys.retain(|v| ids.contains(&v.id));
+        self.save(&state.stored)
+    }
+
+    pub fn export(&self, id: Uuid) -> Result<WrappedKey, Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .stored
+            .keys
+            .iter()
+            .find(|v| v.id == id)
+            .cloned()
+            .ok_or(Error::KeyDestroyed(id))
+    }
+
+    /// Adds keys of blocks shipped or restored from elsewhere. They must be
+    /// wrapped with the same master key.
+    pub fn import(&self, keys: Vec<WrappedKey>) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let keys = keys
+            .into_iter()
+            .filter(|v| !state.keys.contains_key(&v.id))
+            .collect::<Vec<_>>();
+
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        self.import_all(&mut state, keys)?;
+        self.save(&state.stored)
+    }
+
+    /// Seals a block file with the current key, created if there is none.
+    pub fn encrypt(&self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let (id, key) = {
+            let mut state = self.state.lock().unwrap();
+            let id = match state.stored.current {
+                Some(id) => id,
+                None => self.generate(&mut state)?,
+            };
+            (id, state.keys[&id].clone())
+        };
+
+        // The header is authenticated along with the data.
+        let mut block = MAGIC.to_vec();
+        block.extend(id.as_bytes());
+        let sealed = seal(&key, &block, data);
+        block.extend(sealed);
+        Ok(block)
+    }
+
+    pub fn decrypt(&self, mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let id = key_id(&data).ok_or(Error::Malformed)?;
+        let key = self
+            .state
+            .lock()
+            .unwrap()
+            .keys
+            .get(&id)
+            .cloned()
+            .ok_or(Error::KeyDestroyed(id))?;
+
+        let sealed = data.split_off(MAGIC.len() + 16);
+        open(&key, &data, sealed).ok_or(Error::Malformed)
+    }
+
+    fn generate(&self, state: &mut State) -> Result<Uuid, Error> {
+        let mut key = [0; KEY_LEN];
+        rand::fill(&mut key).unwrap();
+        let id = Uuid::now_v7();
+
+        state.stored.keys.push(self.master.wrap(id, &key));
+        state.stored.current = Some(id);
+        state.keys.insert(id, Arc::new(data_key(&key)));
+        self.save(&state.stored)?;
+        Ok(id)
+    }
+
+    /// Returns whether any key had to be rewrapped with the current master key.
+    fn import_all(&self, state: &mut State, keys: Vec<WrappedKey>) -> Result<bool, Error> {
+        let mut rewrapped = false;
+
+        for wrapped in keys {
+            let (key, previous) = self.master.unwrap(&wrapped)?;
+            let wrapped = match previous {
+                true => WrappedKey {
+                    created_at: wrapped.created_at,
+                    ..self.master.wrap(wrapped.id, &key)
+                },
+                false => wrapped,
+            };
+
+            rewrapped |= previous;
+            state.keys.insert(wrapped.id, Arc::new(data_key(&key)));
+            state.stored.keys.push(wrapped);
+        }
+
+        Ok(rewrapped)
+    }
+
+    fn save(&self, stored: &Stored) -> Result<(), Error> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec_pretty(stored)?)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Id of the data key of an encrypted block file on disk.
+pub fn read_key_id(path: &Path) -> Result<Option<Uuid>, Error> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(path)?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+    Ok(key_id(&header))
+}
+
+/// Id of the data key of an encrypted block file.
+pub fn key_id(data: &[u8]) -> Option<Uuid> {
+    if data.len() < HEADER_LEN || !data.starts_with(MAGIC) {
+        return None;
+    }
+
+    Uuid::from_slice(&data[MAGIC.len()..MAGIC.len() + 16]).ok()
+}
+
+fn data_key(key: &[u8]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap())
+}
+
+/// The random nonce followed by the sealed data and its tag.
+fn seal(key: &LessSafeKey, aad: &[u8], mut data: Vec<u8>) -> Vec<u8> {
+    let mut nonce = [0; NONCE_LEN];
+    rand::fill(&mut nonce).unwrap();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(aad),
+        &mut data,
+    )
+    .unwrap();
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(data);
+    sealed
+}
+
+fn open(key: &LessSafeKey, aad: &[u8], mut sealed: Vec<u8>) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+
+    let mut data = sealed.split_off(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&sealed).ok()?;
+    let len = key
+        .open_in_place(nonce, Aad::from(aad), &mut data)
+        .ok()?
+        .len();
+    data.truncate(len);
+    Some(data)
+}
+
+pub fn unhex(v: &str) -> Option<Vec<u8>> {
+    (0..v.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(v.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing::TempDir;
+
+    fn master(dir: &Path, name: &str, byte: u8) -> PathBuf {
+        std::fs::create_dir_all(dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, [byte; KEY_LEN]).unwrap();
+        path
+    }
+
+    #[test]
+    fn master_keys_are_raw_or_hex() {
+        let dir = TempDir::new();
+        let raw = master(&dir, "raw.key", 7);
+        let hex_path = dir.join("hex.key");
+        std::fs::write(&hex_path, format!("{}\n", hex(&[7; KEY_LEN]))).unwrap();
+        let short = dir.join("short.key");
+        std::fs::write(&short, [7; 16]).unwrap();
+
+        // Both forms unwrap the same data keys.
+        let keys = Keyring::open(&dir, MasterKey::load(&raw, None).unwrap()).unwrap();
+        let block = keys.encrypt(b"data".to_vec()).unwrap();
+        let keys = Keyring::open(&dir, MasterKey::load(&hex_path, None).unwrap()).unwrap();
+        assert_eq!(keys.decrypt(block).unwrap(), b"data");
+
+        assert!(matches!(
+            MasterKey::load(&short, None),
+            Err(Error::InvalidMasterKey(_))
+        ));
+    }
+
+    #[test]
+    fn blocks_round_trip_and_detect_tampering() {
+        let dir = TempDir::new();
+        let master = MasterKey::load(&master(&dir, "master.key", 7), None).unwrap();
+        let keys = Keyring::open(&dir, master).unwrap();
+
+        let block = keys.encrypt(b"data".to_vec()).unwrap();
+        assert!(block.starts_with(MAGIC));
+        assert_eq!(key_id(&block), Some(keys.list()[0].id));
+        assert_eq!(keys.decrypt(block.clone()).unwrap(), b"data");
+
+        // The header is authenticated as well as the data.
+        for i in [MAGIC.len() + 1, block.len() - 1] {
+            let mut tampered = block.clone();
+            tampered[i] ^= 0xff;
+            assert!(keys.decrypt(tampered).is_err());
+        }
+        assert!(matches!(
+            keys.decrypt(b"plain".to_vec()),
+            Err(Error::Malformed)
+        ));
+    }
+
+    #[test]
+    fn rotated_keys_still_decrypt() {
+        let dir = TempDir::new();
+        let master = MasterKey::load(&master(&dir, "master.key", 7), None).unwrap();
+        let keys = Keyring::open(&dir, master.clone()).unwrap();
+
+        let old = keys.encrypt(b"old".to_vec()).unwrap();
+        let rotated = keys.rotate().unwrap();
+        let new = keys.encrypt(b"new".to_vec()).unwrap();
+        assert_eq!(key_id(&new), Some(rotated.id));
+        assert_ne!(key_id(&old), key_id(&new));
+
+        // Reopened from the file.
+        let keys = Keyring::open(&dir, master).unwrap();
+        let list = keys.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list.iter()
+                .filter(|v| v.current)
+                .map(|v| v.id)
+                .collect::<Vec<_>>(),
+            [rotated.id]
+        );
+        assert_eq!(keys.decrypt(old).unwrap(), b"old");
+        assert_eq!(keys.decrypt(new).unwrap(), b"new");
+    }
+
+    #[test]
+    fn keys_are_rewrapped_with_a_new_master_key() {
+        let dir = TempDir::new();
+        let old = master(&dir, "old.key", 7);
+        let new = master(&dir, "new.key", 8);
+
+        let keys = Keyring::open(&dir, MasterKey::load(&old, None).unwrap()).unwrap();
+        let block = keys.encrypt(b"data".to_vec()).unwrap();
+
+        assert!(matches!(
+            Keyring::open(&dir, MasterKey::load(&new, None).unwrap()),
+            Err(Error::Unwrap(_))
+        ));
+        Keyring::open(&dir, MasterKey::load(&new, Some(&old)).unwrap()).unwrap();
+
+        // The old master key is not needed any more.
+        let keys = Keyring::open(&dir, MasterKey::load(&new, None).unwrap()).unwrap();
+        assert_eq!(keys.decrypt(block).unwrap(), b"data");
+    }
+
+    #[test]
+    fn shredded_keys_are_gone() {
+        let dir = TempDir::new();
+        let master = MasterKey::load(&master(&dir, "master.key", 7), None).unwrap();
+        let keys = Keyring::open(&dir, master.clone()).unwrap();
+        let block = keys.encrypt(b"data".to_vec()).unwrap();
+        let id = key_id(&block).unwrap();
+
+        assert_eq!(keys.shred().unwrap(), [id]);
+        assert!(matches!(keys.decrypt(block.clone()), Err(Error::KeyDestroyed(v)) if v == id));
+        assert!(matches!(keys.export(id), Err(Error::KeyDestroyed(_))));
+        assert!(!dir.join(Keyring::FILE_NAME).exists());
+
+        let keys = Keyring::open(&dir, master).unwrap();
+        assert!(keys.list().is_empty());
+        assert!(keys.decrypt(block).is_err());
+    }
+
+    #[test]
+    fn keys_move_between_keyrings_of_one_master_key() {
+        let dir = TempDir::new();
+        let master = MasterKey::load(&master(&dir, "master.key", 7), None).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        std::fs::create_dir_all(&a).unwrap();
+        std::fs::create_dir_all(&b).unwrap();
+        let source = Keyring::open(&a, master.clone()).unwrap();
+        let target = Keyring::open(&b, master).unwrap();
+
+        let block = source.encrypt(b"data".to_vec()).unwrap();
+        let id = key_id(&block).unwrap();
+        assert!(target.decrypt(block.clone()).is_err());
+
+        target.import(vec![source.export(id).unwrap()]).unwrap();
+        assert_eq!(target.decrypt(block.clone()).unwrap(), b"data");
+        // Imported keys do not become current.
+        assert!(target.list().iter().all(|v| !v.current));
+
+        target.retain(&HashSet::new()).unwrap();
+        assert!(target.decrypt(block).is_err());
+    }
+}
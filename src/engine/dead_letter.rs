@@ -0,0 +1,217 @@
+use crate::engine::accumulator;
+use crate::engine::accumulator::Accumulator;
+use crate::engine::accumulator::FailedRows;
+use crate::engine::accumulator::Origin;
+use crate::engine::accumulator::Rows;
+use crate::engine::accumulator::Writer;
+use crate::engine::block;
+use crate::engine::catalog::block_id;
+use crate::engine::catalog::Catalog;
+use crate::engine::compaction::Rewriter;
+use crate::engine::partition::TimeRange;
+use crate::engine::pipeline::Pipeline;
+use crate::engine::schema::build_schema;
+use crate::engine::schema::FieldDefinition;
+use crate::engine::schema::FieldType;
+use crate::engine::schema::RECEIVED_AT;
+use crate::engine::storage::Storage;
+use crate::engine::Config;
+use crate::picodata::service::ServiceWarnings;
+use parquet::file::properties::WriterProperties;
+use serde_json::json;
+use serde_json::Value as JsonValue;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tokio::sync::MutexGuard;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tokio_util::task::TaskTracker;
+
+/// Rows rejected by a stream, kept with the rejection context so they can be
+/// inspected and re-ingested once the stream definition is fixed.
+pub struct DeadLetter {
+    catalog: Arc<Catalog>,
+    accumulator: Accumulator,
+    replay_lock: Mutex<()>,
+}
+
+impl DeadLetter {
+    pub const DIR_NAME: &'static str = "dead_letter";
+    /// Field holding the rejected row as JSON text.
+    pub const ROW: &'static str = "row";
+    const SCHEMA_VERSION: u64 = 1;
+
+    pub fn new(
+        catalog: Arc<Catalog>,
+        config: &Config,
+        tt: &TaskTracker,
+        sw: &ServiceWarnings,
+    ) -> Self {
+        Self {
+            accumulator: Accumulator::new(
+                build_schema(&Self::fields(), RECEIVED_AT, Self::SCHEMA_VERSION),
+                Pipeline::default(),
+                config.limits,
+                config.instance_id.clone(),
+                config.memory.clone(),
+                Writer::new(
+                    Self::properties(),
+                    catalog.clone(),
+                    "dead letter flush",
+                    sw.clone(),
+                ),
+                tt,
+            ),
+            catalog,
+            replay_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn catalog(&self) -> &Arc<Catalog> {
+        &self.catalog
+    }
+
+    /// Rewrites blocks with the properties they are flushed with.
+    pub fn rewriter(&self) -> Rewriter {
+        Rewriter::new(self.catalog.clone(), Self::properties())
+    }
+
+    pub async fn write(
+        &self,
+        stream: &str,
+        origin: &Origin,
+        failed: &FailedRows,
+    ) -> Result<(), accumulator::Error> {
+        let received_at = origin.received_at.format(&Rfc3339).ok();
+        let rows = failed
+            .iter()
+            .map(|f| {
+                json!({
+                    "stream": stream,
+                    "reason": f.reason,
+                    "client": origin.client,
+                    "received_at": received_at,
+                    Self::ROW: f.row.to_string(),
+                })
+            })
+            .collect();
+
+        self.accumulator.add_rows(Rows::Json(rows), origin).await?;
+
+        Ok(())
+    }
+
+    /// Like `write`, but waits out a full queue or memory budget instead of
+    /// failing, for callers whose accepted rows cannot be taken back.
+    pub async fn write_waiting(
+        &self,
+        stream: &str,
+        origin: &Origin,
+        failed: &FailedRows,
+    ) -> Result<(), accumulator::Error> {
+        loop {
+            match self.write(stream, origin, failed).await {
+                Err(
+                    accumulator::Error::QueueFull(v) | accumulator::Error::MemoryBudgetExceeded(v),
+                ) => sleep(v).await,
+                v => return v,
+            }
+        }
+    }
+
+    /// Flushed dead-letter blocks, oldest first. The returned guard keeps
+    /// concurrent replays from taking the same blocks.
+    pub async fn blocks(&self) -> (MutexGuard<'_, ()>, Vec<PathBuf>) {
+        let guard = self.replay_lock.lock().await;
+        let blocks = self
+            .catalog
+            .blocks(&TimeRange::default(), None)
+            .into_iter()
+            .map(|v| v.path(self.catalog.dir()))
+            .collect();
+
+        (guard, blocks)
+    }
+
+    /// Removes a replayed block.
+    pub fn remove(&self, path: &Path) -> Result<(), block::Error> {
+        let _guard = self.catalog.lock_write();
+        std::fs::remove_file(path)?;
+        self.catalog.remove(&Vec::from_iter(block_id(path)))
+    }
+
+    /// Original rows stored in a dead-letter block, grouped by the origin
+    /// they were received with. `fallback` stands in for a missing receive
+    /// time.
+    pub async fn rows(
+        &self,
+        path: PathBuf,
+        fallback: &Origin,
+    ) -> Result<Vec<(Origin, Vec<JsonValue>)>, block::Error> {
+        let catalog = self.catalog.clone();
+        let received_at = fallback.received_at;
+        spawn_blocking(move || Self::read_rows(&path, &catalog, received_at))
+            .await
+            .unwrap()
+    }
+
+    fn read_rows(
+        path: &Path,
+        catalog: &Catalog,
+        fallback: OffsetDateTime,
+    ) -> Result<Vec<(Origin, Vec<JsonValue>)>, block::Error> {
+        let mut groups: Vec<(Origin, Vec<JsonValue>)> = Vec::new();
+
+        for batch in block::read(path, catalog.keys())? {
+            for row in block::to_json_rows(&batch) {
+                let text = |name| row.get(name).and_then(|v| v.as_str());
+                let origin = Origin {
+                    client: text("client").map(|v| v.to_string()),
+                    peer: None,
+                    received_at: text("received_at")
+                        .and_then(|v| OffsetDateTime::parse(v, &Rfc3339).ok())
+                        .unwrap_or(fallback),
+                };
+                let original = text(Self::ROW)
+                    .and_then(|v| serde_json::from_str(v).ok())
+                    .unwrap_or(JsonValue::Null);
+
+                match groups.last_mut() {
+                    Some((last, rows))
+                        if last.client == origin.client
+                            && last.received_at == origin.received_at =>
+                    {
+                        rows.push(original)
+                    }
+                    _ => groups.push((origin, vec![original])),
+                }
+            }
+        }
+
+        Ok(groups)
+    }
+
+    fn properties() -> WriterProperties {
+        Storage::default().properties(Accumulator::MAX_ROWS)
+    }
+
+    fn fields() -> Vec<FieldDefinition> {
+        let field = |name: &str, nullable| FieldDefinition {
+            name: name.into(),
+            kind: FieldType::String,
+            nullable,
+        };
+
+        vec![
+            field("stream", false),
+            field("reason", false),
+            field("client", true),
+            field("received_at", true),
+            field(Self::ROW, false),
+        ]
+    }
+}
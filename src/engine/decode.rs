@@ -0,0 +1,404 @@
+use crate::engine::accumulator::Error;
+use crate::engine::schema::DomainField;
+use crate::engine::schema::SYSTEM_FIELDS;
+use arrow::array::ArrayBuilder;
+use arrow::array::BooleanBuilder;
+use arrow::array::Float64Builder;
+use arrow::array::Int64Builder;
+use arrow::array::ListBuilder;
+use arrow::array::StringBuilder;
+use arrow::array::TimestampNanosecondBuilder;
+use arrow::datatypes::DataType;
+use arrow::datatypes::Field;
+use arrow::datatypes::FieldRef;
+use arrow::datatypes::Schema;
+use arrow::datatypes::TimeUnit;
+use serde::de::DeserializeSeed;
+use serde::de::IgnoredAny;
+use serde::de::MapAccess;
+use serde::de::SeqAccess;
+use serde::de::Visitor;
+use serde::Deserializer;
+use serde_json::value::RawValue;
+use serde_json::Value as JsonValue;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Decodes JSON rows straight into the column builders of a schema, without
+/// building a `serde_json::Value` per row. Rows given as values go through
+/// the same tokens, so both paths fail rows for the same reasons.
+pub struct Decoder {
+    fields: Vec<FieldRef>,
+    lookup: HashMap<String, usize>,
+}
+
+/// A JSON value of a known field; strings are borrowed from the row when
+/// they need no unescaping, objects are skipped.
+enum Token<'a> {
+    Null,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(Cow<'a, str>),
+    List(Vec<Token<'a>>),
+    Object,
+}
+
+struct RowSeed<'a, 'de> {
+    lookup: &'a HashMap<String, usize>,
+    tokens: &'a mut Vec<Token<'de>>,
+}
+
+struct KeySeed<'a>(&'a HashMap<String, usize>);
+
+struct TokenSeed;
+
+impl Decoder {
+    pub fn new(schema: &Schema) -> Self {
+        let fields = schema.fields()[SYSTEM_FIELDS..].to_vec();
+        let lookup = fields
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.name().clone(), i))
+            .collect();
+
+        Self { fields, lookup }
+    }
+
+    /// Appends a row to the user field builders, which follow the system
+    /// field builders. A failed row leaves the builders untouched.
+    pub fn decode(
+        &self,
+        row: &RawValue,
+        builders: &mut [Box<dyn ArrayBuilder>],
+    ) -> Result<(), Error> {
+        let mut tokens = Vec::new();
+        tokens.resize_with(self.fields.len(), || Token::Null);
+
+        let mut de = serde_json::Deserializer::from_str(row.get());
+        let seed = RowSeed {
+            lookup: &self.lookup,
+            tokens: &mut tokens,
+        };
+        seed.deserialize(&mut de)
+            .map_err(|_| Error::ObjectExpected)?;
+
+        self.append(tokens, builders)
+    }
+
+    /// Appends a row given as a `serde_json::Value`, with the same checks.
+    pub fn decode_value(
+        &self,
+        row: &JsonValue,
+        builders: &mut [Box<dyn ArrayBuilder>],
+    ) -> Result<(), Error> {
+        let JsonValue::Object(object) = row else {
+            Err(Error::ObjectExpected)?
+        };
+
+        let tokens = self
+            .fields
+            .iter()
+            .map(|f| object.get(f.name()).map_or(Token::Null, Token::from))
+            .collect();
+
+        self.append(tokens, builders)
+    }
+
+    /// Appends a token per user field, checking the whole row first so a bad
+    /// field does not leave the builders with columns of different lengths.
+    fn append(
+        &self,
+        tokens: Vec<Token>,
+        builders: &mut [Box<dyn ArrayBuilder>],
+    ) -> Result<(), Error> {
+        for (f, token) in self.fields.iter().zip(&tokens) {
+            match token {
+                Token::Null if !f.is_nullable() => Err(Error::MissingField(f.name().clone()))?,
+                Token::Null => {}
+                v => v.check(f)?,
+            }
+        }
+
+        let builders = &mut builders[SYSTEM_FIELDS..];
+        for ((f, token), b) in self.fields.iter().zip(tokens).zip(builders) {
+            token.append(f, b.as_mut());
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> From<&'a JsonValue> for Token<'a> {
+    fn from(value: &'a JsonValue) -> Self {
+        match value {
+            JsonValue::Null => Self::Null,
+            JsonValue::Bool(v) => Self::Bool(*v),
+            JsonValue::Number(v) => match (v.as_i64(), v.as_u64()) {
+                (Some(v), _) => Self::I64(v),
+                (None, Some(v)) => Self::U64(v),
+                _ => v.as_f64().map_or(Self::Null, Self::F64),
+            },
+            JsonValue::String(v) => Self::Str(Cow::Borrowed(v)),
+            JsonValue::Array(v) => Self::List(v.iter().map(Self::from).collect()),
+            JsonValue::Object(_) => Self::Object,
+        }
+    }
+}
+
+impl Token<'_> {
+    fn check(&self, f: &Field) -> Result<(), Error> {
+        let valid = match f.data_type() {
+            DataType::List(nested) => {
+                let Self::List(items) = self else {
+                    Err(Error::TypeMissmatch(f.name().clone()))?
+                };
+
+                if !items.iter().all(|v| v.is(nested.data_type())) {
+                    Err(Error::HomogeneousArrayExpected(f.name().clone()))?;
+                }
+
+                true
+            }
+            v => self.is(v),
+        };
+
+        match valid {
+            true => Ok(()),
+            false => Err(Error::TypeMissmatch(f.name().clone())),
+        }
+    }
+
+    fn is(&self, data_type: &DataType) -> bool {
+        match data_type {
+            DataType::Utf8 => matches!(self, Self::Str(_)),
+            DataType::Int64 => self.as_i64().is_some(),
+            DataType::Float64 => self.as_f64().is_some(),
+            DataType::Boolean => matches!(self, Self::Bool(_)),
+            DataType::Timestamp(TimeUnit::Nanosecond, _) => self.as_timestamp().is_some(),
+            _ => false,
+        }
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::I64(v) => Some(*v),
+            Self::U64(v) => (*v).try_into().ok(),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::I64(v) => Some(*v as f64),
+            Self::U64(v) => Some(*v as f64),
+            Self::F64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// Timestamps are given as RFC 3339 strings.
+    fn as_timestamp(&self) -> Option<i64> {
+        let v = OffsetDateTime::parse(self.as_str()?, &Rfc3339).ok()?;
+        v.unix_timestamp_nanos().try_into().ok()
+    }
+
+    /// Appends a checked token.
+    fn append(self, f: &Field, builder: &mut dyn ArrayBuilder) {
+        if matches!(self, Self::Null) {
+            f.append_null(builder);
+            return;
+        }
+
+        let b = builder.as_any_mut();
+
+        match f.data_type() {
+            DataType::Utf8 => b
+                .downcast_mut::<StringBuilder>()
+                .unwrap()
+                .append_value(self.as_str().unwrap()),
+
+            DataType::Int64 => b
+                .downcast_mut::<Int64Builder>()
+                .unwrap()
+                .append_value(self.as_i64().unwrap()),
+
+            DataType::Float64 => b
+                .downcast_mut::<Float64Builder>()
+                .unwrap()
+                .append_value(self.as_f64().unwrap()),
+
+            DataType::Boolean => b
+                .downcast_mut::<BooleanBuilder>()
+                .unwrap()
+                .append_value(self.as_bool().unwrap()),
+
+            DataType::Timestamp(TimeUnit::Nanosecond, _) => b
+                .downcast_mut::<TimestampNanosecondBuilder>()
+                .unwrap()
+                .append_value(self.as_timestamp().unwrap()),
+
+            DataType::List(nested) => {
+                let Self::List(items) = self else {
+                    unreachable!();
+                };
+
+                match nested.data_type() {
+                    DataType::Utf8 => b
+                        .downcast_mut::<ListBuilder<StringBuilder>>()
+                        .unwrap()
+                        .append_value(items.iter().map(|v| v.as_str())),
+
+                    DataType::Int64 => b
+                        .downcast_mut::<ListBuilder<Int64Builder>>()
+                        .unwrap()
+                        .append_value(items.iter().map(|v| v.as_i64())),
+
+                    DataType::Float64 => b
+                        .downcast_mut::<ListBuilder<Float64Builder>>()
+                        .unwrap()
+                        .append_value(items.iter().map(|v| v.as_f64())),
+
+                    DataType::Boolean => b
+                        .downcast_mut::<ListBuilder<BooleanBuilder>>()
+                        .unwrap()
+                        .append_value(items.iter().map(|v| v.as_bool())),
+
+                    _ => unreachable!(),
+                }
+            }
+
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for RowSeed<'_, 'de> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RowSeed<'_, 'de> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        // A repeated key takes the last value, as with `serde_json::Value`.
+        while let Some(key) = map.next_key_seed(KeySeed(self.lookup))? {
+            match key {
+                Some(i) => self.tokens[i] = map.next_value_seed(TokenSeed)?,
+                None => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for KeySeed<'_> {
+    type Value = Option<usize>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de> Visitor<'de> for KeySeed<'_> {
+    type Value = Option<usize>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a field name")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(self.0.get(v).copied())
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for TokenSeed {
+    type Value = Token<'de>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for TokenSeed {
+    type Value = Token<'de>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(Token::Null)
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(Token::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(Token::I64(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(Token::U64(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(Token::F64(v))
+    }
+
+    fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E> {
+        Ok(Token::Str(Cow::Borrowed(v)))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(Token::Str(Cow::Owned(v.to_string())))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Self::Value, E> {
+        Ok(Token::Str(Cow::Owned(v)))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut items = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+        while let Some(v) = seq.next_element_seed(TokenSeed)? {
+            items.push(v);
+        }
+
+        Ok(Token::List(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        Ok(Token::Object)
+    }
+}
@@ -0,0 +1,179 @@
+use crate::engine::accumulator::FailedRows;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::OnceCell;
+
+pub type BatchSlot = Arc<OnceCell<Inserted>>;
+
+/// Outcome of an insert.
+#[derive(Debug, Clone, Default)]
+pub struct Inserted {
+    /// Rows rejected by the stream, sent to the dead letter.
+    pub failed: FailedRows,
+    /// Rows dropped because their id was seen within the dedup window.
+    pub duplicates: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Idempotency {
+    /// Seconds a batch key or row id is remembered for.
+    #[serde(default = "Idempotency::default_window")]
+    pub window: u64,
+    /// Row field holding a client-supplied row id.
+    #[serde(default)]
+    pub id_field: Option<String>,
+}
+
+/// Recently seen batch keys and row ids of a stream. Kept in memory only, so
+/// the window starts over after a restart.
+#[derive(Default)]
+pub struct Dedup {
+    batches: Mutex<Window<BatchSlot>>,
+    rows: Mutex<Window<()>>,
+}
+
+struct Window<T> {
+    entries: HashMap<String, (Instant, T)>,
+    order: VecDeque<(Instant, String)>,
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self {
+            window: Self::default_window(),
+            id_field: None,
+        }
+    }
+}
+
+impl Idempotency {
+    fn default_window() -> u64 {
+        600
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window)
+    }
+
+    /// Client-supplied id of a row, if the stream has an id field.
+    pub fn row_id(&self, row: &JsonValue) -> Option<String> {
+        match row.get(self.id_field.as_ref()?) {
+            Some(JsonValue::String(v)) => Some(v.clone()),
+            Some(JsonValue::Null) | None => None,
+            Some(v) => Some(v.to_string()),
+        }
+    }
+}
+
+impl Dedup {
+    /// Result slot of a batch. The first caller fills it, retries with the
+    /// same key wait for and get the same result.
+    pub fn batch(&self, key: &str, window: Duration) -> BatchSlot {
+        let mut batches = self.batches.lock().unwrap();
+        batches.evict(window);
+
+        if let Some((_, slot)) = batches.entries.get(key) {
+            return slot.clone();
+        }
+
+        let slot = BatchSlot::default();
+        batches.insert(key.to_string(), slot.clone());
+        slot
+    }
+
+    /// Claims row ids, returning for each whether it was not seen before.
+    pub fn claim_rows(&self, ids: &[Option<String>], window: Duration) -> Vec<bool> {
+        let mut rows = self.rows.lock().unwrap();
+        rows.evict(window);
+
+        ids.iter()
+            .map(|id| match id {
+                Some(id) if rows.entries.contains_key(id) => false,
+                Some(id) => {
+                    rows.insert(id.clone(), ());
+                    true
+                }
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Forgets row ids claimed by an insert that did not go through.
+    pub fn release_rows(&self, ids: &[Option<String>]) {
+        let mut rows = self.rows.lock().unwrap();
+        for id in ids.iter().flatten() {
+            rows.entries.remove(id);
+        }
+    }
+}
+
+impl<T> Default for Window<T> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> Window<T> {
+    fn insert(&mut self, key: String, value: T) {
+        let now = Instant::now();
+        self.order.push_back((now, key.clone()));
+        self.entries.insert(key, (now, value));
+    }
+
+    fn evict(&mut self, window: Duration) {
+        while let Some((at, _)) = self.order.front() {
+            if at.elapsed() < window {
+                break;
+            }
+
+            let (at, key) = self.order.pop_front().unwrap();
+
+            // The key may have been released and claimed again since.
+            if self.entries.get(&key).is_some_and(|(v, _)| *v == at) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn row_ids_are_claimed_once_until_released() {
+        let dedup = Dedup::default();
+        let window = Duration::from_secs(60);
+        let ids = [Some("a".to_string()), Some("b".to_string()), None];
+
+        assert_eq!(dedup.claim_rows(&ids, window), [true, true, true]);
+        assert_eq!(dedup.claim_rows(&ids, window), [false, false, true]);
+
+        dedup.release_rows(&ids[..1]);
+        assert_eq!(dedup.claim_rows(&ids, window), [true, false, true]);
+    }
+
+    #[test]
+    fn row_id_reads_the_id_field() {
+        let idempotency = Idempotency {
+            window: 60,
+            id_field: Some("id".into()),
+        };
+
+        assert_eq!(idempotency.row_id(&json!({"id": "x"})), Some("x".into()));
+        assert_eq!(idempotency.row_id(&json!({"id": 7})), Some("7".into()));
+        assert_eq!(idempotency.row_id(&json!({"id": null})), None);
+        assert_eq!(idempotency.row_id(&json!({})), None);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::engine::catalog::BlockEntry;
+use crate::engine::retention;
+use crate::engine::Streams;
+use crate::picodata::service::ServiceWarnings;
+use nix::sys::statvfs::statvfs;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::select;
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+use tokio_util::sync::DropGuard;
+use tokio_util::task::TaskTracker;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("statvfs: {0}")]
+    Statvfs(#[from] nix::Error),
+    #[error("retention: {0}")]
+    Retention(#[from] retention::Error),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiskConfig {
+    pub interval: Duration,
+    /// Free bytes below which a warning is raised.
+    pub soft_limit: u64,
+    /// Free bytes below which inserts are rejected.
+    pub hard_limit: u64,
+    /// Below the hard limit, remove the oldest blocks of streams that are
+    /// not under legal hold until the limit is met again.
+    pub emergency_retention: bool,
+}
+
+/// Watches free space of the data volume.
+pub struct Watchdog {
+    dir: PathBuf,
+    config: DiskConfig,
+    full: AtomicBool,
+    sw: ServiceWarnings,
+}
+
+impl Watchdog {
+    pub fn new(dir: PathBuf, config: DiskConfig, sw: ServiceWarnings) -> Arc<Self> {
+        Arc::new(Self {
+            dir,
+            config,
+            full: AtomicBool::new(false),
+            sw,
+        })
+    }
+
+    /// Free space is below the hard limit, inserts must be rejected.
+    pub fn is_full(&self) -> bool {
+        self.full.load(Ordering::Relaxed)
+    }
+
+    /// Whether `bytes` more can be written without going below the hard
+    /// limit. Free space is read now, not at the last check.
+    pub fn fits(&self, bytes: u64) -> bool {
+        match self.free() {
+            Ok(free) => free >= self.config.hard_limit.saturating_add(bytes),
+            Err(_) => !self.is_full(),
+        }
+    }
+
+    /// Checks free space every interval until the returned guard is dropped.
+    pub fn spawn(self: &Arc<Self>, streams: Streams, tt: &TaskTracker) -> DropGuard {
+        let ct = CancellationToken::new();
+        tt.spawn(self.clone().run(streams, ct.clone()));
+        ct.drop_guard()
+    }
+
+    async fn run(self: Arc<Self>, streams: Streams, ct: CancellationToken) {
+        let mut ticker = interval(self.config.interval);
+
+        loop {
+            select! {
+                _ = ct.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+
+            let this = self.clone();
+            let streams = streams.clone();
+            let result = spawn_blocking(move || this.check(&streams)).await.unwrap();
+
+            let warning = match result {
+                Ok(free) if free < self.config.hard_limit => Some(format!(
+                    "{free} bytes free, below the hard limit of {} bytes, inserts are rejected",
+                    self.config.hard_limit
+                )),
+                Ok(free) if free < self.config.soft_limit => Some(format!(
+                    "{free} bytes free, below the soft limit of {} bytes",
+                    self.config.soft_limit
+                )),
+                Ok(_) => None,
+                Err(e) => Some(e.to_string()),
+            };
+
+            self.sw.set_disk_warning(warning);
+        }
+    }
+
+    /// Returns free bytes after emergency retention, if it is enabled.
+    fn check(&self, streams: &Streams) -> Result<u64, Error> {
+        let mut free = self.free()?;
+
+        if free < self.config.hard_limit && self.config.emergency_retention {
+            free = self.reclaim(streams, self.config.hard_limit - free)?;
+        }
+
+        self.full
+            .store(free < self.config.hard_limit, Ordering::Relaxed);
+        Ok(free)
+    }
+
+    /// Removes the oldest blocks of all streams until their sizes cover the
+    /// deficit. Space the filesystem frees late is seen on the next check.
+    fn reclaim(&self, streams: &Streams, deficit: u64) -> Result<u64, Error> {
+        let streams = streams
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let blocks = streams
+            .iter()
+            .flat_map(|s| s.removable_blocks().into_iter().map(move |b| (b, s)));
+
+        for (block, stream) in oldest_covering(blocks, deficit) {
+            stream.remove_block(&block)?;
+        }
+
+        self.free()
+    }
+
+    fn free(&self) -> Result<u64, Error> {
+        let stat = statvfs(&self.dir)?;
+        Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+    }
+}
+
+/// The oldest blocks whose sizes add up to at least `deficit`.
+fn oldest_covering<T>(
+    blocks: impl Iterator<Item = (BlockEntry, T)>,
+    deficit: u64,
+) -> Vec<(BlockEntry, T)> {
+    let mut blocks = blocks.collect::<Vec<_>>();
+    blocks.sort_by(|(a, _), (b, _)| (&a.partition, a.id).cmp(&(&b.partition, b.id)));
+
+    let mut covered = 0;
+    blocks
+        .into_iter()
+        .take_while(|(block, _)| {
+            let take = covered < deficit;
+            covered += block.bytes;
+            take
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use uuid::Uuid;
+
+    fn block(partition: &str, bytes: u64) -> BlockEntry {
+        BlockEntry {
+            id: Uuid::now_v7(),
+            stream: "s".into(),
+            version: 1,
+            partition: Path::new(partition).into(),
+            rows: 1,
+            bytes,
+            min_time: None,
+            max_time: None,
+            columns: Default::default(),
+            remote: None,
+            origin: None,
+            replicas: Vec::new(),
+            checksum: None,
+            damaged: None,
+        }
+    }
+
+    #[test]
+    fn oldest_blocks_cover_the_deficit() {
+        let blocks = [
+            (block("2024/01/02/00", 30), "a"),
+            (block("2024/01/01/00", 50), "b"),
+            (block("2024/01/01/01", 40), "a"),
+            (block("2024/01/03/00", 10), "b"),
+        ];
+
+        let picked = |deficit| {
+            oldest_covering(blocks.clone().into_iter(), deficit)
+                .into_iter()
+                .map(|(v, _)| v.bytes)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(picked(0), Vec::<u64>::new());
+        assert_eq!(picked(50), [50]);
+        assert_eq!(picked(51), [50, 40]);
+        assert_eq!(picked(1000), [50, 40, 30, 10]);
+    }
+}
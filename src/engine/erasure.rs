@@ -0,0 +1,840 @@
+use crate::engine::accumulator::Accumulator;
+use crate::engine::block;
+use crate::engine::block::Provenance;
+use crate::engine::compaction;
+use crate::engine::compaction::Rewriter;
+use crate::engine::dead_letter::DeadLetter;
+use crate::engine::filter::Filter;
+use crate::engine::partition;
+use crate::engine::partition::TimeRange;
+use crate::engine::pattern::Pattern;
+use crate::engine::query::Query;
+use crate::engine::replication::Copies;
+use crate::engine::schema::FieldDefinition;
+use crate::engine::schema::FieldType;
+use crate::engine::snapshot;
+use crate::engine::snapshot::Snapshots;
+use crate::engine::tiering;
+use crate::engine::tiering::Remote;
+use crate::picodata::service::ServiceWarnings;
+use arrow::array::ArrayRef;
+use arrow::array::AsArray;
+use arrow::array::BooleanArray;
+use arrow::array::RecordBatch;
+use arrow::array::StringArray;
+use arrow::compute::concat_batches;
+use arrow::compute::filter_record_batch;
+use arrow::compute::not;
+use arrow::compute::nullif;
+use arrow::datatypes::DataType;
+use arrow::datatypes::Field;
+use arrow::error::ArrowError;
+use regex::NoExpand;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::runtime::Handle;
+use tokio::select;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tokio_util::sync::DropGuard;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("block error: {0}")]
+    Block(#[from] block::Error),
+    #[error("rewrite: {0}")]
+    Rewrite(#[from] compaction::Error),
+    #[error("arrow error: {0}")]
+    Arrow(#[from] ArrowError),
+    #[error("tiering: {0}")]
+    Tiering(#[from] tiering::Error),
+    #[error("snapshot: {0}")]
+    Snapshot(#[from] snapshot::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("interrupted by a shutdown")]
+    Interrupted,
+}
+
+/// Rows to erase: those matching the filter within the time range. With
+/// redactions the rows are kept and only the redacted fields are rewritten.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErasureRequest {
+    pub filter: Filter,
+    #[serde(flatten)]
+    pub range: TimeRange,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redact: Vec<Redaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Redaction {
+    pub field: String,
+    #[serde(flatten)]
+    pub with: Redact,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "with", rename_all = "snake_case")]
+pub enum Redact {
+    Null,
+    /// SHA-256 of the value, so equal values still compare equal.
+    Hash,
+    /// Replaces the parts of the value matching the pattern.
+    Mask {
+        pattern: Pattern,
+        #[serde(default = "Redact::default_mask")]
+        mask: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed,
+}
+
+/// An erasure job, as shown by the API and recorded in the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Erasure {
+    pub id: Uuid,
+    pub state: JobState,
+    #[serde(flatten)]
+    pub request: ErasureRequest,
+    /// Client the request came from.
+    pub requested_by: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub requested_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub finished_at: Option<OffsetDateTime>,
+    /// Blocks of the stream and its dead letters that may hold matching
+    /// rows, listed when the job starts.
+    pub blocks_total: usize,
+    pub blocks_done: usize,
+    /// Blocks rewritten, or removed once every row was erased.
+    pub blocks_rewritten: usize,
+    pub rows_erased: u64,
+    #[serde(default)]
+    pub rows_redacted: u64,
+    /// Snapshots whose blocks were rewritten. Their exported archives are
+    /// removed.
+    #[serde(default)]
+    pub snapshots_rewritten: usize,
+    /// Copies of rows this instance leaves as they are.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kept: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What a job needs of the stream it erases rows from.
+pub struct Eraser {
+    pub stream: String,
+    pub rewriter: Rewriter,
+    pub dead_letter: Rewriter,
+    pub snapshots: Arc<Snapshots>,
+    pub copies: Arc<Copies>,
+    pub time_field: String,
+    pub remote: Option<Arc<Remote>>,
+    pub sw: ServiceWarnings,
+}
+
+/// Applies a request to a batch: the rows it leaves and how many matched.
+type Apply<'a> = dyn Fn(&RecordBatch) -> Result<(RecordBatch, u64), Error> + 'a;
+
+/// A catalog a job rewrites the blocks of.
+struct Target<'a> {
+    stream: &'a str,
+    rewriter: &'a Rewriter,
+    /// Object store of the offloaded blocks, none for dead letters.
+    remote: Option<&'a Arc<Remote>>,
+    apply: &'a Apply<'a>,
+}
+
+/// Erasure jobs of a stream. A job is recorded in the audit log when it
+/// starts and again when it ends; the log is never compacted or rotated.
+pub struct Erasures {
+    log: Mutex<File>,
+    jobs: Mutex<BTreeMap<Uuid, Erasure>>,
+    ct: CancellationToken,
+}
+
+impl ErasureRequest {
+    /// A field is redacted at most once per request.
+    pub fn validate(&self, fields: &[FieldDefinition], time_field: &str) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for redaction in &self.redact {
+            if !names.insert(redaction.field.as_str()) {
+                Err(format!("{}: redacted more than once", redaction.field))?;
+            }
+            redaction.validate(fields, time_field)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Redaction {
+    /// Only fields of the definition can be redacted, the time field and
+    /// system fields excepted.
+    pub fn validate(&self, fields: &[FieldDefinition], time_field: &str) -> Result<(), String> {
+        let field = fields
+            .iter()
+            .find(|v| v.name == self.field && v.name != time_field)
+            .ok_or_else(|| format!("{}: not a field that can be redacted", self.field))?;
+
+        match self.with {
+            Redact::Null if !field.nullable => Err(format!("{}: not nullable", self.field)),
+            Redact::Hash | Redact::Mask { .. } if field.kind != FieldType::String => {
+                Err(format!("{}: not a string field", self.field))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Redacts the field of a rejected row. Its value may be of any type,
+    /// values other than strings are redacted as their JSON text.
+    fn apply_json(&self, row: &mut JsonValue) {
+        let Some(value) = row.get_mut(&self.field) else {
+            return;
+        };
+
+        let text = match &mut *value {
+            JsonValue::String(v) => std::mem::take(v),
+            v => v.to_string(),
+        };
+        *value = self
+            .with
+            .replace(&text)
+            .map_or(JsonValue::Null, JsonValue::String);
+    }
+}
+
+impl Redact {
+    fn default_mask() -> String {
+        "***".to_string()
+    }
+
+    /// Whether the column of a block can be redacted.
+    fn applies_to(&self, field: &Field) -> bool {
+        match self {
+            Self::Null => field.is_nullable(),
+            _ => *field.data_type() == DataType::Utf8,
+        }
+    }
+
+    /// Redacts the values of the rows that match.
+    fn apply(&self, column: &ArrayRef, matches: &BooleanArray) -> Result<ArrayRef, ArrowError> {
+        if let Self::Null = self {
+            return nullif(column, matches);
+        }
+
+        let values = column
+            .as_string_opt::<i32>()
+            .ok_or_else(|| ArrowError::InvalidArgumentError("not a string field".into()))?;
+        let values = values
+            .iter()
+            .zip(matches.iter())
+            .map(|(v, matches)| match matches {
+                Some(true) => v.and_then(|v| self.replace(v)),
+                _ => v.map(str::to_string),
+            })
+            .collect::<StringArray>();
+
+        Ok(Arc::new(values))
+    }
+
+    /// The redacted value, none for null.
+    fn replace(&self, value: &str) -> Option<String> {
+        match self {
+            Self::Null => None,
+            Self::Hash => Some(block::checksum(value.as_bytes())),
+            Self::Mask { pattern, mask } => {
+                Some(pattern.replace_all(value, NoExpand(mask)).into_owned())
+            }
+        }
+    }
+}
+
+impl Eraser {
+    fn apply(
+        &self,
+        batch: &RecordBatch,
+        request: &ErasureRequest,
+        query: &Query,
+    ) -> Result<(RecordBatch, u64), Error> {
+        let matches = block::to_json_rows(batch)
+            .iter()
+            .map(|v| query.matches(v, &self.time_field))
+            .collect::<Vec<_>>();
+        let matches = BooleanArray::from(matches);
+
+        let batch = match request.redact.is_empty() {
+            true => filter_record_batch(batch, &not(&matches)?)?,
+            false => redact(batch, &matches, &request.redact)?,
+        };
+        Ok((batch, matches.true_count() as u64))
+    }
+
+    /// Dead letters hold the rejected rows as JSON text, which is matched
+    /// and redacted like the rows of the stream. Rows with a time field that
+    /// does not parse match only requests without a time range.
+    fn apply_dead_letter(
+        &self,
+        batch: &RecordBatch,
+        request: &ErasureRequest,
+        query: &Query,
+    ) -> Result<(RecordBatch, u64), Error> {
+        let Some((i, rows)) = batch
+            .schema()
+            .index_of(DeadLetter::ROW)
+            .ok()
+            .and_then(|i| Some((i, batch.column(i).as_string_opt::<i32>()?)))
+        else {
+            return Ok((batch.clone(), 0));
+        };
+
+        let mut matches = Vec::new();
+        let mut redacted = Vec::new();
+        for text in rows {
+            let mut row = text.and_then(|v| serde_json::from_str::<JsonValue>(v).ok());
+            let matched = row
+                .as_ref()
+                .is_some_and(|v| query.matches(v, &self.time_field));
+
+            matches.push(matched);
+            redacted.push(match (matched, &mut row) {
+                (true, Some(row)) => {
+                    for redaction in &request.redact {
+                        redaction.apply_json(row);
+                    }
+                    Some(row.to_string())
+                }
+                _ => text.map(str::to_string),
+            });
+        }
+
+        let matches = BooleanArray::from(matches);
+        let batch = match request.redact.is_empty() {
+            true => filter_record_batch(batch, &not(&matches)?)?,
+            false => {
+                let mut columns = batch.columns().to_vec();
+                columns[i] = Arc::new(StringArray::from(redacted));
+                RecordBatch::try_new(batch.schema(), columns)?
+            }
+        };
+        Ok((batch, matches.true_count() as u64))
+    }
+
+    /// Copies of rows a job leaves as they are, for the audit log.
+    fn kept(&self) -> Vec<String> {
+        self.copies
+            .origins()
+            .into_iter()
+            .map(|v| format!("copies of the blocks of {v}, erased by an erasure there"))
+            .collect()
+    }
+}
+
+impl Erasures {
+    const FILE_NAME: &'static str = "erasures.jsonl";
+
+    /// Opens the audit log of a stream directory. Jobs cut short by a
+    /// restart are recorded as failed, they are not resumed.
+    pub fn open(dir: &Path) -> Result<Arc<Self>, Error> {
+        let path = dir.join(Self::FILE_NAME);
+        let mut data = match std::fs::read(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => Err(e)?,
+        };
+
+        // A torn last line is left from a crash.
+        let end = data.iter().rposition(|v| *v == b'\n').map_or(0, |v| v + 1);
+        if end < data.len() {
+            data.truncate(end);
+            std::fs::write(&path, &data)?;
+        }
+
+        let mut jobs = BTreeMap::new();
+        for job in data
+            .split(|v| *v == b'\n')
+            .filter_map(|v| serde_json::from_slice::<Erasure>(v).ok())
+        {
+            jobs.insert(job.id, job);
+        }
+
+        let erasures = Arc::new(Self {
+            log: Mutex::new(File::options().create(true).append(true).open(&path)?),
+            jobs: Mutex::default(),
+            ct: CancellationToken::new(),
+        });
+
+        for mut job in jobs.into_values() {
+            if job.state == JobState::Running {
+                job.state = JobState::Failed;
+                job.finished_at = Some(OffsetDateTime::now_utc());
+                job.error = Some("interrupted by a restart".to_string());
+                erasures.record(&job)?;
+            }
+            erasures.jobs.lock().unwrap().insert(job.id, job);
+        }
+
+        Ok(erasures)
+    }
+
+    /// Jobs are interrupted when the returned guard is dropped.
+    pub fn drop_guard(&self) -> DropGuard {
+        self.ct.clone().drop_guard()
+    }
+
+    /// Jobs of the stream, oldest first.
+    pub fn list(&self) -> Vec<Erasure> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Erasure> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Records a job and runs it in the background.
+    pub fn start(
+        self: &Arc<Self>,
+        eraser: Eraser,
+        request: ErasureRequest,
+        requested_by: Option<String>,
+        tt: &TaskTracker,
+    ) -> Result<Erasure, Error> {
+        let job = Erasure {
+            id: Uuid::now_v7(),
+            state: JobState::Running,
+            request,
+            requested_by,
+            requested_at: OffsetDateTime::now_utc(),
+            finished_at: None,
+            blocks_total: 0,
+            blocks_done: 0,
+            blocks_rewritten: 0,
+            rows_erased: 0,
+            rows_redacted: 0,
+            snapshots_rewritten: 0,
+            kept: Vec::new(),
+            error: None,
+        };
+
+        self.record(&job)?;
+        self.jobs.lock().unwrap().insert(job.id, job.clone());
+        tt.spawn(self.clone().run(job.clone(), Arc::new(eraser)));
+        Ok(job)
+    }
+
+    async fn run(self: Arc<Self>, job: Erasure, eraser: Arc<Eraser>) {
+        let id = job.id;
+        // Rows accepted before the request reach the blocks with the next
+        // flush, the second interval leaves time for a flush still writing.
+        let result = select! {
+            _ = self.ct.cancelled() => Err(Error::Interrupted),
+            _ = sleep(Accumulator::FLUSH_INTERVAL * 2) => {
+                let this = self.clone();
+                let eraser = eraser.clone();
+                let handle = Handle::current();
+                spawn_blocking(move || this.erase(&job, &eraser, &handle))
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let job = self.update(id, |v| {
+            v.state = match result {
+                Ok(()) => JobState::Done,
+                Err(_) => JobState::Failed,
+            };
+            v.finished_at = Some(OffsetDateTime::now_utc());
+            v.error = result.as_ref().err().map(|e| e.to_string());
+        });
+
+        let e = match (result, self.record(&job)) {
+            (Err(e), _) | (Ok(()), Err(e)) => Some(e.to_string()),
+            (Ok(()), Ok(())) => None,
+        };
+        eraser.sw.set_job_error("erasure", &eraser.stream, e);
+    }
+
+    /// Rewrites the blocks of the stream and its dead letters, then those
+    /// of the snapshots, and compacts the catalog logs, which keep the
+    /// statistics of replaced blocks. Holds off compaction for the whole job,
+    /// so no block is merged away between being listed and being rewritten.
+    fn erase(&self, job: &Erasure, eraser: &Eraser, handle: &Handle) -> Result<(), Error> {
+        let catalog = eraser.rewriter.catalog();
+        let dead_letter = eraser.dead_letter.catalog();
+        let _guard = catalog.lock_rewrite();
+        let _dead_letter_guard = dead_letter.lock_rewrite();
+        let query = Query {
+            filter: Some(job.request.filter.clone()),
+            range: job.request.range,
+            limit: usize::MAX,
+        };
+
+        // Statistics of dead letters are not of the stream's fields.
+        let blocks = query.blocks(catalog);
+        let dead_letters = dead_letter.blocks(&TimeRange::default(), None);
+        self.update(job.id, |v| {
+            v.blocks_total = blocks.len() + dead_letters.len()
+        });
+
+        let apply = |batch: &RecordBatch| eraser.apply(batch, &job.request, &query);
+        let apply_dead_letter =
+            |batch: &RecordBatch| eraser.apply_dead_letter(batch, &job.request, &query);
+        // Dead letters are never offloaded.
+        let targets = [
+            (
+                &eraser.rewriter,
+                eraser.remote.as_ref(),
+                blocks,
+                &apply as &Apply,
+            ),
+            (
+                &eraser.dead_letter,
+                None,
+                dead_letters,
+                &apply_dead_letter as &Apply,
+            ),
+        ];
+
+        for (rewriter, remote, blocks, apply) in targets {
+            for entry in blocks {
+                if self.ct.is_cancelled() {
+                    Err(Error::Interrupted)?;
+                }
+
+                let target = Target {
+                    stream: &eraser.stream,
+                    rewriter,
+                    remote,
+                    apply,
+                };
+                let rows = self.rewrite_block(&target, entry.id, handle)?;
+                self.update(job.id, |v| {
+                    v.blocks_done += 1;
+                    v.blocks_rewritten += usize::from(rows > 0);
+                    match job.request.redact.is_empty() {
+                        true => v.rows_erased += rows,
+                        false => v.rows_redacted += rows,
+                    }
+                });
+            }
+        }
+
+        let snapshots = eraser.snapshots.rewrite(catalog, |entry, path| {
+            if !entry.may_match(&query.range, query.filter.as_ref()) {
+                return Ok::<_, Error>(false);
+            }
+            rewrite_file(&eraser.rewriter, path, &apply)
+        })?;
+
+        catalog.compact_log()?;
+        dead_letter.compact_log()?;
+
+        self.update(job.id, |v| {
+            v.snapshots_rewritten = snapshots;
+            v.kept = eraser.kept();
+        });
+        Ok(())
+    }
+
+    /// Rewrites a block without the matching rows, or removes it if no row
+    /// is left, or with the matching rows redacted. Returns the rows erased
+    /// or redacted, zero if the block was removed meanwhile.
+    fn rewrite_block(&self, target: &Target, id: Uuid, handle: &Handle) -> Result<u64, Error> {
+        let catalog = target.rewriter.catalog();
+        let keys = catalog.keys();
+
+        let guard = catalog.lock_read();
+        let Some(entry) = catalog.get(id) else {
+            return Ok(0);
+        };
+        let path = match (&entry.remote, target.remote) {
+            (Some(key), Some(remote)) => handle.block_on(remote.fetch(key))?,
+            (Some(_), None) => Err(tiering::Error::NoObjectStore)?,
+            (None, _) => entry.path(catalog.dir()).into(),
+        };
+        let batches = block::read(&path, keys)?;
+        let schema = block::schema(&path, keys)?;
+        let provenance = Provenance::read(&path, keys)?;
+        drop(guard);
+
+        let mut rewritten = Vec::new();
+        let mut matched = 0;
+        for batch in batches {
+            let (batch, rows) = (target.apply)(&batch)?;
+            rewritten.push(batch);
+            matched += rows;
+        }
+
+        if matched == 0 {
+            return Ok(0);
+        }
+
+        // Offloaded blocks come back as local blocks, offloaded again later.
+        let partition = catalog.dir().join(&entry.partition);
+        std::fs::create_dir_all(&partition)?;
+        target.rewriter.recover(&partition)?;
+
+        let batch = concat_batches(&schema, &rewritten)?;
+        let batch = (batch.num_rows() > 0).then_some(batch);
+        let replaced =
+            target
+                .rewriter
+                .replace(&partition, &[entry.id], batch.as_ref(), &provenance)?;
+        if replaced.is_none() {
+            return Ok(0);
+        }
+
+        // The tierer may have offloaded the block since it was read. Deleting
+        // the object removes the cached copy as well.
+        if let Some(remote) = target.remote {
+            let key = entry
+                .remote
+                .clone()
+                .unwrap_or_else(|| Remote::key(target.stream, &entry));
+            handle.block_on(remote.delete(&key))?;
+        }
+        if std::fs::read_dir(&partition)?.next().is_none() {
+            partition::remove(&partition)?;
+        }
+
+        Ok(matched)
+    }
+
+    fn update(&self, id: Uuid, f: impl FnOnce(&mut Erasure)) -> Erasure {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(&id).unwrap();
+        f(job);
+        job.clone()
+    }
+
+    fn record(&self, job: &Erasure) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(job)?;
+        line.push(b'\n');
+
+        let mut log = self.log.lock().unwrap();
+        log.write_all(&line)?;
+        log.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Rewrites a block file in place, or removes it once no row is left.
+/// Returns whether any row matched.
+fn rewrite_file(rewriter: &Rewriter, path: &Path, apply: &Apply) -> Result<bool, Error> {
+    let keys = rewriter.catalog().keys();
+    let schema = block::schema(path, keys)?;
+    let provenance = Provenance::read(path, keys)?;
+
+    let mut rewritten = Vec::new();
+    let mut matched = 0;
+    for batch in block::read(path, keys)? {
+        let (batch, rows) = apply(&batch)?;
+        rewritten.push(batch);
+        matched += rows;
+    }
+
+    if matched == 0 {
+        return Ok(false);
+    }
+
+    let batch = concat_batches(&schema, &rewritten)?;
+    if batch.num_rows() == 0 {
+        std::fs::remove_file(path)?;
+        return Ok(true);
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    block::write(&tmp_path, &batch, rewriter.props(), &provenance, keys)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(true)
+}
+
+/// Blocks written before a field was added do not have it, and those
+/// written while it was of another type, or not nullable for nulls, keep it
+/// as it is.
+fn redact(
+    batch: &RecordBatch,
+    matches: &BooleanArray,
+    redactions: &[Redaction],
+) -> Result<RecordBatch, ArrowError> {
+    let schema = batch.schema();
+    let mut columns = batch.columns().to_vec();
+
+    for redaction in redactions {
+        let Ok(i) = schema.index_of(&redaction.field) else {
+            continue;
+        };
+        if redaction.with.applies_to(schema.field(i)) {
+            columns[i] = redaction.with.apply(&columns[i], matches)?;
+        }
+    }
+
+    RecordBatch::try_new(schema, columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Array;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::Schema;
+    use serde_json::json;
+
+    fn redaction(value: JsonValue) -> Redaction {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn batch() -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("secret", DataType::Utf8, true),
+            Field::new("n", DataType::Int64, true),
+            Field::new("kept", DataType::Utf8, false),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(StringArray::from(vec![
+                    Some("pin 1234"),
+                    Some("pin 5678"),
+                    None,
+                ])),
+                Arc::new(Int64Array::from(vec![1, 2, 3])),
+                Arc::new(StringArray::from(vec!["k"; 3])),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn strings(batch: &RecordBatch, name: &str) -> Vec<Option<String>> {
+        batch
+            .column_by_name(name)
+            .unwrap()
+            .as_string::<i32>()
+            .iter()
+            .map(|v| v.map(str::to_string))
+            .collect()
+    }
+
+    #[test]
+    fn redacts_only_matching_rows() {
+        let matches = BooleanArray::from(vec![true, false, true]);
+        let mask = redaction(json!({"field": "secret", "with": "mask", "pattern": "\\d+"}));
+        let redacted = redact(&batch(), &matches, &[mask]).unwrap();
+        assert_eq!(
+            strings(&redacted, "secret"),
+            [
+                Some("pin ***".to_string()),
+                Some("pin 5678".to_string()),
+                None
+            ]
+        );
+
+        let null = redaction(json!({"field": "secret", "with": "null"}));
+        let redacted = redact(&batch(), &matches, &[null]).unwrap();
+        assert_eq!(
+            strings(&redacted, "secret"),
+            [None, Some("pin 5678".to_string()), None]
+        );
+    }
+
+    #[test]
+    fn hash_keeps_equal_values_equal() {
+        let matches = BooleanArray::from(vec![true, true, false]);
+        let hash = redaction(json!({"field": "secret", "with": "hash"}));
+        let redacted = redact(&batch(), &matches, &[hash]).unwrap();
+
+        let values = strings(&redacted, "secret");
+        assert_eq!(values[0], Some(block::checksum(b"pin 1234")));
+        assert_eq!(values[1], Some(block::checksum(b"pin 5678")));
+    }
+
+    #[test]
+    fn leaves_fields_missing_or_of_another_type() {
+        let matches = BooleanArray::from(vec![true, true, true]);
+        let redactions = [
+            redaction(json!({"field": "n", "with": "hash"})),
+            redaction(json!({"field": "missing", "with": "null"})),
+            // Not nullable in blocks written before the field was made so.
+            redaction(json!({"field": "kept", "with": "null"})),
+        ];
+        let redacted = redact(&batch(), &matches, &redactions).unwrap();
+        assert_eq!(redacted, batch());
+
+        let null = redaction(json!({"field": "n", "with": "null"}));
+        let redacted = redact(&batch(), &matches, &[null]).unwrap();
+        assert_eq!(redacted.column_by_name("n").unwrap().null_count(), 3);
+    }
+
+    #[test]
+    fn redacts_rejected_rows_as_json() {
+        let mut row = json!({"secret": "pin 1234", "n": 1234});
+        redaction(json!({"field": "secret", "with": "mask", "pattern": "\\d+"}))
+            .apply_json(&mut row);
+        redaction(json!({"field": "n", "with": "mask", "pattern": "\\d+"})).apply_json(&mut row);
+        redaction(json!({"field": "missing", "with": "null"})).apply_json(&mut row);
+        assert_eq!(row, json!({"secret": "pin ***", "n": "***"}));
+
+        redaction(json!({"field": "n", "with": "null"})).apply_json(&mut row);
+        assert_eq!(row, json!({"secret": "pin ***", "n": null}));
+    }
+
+    #[test]
+    fn validates_redactions() {
+        let field = |name: &str, kind, nullable| FieldDefinition {
+            name: name.into(),
+            kind,
+            nullable,
+        };
+        let fields = [
+            field("ts", FieldType::Timestamp, false),
+            field("secret", FieldType::String, false),
+            field("n", FieldType::I64, true),
+        ];
+        let request = |redact: JsonValue| -> ErasureRequest {
+            serde_json::from_value(json!({"filter": {"exists": {"field": "n"}}, "redact": redact}))
+                .unwrap()
+        };
+
+        let valid = request(json!([
+            {"field": "secret", "with": "hash"},
+            {"field": "n", "with": "null"},
+        ]));
+        assert!(valid.validate(&fields, "ts").is_ok());
+
+        for redact in [
+            json!([{"field": "ts", "with": "null"}]),
+            json!([{"field": "missing", "with": "null"}]),
+            json!([{"field": "secret", "with": "null"}]),
+            json!([{"field": "n", "with": "hash"}]),
+            json!([
+                {"field": "secret", "with": "hash"},
+                {"field": "secret", "with": "mask", "pattern": "a"},
+            ]),
+        ] {
+            assert!(request(redact).validate(&fields, "ts").is_err());
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::engine::catalog::ColumnStats;
+use crate::engine::pattern::Pattern;
+use crate::engine::value::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Exists(FieldRef),
+    Matches(PatternOperand),
+    Eq(Operand),
+    Ne(Operand),
+    Gt(Operand),
+    Ge(Operand),
+    Lt(Operand),
+    Le(Operand),
+    EqFold(Operand),
+    Contains(Operand),
+    ContainsFold(Operand),
+    StartsWith(Operand),
+    StartsWithFold(Operand),
+    EndsWith(Operand),
+    EndsWithFold(Operand),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldRef {
+    pub field: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Operand {
+    pub field: String,
+    pub value: JsonValue,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatternOperand {
+    pub field: String,
+    pub pattern: Pattern,
+}
+
+impl Filter {
+    pub fn matches(&self, row: &JsonValue) -> bool {
+        match self {
+            Self::And(v) => v.iter().all(|f| f.matches(row)),
+            Self::Or(v) => v.iter().any(|f| f.matches(row)),
+            Self::Not(v) => !v.matches(row),
+            Self::Exists(v) => row.get(&v.field).is_some_and(|v| !v.is_null()),
+            Self::Matches(v) => Self::field(row, &v.field).matches(&v.pattern),
+            Self::Eq(v) => v.compare(row) == Some(Ordering::Equal),
+            Self::Ne(v) => v.compare(row) != Some(Ordering::Equal),
+            Self::Gt(v) => v.compare(row) == Some(Ordering::Greater),
+            Self::Ge(v) => matches!(v.compare(row), Some(Ordering::Greater | Ordering::Equal)),
+            Self::Lt(v) => v.compare(row) == Some(Ordering::Less),
+            Self::Le(v) => matches!(v.compare(row), Some(Ordering::Less | Ordering::Equal)),
+            Self::EqFold(v) => v.apply(row, Value::eq_fold),
+            Self::Contains(v) => v.apply(row, Value::contains),
+            Self::ContainsFold(v) => v.apply(row, Value::contains_fold),
+            Self::StartsWith(v) => v.apply(row, Value::starts_with),
+            Self::StartsWithFold(v) => v.apply(row, Value::starts_with_fold),
+            Self::EndsWith(v) => v.apply(row, Value::ends_with),
+            Self::EndsWithFold(v) => v.apply(row, Value::ends_with_fold),
+        }
+    }
+
+    /// Whether a block with these column statistics may hold matching rows.
+    pub fn may_match(&self, columns: &BTreeMap<String, ColumnStats>) -> bool {
+        match self {
+            Self::And(v) => v.iter().all(|f| f.may_match(columns)),
+            Self::Or(v) => v.iter().any(|f| f.may_match(columns)),
+            Self::Eq(v) => v.bounds(columns, |min, max| min.is_le() && max.is_ge()),
+            Self::Gt(v) => v.bounds(columns, |_, max| max.is_gt()),
+            Self::Ge(v) => v.bounds(columns, |_, max| max.is_ge()),
+            Self::Lt(v) => v.bounds(columns, |min, _| min.is_lt()),
+            Self::Le(v) => v.bounds(columns, |min, _| min.is_le()),
+            _ => true,
+        }
+    }
+
+    fn field<'a>(row: &'a JsonValue, name: &str) -> Value<'a> {
+        row.get(name).map_or(Value::Null, Value::from)
+    }
+}
+
+impl Operand {
+    fn compare(&self, row: &JsonValue) -> Option<Ordering> {
+        let field = row.get(&self.field).map_or(Value::Null, ordered);
+        field.compare(&ordered(&self.value))
+    }
+
+    /// Checks the value against the min and max of the field, passing how
+    /// they compare to it. Without usable statistics the block may match.
+    fn bounds<F>(&self, columns: &BTreeMap<String, ColumnStats>, f: F) -> bool
+    where
+        F: FnOnce(Ordering, Ordering) -> bool,
+    {
+        let Some(stats) = columns.get(&self.field) else {
+            return true;
+        };
+
+        // Statistics of strings are ordered as text, so they cannot rule out
+        // times, which rows are matched by as times. Nor can those recorded
+        // without the column type.
+        let value = ordered(&self.value);
+        let (min, max) = match stats.timestamp {
+            true => (ordered(&stats.min), ordered(&stats.max)),
+            false if matches!(value, Value::Timestamp(_)) => return true,
+            false => (Value::from(&stats.min), Value::from(&stats.max)),
+        };
+        match (min.compare(&value), max.compare(&value)) {
+            (Some(min), Some(max)) => f(min, max),
+            _ => true,
+        }
+    }
+
+    fn apply<'a>(&'a self, row: &'a JsonValue, op: fn(&Value<'a>, &Value<'a>) -> bool) -> bool {
+        op(&Filter::field(row, &self.field), &Value::from(&self.value))
+    }
+}
+
+/// Value to order by: timestamps are kept as RFC3339 text, which does not
+/// sort by time across offsets and fractions of a second, so they are parsed.
+fn ordered(value: &JsonValue) -> Value<'_> {
+    match value
+        .as_str()
+        .and_then(|v| OffsetDateTime::parse(v, &Rfc3339).ok())
+    {
+        Some(v) => Value::Timestamp(v),
+        None => Value::from(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(value: JsonValue) -> Filter {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn timestamps_compare_as_times() {
+        let f = filter(json!({"gt": {"field": "ts", "value": "2024-01-01T00:00:00Z"}}));
+
+        assert!(f.matches(&json!({"ts": "2024-01-01T00:00:00.5Z"})));
+        assert!(f.matches(&json!({"ts": "2024-01-01T01:00:00+00:30"})));
+        assert!(!f.matches(&json!({"ts": "2024-01-01T02:00:00+03:00"})));
+    }
+
+    #[test]
+    fn statistics_prune_by_time() {
+        let stats = |min: &str, max: &str| {
+            BTreeMap::from([(
+                "ts".to_string(),
+                ColumnStats {
+                    min: json!(min),
+                    max: json!(max),
+                    nulls: 0,
+                    timestamp: true,
+                },
+            )])
+        };
+        let f = filter(json!({"ge": {"field": "ts", "value": "2024-01-01T10:00:00.25Z"}}));
+
+        assert!(f.may_match(&stats("2024-01-01T09:00:00Z", "2024-01-01T10:00:00.5Z")));
+        assert!(!f.may_match(&stats("2024-01-01T09:00:00Z", "2024-01-01T10:00:00Z")));
+        assert!(f.may_match(&BTreeMap::new()));
+    }
+
+    #[test]
+    fn string_statistics_do_not_prune_by_time() {
+        // Lexicographic bounds of a string column holding times with offsets.
+        let stats = BTreeMap::from([(
+            "s".to_string(),
+            ColumnStats {
+                min: json!("2024-01-01T01:00:00+03:00"),
+                max: json!("2024-01-01T05:00:00+05:00"),
+                nulls: 0,
+                timestamp: false,
+            },
+        )]);
+        let f = filter(json!({"gt": {"field": "s", "value": "2024-01-01T00:00:00Z"}}));
+
+        assert!(f.matches(&json!({"s": "2024-01-01T04:00:00+03:00"})));
+        assert!(f.may_match(&stats));
+    }
+
+    #[test]
+    fn statistics_prune_numbers_and_strings() {
+        let stats = BTreeMap::from([
+            (
+                "n".to_string(),
+                ColumnStats {
+                    min: json!(1),
+                    max: json!(10),
+                    nulls: 0,
+                    timestamp: false,
+                },
+            ),
+            (
+                "s".to_string(),
+                ColumnStats {
+                    min: json!("b"),
+                    max: json!("d"),
+                    nulls: 0,
+                    timestamp: false,
+                },
+            ),
+        ]);
+
+        assert!(filter(json!({"eq": {"field": "n", "value": 2.5}})).may_match(&stats));
+        assert!(!filter(json!({"gt": {"field": "n", "value": 10}})).may_match(&stats));
+        assert!(!filter(json!({"lt": {"field": "s", "value": "b"}})).may_match(&stats));
+        assert!(filter(json!({"or": [
+            {"eq": {"field": "s", "value": "a"}},
+            {"le": {"field": "n", "value": 1}}
+        ]}))
+        .may_match(&stats));
+    }
+}
@@ -0,0 +1,441 @@
+use regex::Captures;
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use thiserror::Error;
+
+type PatternName = String;
+
+/// Built-in patterns, a subset of the Logstash library rewritten for the
+/// `regex` crate (no look-around and no backreferences).
+const LIBRARY: &[(&str, &str)] = &[
+    ("USERNAME", r"[a-zA-Z0-9._-]+"),
+    ("USER", r"%{USERNAME}"),
+    ("INT", r"[+-]?[0-9]+"),
+    ("BASE10NUM", r"[+-]?(?:[0-9]+(?:\.[0-9]+)?|\.[0-9]+)"),
+    ("NUMBER", r"%{BASE10NUM}"),
+    ("POSINT", r"[1-9][0-9]*"),
+    ("NONNEGINT", r"[0-9]+"),
+    ("WORD", r"\b\w+\b"),
+    ("NOTSPACE", r"\S+"),
+    ("SPACE", r"\s*"),
+    ("DATA", r".*?"),
+    ("GREEDYDATA", r".*"),
+    ("QUOTEDSTRING", r#""(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*'"#),
+    (
+        "UUID",
+        r"[A-Fa-f0-9]{8}-(?:[A-Fa-f0-9]{4}-){3}[A-Fa-f0-9]{12}",
+    ),
+    (
+        "IPV4",
+        r"(?:(?:25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])\.){3}(?:25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])",
+    ),
+    (
+        "IPV6",
+        r"(?:[0-9A-Fa-f]{0,4}:){2,7}(?:[0-9A-Fa-f]{1,4}|%{IPV4})?",
+    ),
+    ("IP", r"(?:%{IPV6}|%{IPV4})"),
+    (
+        "HOSTNAME",
+        r"\b(?:[0-9A-Za-z][0-9A-Za-z-]{0,62})(?:\.(?:[0-9A-Za-z][0-9A-Za-z-]{0,62}))*\.?\b",
+    ),
+    ("IPORHOST", r"(?:%{IP}|%{HOSTNAME})"),
+    ("HOSTPORT", r"%{IPORHOST}:%{POSINT}"),
+    ("PATH", r"(?:/[^/\s?#]*)+"),
+    ("URIPROTO", r"[A-Za-z][A-Za-z0-9+\-.]*"),
+    ("URIPARAM", r"\?[^\s]*"),
+    ("URIPATHPARAM", r"%{PATH}(?:%{URIPARAM})?"),
+    (
+        "URI",
+        r"%{URIPROTO}://(?:%{USER}(?::[^@]*)?@)?%{IPORHOST}(?::%{POSINT})?(?:%{URIPATHPARAM})?",
+    ),
+    (
+        "MONTH",
+        r"\b(?:[Jj]an(?:uary)?|[Ff]eb(?:ruary)?|[Mm]ar(?:ch)?|[Aa]pr(?:il)?|[Mm]ay|[Jj]une?|[Jj]uly?|[Aa]ug(?:ust)?|[Ss]ep(?:tember)?|[Oo]ct(?:ober)?|[Nn]ov(?:ember)?|[Dd]ec(?:ember)?)\b",
+    ),
+    ("MONTHNUM", r"(?:0?[1-9]|1[0-2])"),
+    ("MONTHDAY", r"(?:(?:0[1-9])|(?:[12][0-9])|(?:3[01])|[1-9])"),
+    (
+        "DAY",
+        r"(?:Mon(?:day)?|Tue(?:sday)?|Wed(?:nesday)?|Thu(?:rsday)?|Fri(?:day)?|Sat(?:urday)?|Sun(?:day)?)",
+    ),
+    ("YEAR", r"\d\d(?:\d\d)?"),
+    ("HOUR", r"(?:2[0123]|[01]?[0-9])"),
+    ("MINUTE", r"(?:[0-5][0-9])"),
+    ("SECOND", r"(?:(?:[0-5]?[0-9]|60)(?:[:.,][0-9]+)?)"),
+    ("TIME", r"%{HOUR}:%{MINUTE}(?::%{SECOND})?"),
+    ("ISO8601_TIMEZONE", r"(?:Z|[+-]%{HOUR}(?::?%{MINUTE}))"),
+    (
+        "TIMESTAMP_ISO8601",
+        r"%{YEAR}-%{MONTHNUM}-%{MONTHDAY}[T ]%{HOUR}:?%{MINUTE}(?::?%{SECOND})?%{ISO8601_TIMEZONE}?",
+    ),
+    ("HTTPDATE", r"%{MONTHDAY}/%{MONTH}/%{YEAR}:%{TIME} %{INT}"),
+    ("SYSLOGTIMESTAMP", r"%{MONTH} +%{MONTHDAY} %{TIME}"),
+    (
+        "LOGLEVEL",
+        r"(?i:trace|debug|info|notice|warn(?:ing)?|err(?:or)?|crit(?:ical)?|fatal|severe|alert|emerg(?:ency)?)",
+    ),
+    ("HTTPDUSER", r"(?:%{USER}|-)"),
+    (
+        "COMMONAPACHELOG",
+        r#"%{IPORHOST:client_ip} %{HTTPDUSER:ident} %{HTTPDUSER:auth} \[%{HTTPDATE:timestamp}\] "(?:%{WORD:http_method} %{NOTSPACE:http_path}(?: HTTP/%{NUMBER:http_version})?|%{DATA:raw_request})" %{NUMBER:http_status:int} (?:%{NUMBER:bytes:int}|-)"#,
+    ),
+    (
+        "COMBINEDAPACHELOG",
+        r#"%{COMMONAPACHELOG} (?:"%{DATA:referrer}"|-) (?:"%{DATA:user_agent}"|-)"#,
+    ),
+    (
+        "SYSLOGLINE",
+        r"%{SYSLOGTIMESTAMP:timestamp} %{IPORHOST:host} %{DATA:program}(?:\[%{POSINT:pid:int}\])?: %{GREEDYDATA:message}",
+    ),
+];
+
+static REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"%\{(?P<pattern>[A-Z0-9_]+)(?::(?P<field>[^:}]+))?(?::(?P<kind>int|float|bool))?\}")
+        .unwrap()
+});
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("unknown pattern: {0}")]
+    UnknownPattern(PatternName),
+    #[error("pattern is recursive: {0}")]
+    RecursivePattern(PatternName),
+    #[error("pattern expands to more than {} bytes", Compiler::MAX_LENGTH)]
+    PatternTooLong,
+    #[error("capture name is reserved: {0}")]
+    ReservedName(String),
+    #[error("regex: {0}")]
+    Regex(#[from] regex::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrokDefinition {
+    pub field: String,
+    pub patterns: Vec<String>,
+    #[serde(default)]
+    pub definitions: BTreeMap<PatternName, String>,
+    #[serde(default = "GrokDefinition::default_failure_field")]
+    pub failure_field: String,
+    #[serde(default = "GrokDefinition::default_failure_tag")]
+    pub failure_tag: String,
+}
+
+/// Field extraction with Grok expressions. Plain named-capture regular
+/// expressions are valid Grok expressions as well.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "GrokDefinition", into = "GrokDefinition")]
+pub struct Grok {
+    definition: GrokDefinition,
+    matchers: Vec<Matcher>,
+}
+
+#[derive(Debug, Clone)]
+struct Matcher {
+    regex: Regex,
+    captures: Vec<Capture>,
+}
+
+#[derive(Debug, Clone)]
+struct Capture {
+    group: String,
+    field: String,
+    kind: CaptureKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CaptureKind {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+struct Compiler<'a> {
+    definitions: &'a BTreeMap<PatternName, String>,
+    library: HashMap<&'static str, &'static str>,
+    captures: Vec<Capture>,
+}
+
+impl GrokDefinition {
+    fn default_failure_field() -> String {
+        "tags".into()
+    }
+
+    fn default_failure_tag() -> String {
+        "_grok_failure".into()
+    }
+}
+
+impl TryFrom<GrokDefinition> for Grok {
+    type Error = Error;
+
+    fn try_from(definition: GrokDefinition) -> Result<Self, Self::Error> {
+        let matchers = definition
+            .patterns
+            .iter()
+            .map(|p| Compiler::new(&definition.definitions).compile(p))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            definition,
+            matchers,
+        })
+    }
+}
+
+impl From<Grok> for GrokDefinition {
+    fn from(value: Grok) -> Self {
+        value.definition
+    }
+}
+
+impl Grok {
+    /// Writes captures of the first matching pattern into the row. When none
+    /// matches, the failure tag is appended to the failure field instead.
+    pub fn apply(&self, object: &mut Map<String, JsonValue>) {
+        let source = object
+            .get(&self.definition.field)
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string());
+
+        let matched = source.and_then(|source| {
+            self.matchers.iter().find_map(|m| {
+                m.regex
+                    .captures(&source)
+                    .map(|captures| m.extract(&captures))
+            })
+        });
+
+        match matched {
+            Some(values) => object.extend(values),
+            None => self.tag_failure(object),
+        }
+    }
+
+    /// Whether a capture or a failure tag may be written into the field.
+    pub fn writes(&self, field: &str) -> bool {
+        self.definition.failure_field == field
+            || self
+                .matchers
+                .iter()
+                .any(|m| m.captures.iter().any(|c| c.field == field))
+    }
+
+    fn tag_failure(&self, object: &mut Map<String, JsonValue>) {
+        let tag = JsonValue::from(self.definition.failure_tag.as_str());
+
+        // A field that already holds something else is left as it is.
+        match object.get_mut(&self.definition.failure_field) {
+            Some(JsonValue::Array(tags)) if !tags.contains(&tag) => tags.push(tag),
+            Some(JsonValue::Array(_)) => {}
+            Some(JsonValue::Null) | None => {
+                object.insert(self.definition.failure_field.clone(), vec![tag].into());
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+impl Matcher {
+    fn extract(&self, captures: &Captures) -> Vec<(String, JsonValue)> {
+        self.captures
+            .iter()
+            .filter_map(|c| {
+                let v = captures.name(&c.group)?.as_str();
+                Some((c.field.clone(), c.kind.convert(v)))
+            })
+            .collect()
+    }
+}
+
+impl CaptureKind {
+    fn convert(&self, v: &str) -> JsonValue {
+        match self {
+            Self::String => v.into(),
+            Self::Int => v.parse::<i64>().map_or(JsonValue::Null, |v| v.into()),
+            Self::Float => v.parse::<f64>().map_or(JsonValue::Null, |v| v.into()),
+            Self::Bool => v.parse::<bool>().map_or(JsonValue::Null, |v| v.into()),
+        }
+    }
+}
+
+impl<'a> Compiler<'a> {
+    /// Caps patterns that reference others many times over.
+    const MAX_LENGTH: usize = 64 * 1024;
+    /// Prefix of the groups generated for `%{PATTERN:field}`, hand-written
+    /// groups may not use it.
+    const GROUP_PREFIX: &'static str = "__grok";
+
+    fn new(definitions: &'a BTreeMap<PatternName, String>) -> Self {
+        Self {
+            definitions,
+            library: LIBRARY.iter().copied().collect(),
+            captures: Vec::new(),
+        }
+    }
+
+    fn compile(mut self, expression: &str) -> Result<Matcher, Error> {
+        let expanded = self.expand(expression, &mut Vec::new())?;
+        let regex = Regex::new(&expanded)?;
+
+        // Plain named groups written by hand are captured as strings.
+        for name in regex.capture_names().flatten() {
+            if !self.captures.iter().any(|c| c.group == name) {
+                if name.starts_with(Self::GROUP_PREFIX) {
+                    Err(Error::ReservedName(name.to_string()))?;
+                }
+
+                self.captures.push(Capture {
+                    group: name.to_string(),
+                    field: name.to_string(),
+                    kind: CaptureKind::String,
+                });
+            }
+        }
+
+        Ok(Matcher {
+            regex,
+            captures: self.captures,
+        })
+    }
+
+    /// `visiting` holds the patterns being expanded, a reference back to one
+    /// of them is a cycle.
+    fn expand(&mut self, expression: &str, visiting: &mut Vec<String>) -> Result<String, Error> {
+        let mut expanded = String::with_capacity(expression.len());
+        let mut last = 0;
+
+        for reference in REFERENCE.captures_iter(expression) {
+            let whole = reference.get(0).unwrap();
+            let name = &reference["pattern"];
+
+            if visiting.iter().any(|v| v == name) {
+                Err(Error::RecursivePattern(name.to_string()))?;
+            }
+
+            let pattern = self
+                .definitions
+                .get(name)
+                .map(|v| v.as_str())
+                .or_else(|| self.library.get(name).copied())
+                .ok_or_else(|| Error::UnknownPattern(name.to_string()))?;
+
+            visiting.push(name.to_string());
+            let pattern = self.expand(pattern, visiting)?;
+            visiting.pop();
+
+            expanded.push_str(&expression[last..whole.start()]);
+            last = whole.end();
+
+            match reference.name("field") {
+                Some(field) => {
+                    let group = format!("{}{}", Self::GROUP_PREFIX, self.captures.len());
+                    expanded.push_str(&format!("(?P<{}>{})", group, pattern));
+                    self.captures.push(Capture {
+                        group,
+                        field: field.as_str().to_string(),
+                        kind: match reference.name("kind").map(|v| v.as_str()) {
+                            Some("int") => CaptureKind::Int,
+                            Some("float") => CaptureKind::Float,
+                            Some("bool") => CaptureKind::Bool,
+                            _ => CaptureKind::String,
+                        },
+                    });
+                }
+                None => expanded.push_str(&format!("(?:{})", pattern)),
+            }
+
+            if expanded.len() > Self::MAX_LENGTH {
+                Err(Error::PatternTooLong)?;
+            }
+        }
+
+        expanded.push_str(&expression[last..]);
+        match expanded.len() > Self::MAX_LENGTH {
+            true => Err(Error::PatternTooLong),
+            false => Ok(expanded),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn grok(definition: JsonValue) -> Result<Grok, Error> {
+        Grok::try_from(serde_json::from_value::<GrokDefinition>(definition).unwrap())
+    }
+
+    #[test]
+    fn captures_typed_fields() {
+        let grok = grok(json!({
+            "field": "message",
+            "patterns": ["%{IP:client} %{WORD:method} %{NUMBER:took:float} %{INT:status:int}"]
+        }))
+        .unwrap();
+
+        let mut row = json!({"message": "10.0.0.1 GET 0.25 200"});
+        grok.apply(row.as_object_mut().unwrap());
+        assert_eq!(row["client"], json!("10.0.0.1"));
+        assert_eq!(row["method"], json!("GET"));
+        assert_eq!(row["took"], json!(0.25));
+        assert_eq!(row["status"], json!(200));
+    }
+
+    #[test]
+    fn tags_rows_that_do_not_match() {
+        let grok = grok(json!({"field": "message", "patterns": ["%{INT:n}"]})).unwrap();
+        let mut row = json!({"message": "none", "tags": ["a"]});
+        grok.apply(row.as_object_mut().unwrap());
+        grok.apply(row.as_object_mut().unwrap());
+        assert_eq!(row["tags"], json!(["a", "_grok_failure"]));
+    }
+
+    #[test]
+    fn keeps_failure_fields_of_another_type() {
+        let grok = grok(json!({"field": "message", "patterns": ["%{INT:n}"]})).unwrap();
+        let mut row = json!({"message": "none", "tags": "kept"});
+        grok.apply(row.as_object_mut().unwrap());
+        assert_eq!(row["tags"], json!("kept"));
+    }
+
+    #[test]
+    fn reserves_generated_group_names() {
+        let e = grok(json!({"field": "m", "patterns": ["(?P<__grok0>a)%{INT:n}"]}));
+        assert!(matches!(e, Err(Error::ReservedName(v)) if v == "__grok0"));
+
+        let grok = grok(json!({"field": "m", "patterns": ["(?P<grok0>a)%{INT:n}"]})).unwrap();
+        let mut row = json!({"m": "a1"});
+        grok.apply(row.as_object_mut().unwrap());
+        assert_eq!(row["grok0"], json!("a"));
+        assert_eq!(row["n"], json!("1"));
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let e = grok(json!({"field": "m", "patterns": ["%{A}"], "definitions": {"A": "%{A}%{A}"}}));
+        assert!(matches!(e, Err(Error::RecursivePattern(v)) if v == "A"));
+
+        let e = grok(
+            json!({"field": "m", "patterns": ["%{A}"], "definitions": {"A": "x%{B}", "B": "%{A}"}}),
+        );
+        assert!(matches!(e, Err(Error::RecursivePattern(_))));
+    }
+
+    #[test]
+    fn caps_the_expanded_size() {
+        let definitions = (0..32)
+            .map(|i| (format!("P{i}"), format!("%{{P{}}}%{{P{}}}", i + 1, i + 1)))
+            .chain([("P32".to_string(), "x".to_string())])
+            .collect::<BTreeMap<_, _>>();
+        let e = grok(json!({"field": "m", "patterns": ["%{P0}"], "definitions": definitions}));
+        assert!(matches!(e, Err(Error::PatternTooLong)));
+    }
+}
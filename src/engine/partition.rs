@@ -0,0 +1,310 @@
+use arrow::array::AsArray;
+use arrow::array::BooleanArray;
+use arrow::array::RecordBatch;
+use arrow::compute::filter_record_batch;
+use arrow::compute::sort_to_indices;
+use arrow::compute::take_record_batch;
+use arrow::datatypes::Schema;
+use arrow::datatypes::TimestampNanosecondType;
+use arrow::error::ArrowError;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+use time::Date;
+use time::Month;
+use time::OffsetDateTime;
+use time::UtcOffset;
+
+/// Schema metadata key naming the column blocks are partitioned by.
+pub const TIME_FIELD: &str = "time_field";
+
+const NANOS_PER_HOUR: i64 = 3_600_000_000_000;
+
+/// Hour of event time, counted from the unix epoch. Blocks live under
+/// `YYYY/MM/DD/HH` directories of their hour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Hour(i64);
+
+/// Event time range of a query, `from` inclusive and `to` exclusive.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TimeRange {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub from: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub to: Option<OffsetDateTime>,
+}
+
+type Key = [u32; 4];
+
+impl Hour {
+    pub fn of(nanos: i64) -> Self {
+        Self(nanos.div_euclid(NANOS_PER_HOUR))
+    }
+
+    pub fn dir(&self, root: &Path) -> PathBuf {
+        let [year, month, day, hour] = key(self.start());
+        root.join(format!("{year:04}"))
+            .join(format!("{month:02}"))
+            .join(format!("{day:02}"))
+            .join(format!("{hour:02}"))
+    }
+
+    /// Hour of a partition directory returned by [`list`].
+    pub fn of_dir(partition: &Path) -> Option<Self> {
+        let mut parts = partition
+            .iter()
+            .rev()
+            .take(3)
+            .map(|v| v.to_str()?.parse::<u8>().ok());
+
+        let hour = parts.next()??;
+        let day = parts.next()??;
+        let month = Month::try_from(parts.next()??).ok()?;
+        let year = partition.iter().rev().nth(3)?.to_str()?.parse().ok()?;
+
+        let start = Date::from_calendar_date(year, month, day)
+            .ok()?
+            .with_hms(hour, 0, 0)
+            .ok()?
+            .assume_utc();
+
+        Some(Self(start.unix_timestamp().div_euclid(3600)))
+    }
+
+    pub fn end(&self) -> OffsetDateTime {
+        self.start() + time::Duration::HOUR
+    }
+
+    fn start(&self) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(self.0 * 3600).unwrap()
+    }
+}
+
+impl TimeRange {
+    pub fn contains(&self, time: OffsetDateTime) -> bool {
+        self.from.is_none_or(|v| time >= v) && self.to.is_none_or(|v| time < v)
+    }
+}
+
+/// Name of the column a stream schema is partitioned by.
+pub fn time_field(schema: &Schema) -> &str {
+    schema.metadata()[TIME_FIELD].as_str()
+}
+
+/// Splits a batch into one batch per hour of its time column.
+pub fn split(batch: &RecordBatch) -> Result<Vec<(Hour, RecordBatch)>, ArrowError> {
+    let schema = batch.schema();
+    let name = time_field(&schema);
+    let times = batch
+        .column_by_name(name)
+        .ok_or_else(|| ArrowError::SchemaError(format!("missing time field: {name}")))?
+        .as_primitive::<TimestampNanosecondType>();
+
+    let hours = times
+        .iter()
+        .map(|v| Hour::of(v.unwrap_or_default()))
+        .collect::<Vec<_>>();
+
+    let distinct = hours.iter().copied().collect::<BTreeSet<_>>();
+    if distinct.len() == 1 {
+        return Ok(vec![(hours[0], batch.clone())]);
+    }
+
+    distinct
+        .into_iter()
+        .map(|hour| {
+            let mask = BooleanArray::from(hours.iter().map(|v| *v == hour).collect::<Vec<_>>());
+            Ok((hour, filter_record_batch(batch, &mask)?))
+        })
+        .collect()
+}
+
+/// Sorts the rows of a batch by its time column.
+pub fn sort(batch: &RecordBatch) -> Result<RecordBatch, ArrowError> {
+    let schema = batch.schema();
+    let time = batch
+        .column_by_name(time_field(&schema))
+        .ok_or_else(|| ArrowError::SchemaError("missing time field".into()))?;
+
+    let indices = sort_to_indices(time, None, None)?;
+    take_record_batch(batch, &indices)
+}
+
+/// Hour directories under `root` that may hold rows of the range, oldest
+/// first. Directories that are not partitions, like `dead_letter`, are skipped.
+pub fn list(root: &Path, range: &TimeRange) -> Result<Vec<PathBuf>, std::io::Error> {
+    let from = range.from.map(key);
+    let to = range.to.map(key);
+    let mut partitions = Vec::new();
+
+    walk(root, &mut Vec::new(), from, to, &mut partitions)?;
+
+    partitions.sort();
+    Ok(partitions)
+}
+
+/// Removes a partition directory with its blocks, then the day, month and
+/// year directories left empty.
+pub fn remove(partition: &Path) -> Result<(), std::io::Error> {
+    std::fs::remove_dir_all(partition)?;
+
+    for dir in partition.ancestors().skip(1).take(3) {
+        match std::fs::remove_dir(dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::DirectoryNotEmpty => break,
+            v => v?,
+        }
+    }
+
+    Ok(())
+}
+
+fn walk(
+    dir: &Path,
+    prefix: &mut Vec<u32>,
+    from: Option<Key>,
+    to: Option<Key>,
+    partitions: &mut Vec<PathBuf>,
+) -> Result<(), std::io::Error> {
+    if prefix.len() == 4 {
+        partitions.push(dir.to_path_buf());
+        return Ok(());
+    }
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e)?,
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let Some(n) = entry.file_name().to_str().and_then(|v| v.parse().ok()) else {
+            continue;
+        };
+
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        // Compare only the levels walked so far: a year is kept when the
+        // range touches any of its hours.
+        prefix.push(n);
+        let level = prefix.len();
+        let within = from.is_none_or(|v| v[..level] <= prefix[..])
+            && to.is_none_or(|v| prefix[..] <= v[..level]);
+
+        if within {
+            walk(&entry.path(), prefix, from, to, partitions)?;
+        }
+
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+fn key(time: OffsetDateTime) -> Key {
+    let time = time.to_offset(UtcOffset::UTC);
+    [
+        time.year() as u32,
+        time.month() as u32,
+        time.day() as u32,
+        time.hour() as u32,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing::TempDir;
+    use arrow::array::Int64Array;
+    use arrow::array::TimestampNanosecondArray;
+    use arrow::datatypes::DataType;
+    use arrow::datatypes::Field;
+    use arrow::datatypes::TimeUnit;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use time::format_description::well_known::Rfc3339;
+
+    fn at(time: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(time, &Rfc3339).unwrap()
+    }
+
+    fn nanos(time: OffsetDateTime) -> i64 {
+        time.unix_timestamp_nanos() as i64
+    }
+
+    #[test]
+    fn hour_dirs_round_trip() {
+        let root = Path::new("/data");
+        let hour = Hour::of(nanos(at("2024-02-29T23:59:59Z")));
+        let dir = hour.dir(root);
+
+        assert_eq!(dir, Path::new("/data/2024/02/29/23"));
+        assert_eq!(Hour::of_dir(&dir), Some(hour));
+        assert_eq!(hour.end(), at("2024-03-01T00:00:00Z"));
+        assert_eq!(Hour::of_dir(Path::new("/data/dead_letter")), None);
+    }
+
+    #[test]
+    fn split_by_hour() {
+        let schema = Arc::new(Schema::new_with_metadata(
+            vec![
+                Field::new("ts", DataType::Timestamp(TimeUnit::Nanosecond, None), false),
+                Field::new("n", DataType::Int64, false),
+            ],
+            HashMap::from([(TIME_FIELD.to_string(), "ts".to_string())]),
+        ));
+        let times = [
+            at("2024-01-01T10:05:00Z"),
+            at("2024-01-01T11:00:00Z"),
+            at("2024-01-01T10:59:00Z"),
+        ];
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(TimestampNanosecondArray::from_iter_values(times.map(nanos))),
+                Arc::new(Int64Array::from(vec![1, 2, 3])),
+            ],
+        )
+        .unwrap();
+
+        let parts = split(&batch).unwrap();
+
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].0, Hour::of(nanos(times[0])));
+        assert_eq!(parts[0].1.num_rows(), 2);
+        assert_eq!(parts[1].0, Hour::of(nanos(times[1])));
+        assert_eq!(parts[1].1.num_rows(), 1);
+    }
+
+    #[test]
+    fn list_prunes_by_range_and_remove_cleans_up() {
+        let root = TempDir::new();
+        let hours = [
+            at("2023-12-31T23:00:00Z"),
+            at("2024-01-01T00:00:00Z"),
+            at("2024-01-01T05:00:00Z"),
+        ]
+        .map(|v| Hour::of(nanos(v)).dir(&root));
+        for dir in &hours {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        std::fs::create_dir_all(root.join("dead_letter")).unwrap();
+
+        assert_eq!(list(&root, &TimeRange::default()).unwrap(), hours);
+
+        let range = TimeRange {
+            from: Some(at("2024-01-01T00:30:00Z")),
+            to: Some(at("2024-01-01T05:00:00Z")),
+        };
+        assert_eq!(list(&root, &range).unwrap(), hours[1..]);
+
+        remove(&hours[0]).unwrap();
+        assert!(!root.join("2023").exists());
+        remove(&hours[1]).unwrap();
+        assert!(root.join("2024/01/01/05").exists());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::engine::filter::Filter;
+use crate::engine::grok::Grok;
+use crate::engine::pattern::Pattern;
+use crate::engine::value::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value as JsonValue;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Pipeline(Vec<Step>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum Step {
+    Rename {
+        from: String,
+        to: String,
+    },
+    Drop {
+        field: String,
+    },
+    Set {
+        field: String,
+        value: JsonValue,
+    },
+    Trim {
+        field: String,
+    },
+    Lowercase {
+        field: String,
+    },
+    Uppercase {
+        field: String,
+    },
+    Arithmetic {
+        field: String,
+        op: ArithmeticOp,
+        operand: ArithmeticOperand,
+        target: Option<String>,
+    },
+    Extract {
+        field: String,
+        pattern: Pattern,
+        target: String,
+    },
+    Grok(Grok),
+    If {
+        filter: Filter,
+        steps: Vec<Step>,
+        #[serde(default)]
+        otherwise: Vec<Step>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArithmeticOperand {
+    Field(String),
+    Value(JsonValue),
+}
+
+impl Pipeline {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn apply(&self, row: &mut JsonValue) {
+        Step::apply_all(&self.0, row);
+    }
+
+    /// Whether any step, taken or not, may set, change or remove the field.
+    pub fn writes(&self, field: &str) -> bool {
+        self.0.iter().any(|v| v.writes(field))
+    }
+}
+
+impl Step {
+    fn writes(&self, name: &str) -> bool {
+        match self {
+            Self::Rename { from, to } => from == name || to == name,
+            Self::Drop { field }
+            | Self::Set { field, .. }
+            | Self::Trim { field }
+            | Self::Lowercase { field }
+            | Self::Uppercase { field } => field == name,
+            Self::Arithmetic { field, target, .. } => target.as_ref().unwrap_or(field) == name,
+            Self::Extract { target, .. } => target == name,
+            Self::Grok(grok) => grok.writes(name),
+            Self::If {
+                steps, otherwise, ..
+            } => steps.iter().chain(otherwise).any(|v| v.writes(name)),
+        }
+    }
+
+    fn apply_all(steps: &[Step], row: &mut JsonValue) {
+        for step in steps {
+            step.apply(row);
+        }
+    }
+
+    fn apply(&self, row: &mut JsonValue) {
+        if let Self::If {
+            filter,
+            steps,
+            otherwise,
+        } = self
+        {
+            return match filter.matches(row) {
+                true => Self::apply_all(steps, row),
+                false => Self::apply_all(otherwise, row),
+            };
+        }
+
+        let Some(object) = row.as_object_mut() else {
+            return;
+        };
+
+        match self {
+            Self::Rename { from, to } => {
+                if let Some(v) = object.remove(from) {
+                    object.insert(to.clone(), v);
+                }
+            }
+
+            Self::Drop { field } => {
+                object.remove(field);
+            }
+
+            Self::Set { field, value } => {
+                object.insert(field.clone(), value.clone());
+            }
+
+            Self::Trim { field } => Self::map(object, field, field, |v| v.trim().into_owned()),
+
+            Self::Lowercase { field } => {
+                Self::map(object, field, field, |v| v.to_lowercase().into_owned())
+            }
+
+            Self::Uppercase { field } => {
+                Self::map(object, field, field, |v| v.to_uppercase().into_owned())
+            }
+
+            Self::Arithmetic {
+                field,
+                op,
+                operand,
+                target,
+            } => {
+                let rv = match operand {
+                    ArithmeticOperand::Field(v) => object.get(v).cloned(),
+                    ArithmeticOperand::Value(v) => Some(v.clone()),
+                };
+
+                let Some(rv) = rv else {
+                    return;
+                };
+
+                let rv = Value::from(&rv);
+                let target = target.as_ref().unwrap_or(field);
+
+                Self::map(object, field, target, |lv| match op {
+                    ArithmeticOp::Add => lv.add(&rv).into_owned(),
+                    ArithmeticOp::Sub => lv.sub(&rv).into_owned(),
+                    ArithmeticOp::Mul => lv.mul(&rv).into_owned(),
+                    ArithmeticOp::Div => lv.div(&rv).into_owned(),
+                });
+            }
+
+            Self::Extract {
+                field,
+                pattern,
+                target,
+            } => {
+                let Some(captures) = object
+                    .get(field)
+                    .and_then(|v| v.as_str())
+                    .and_then(|v| pattern.captures(v))
+                else {
+                    return;
+                };
+
+                let extracted = captures.get(1).or_else(|| captures.get(0)).unwrap();
+                let extracted = extracted.as_str().to_string();
+                object.insert(target.clone(), extracted.into());
+            }
+
+            Self::Grok(grok) => grok.apply(object),
+
+            Self::If { .. } => unreachable!(),
+        }
+    }
+
+    /// Values the function does not apply to, e.g. of another type, give
+    /// null and are left as they are.
+    fn map<F>(object: &mut Map<String, JsonValue>, field: &str, target: &str, f: F)
+    where
+        F: FnOnce(&Value) -> Value<'static>,
+    {
+        let Some(v) = object.get(field) else {
+            return;
+        };
+
+        let v = f(&Value::from(v));
+        if v != Value::Null {
+            object.insert(target.to_string(), v.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn apply(steps: JsonValue, row: JsonValue) -> JsonValue {
+        let pipeline: Pipeline = serde_json::from_value(steps).unwrap();
+        let mut row = row;
+        pipeline.apply(&mut row);
+        row
+    }
+
+    #[test]
+    fn string_steps_leave_other_types_alone() {
+        let steps = json!([
+            {"step": "trim", "field": "n"},
+            {"step": "lowercase", "field": "n"},
+            {"step": "uppercase", "field": "s"}
+        ]);
+        let row = apply(steps, json!({"n": 5, "s": " ab "}));
+        assert_eq!(row, json!({"n": 5, "s": " AB "}));
+    }
+
+    #[test]
+    fn arithmetic_promotes_mixed_operands() {
+        let steps = json!([
+            {"step": "arithmetic", "field": "i", "op": "mul", "operand": {"value": 1.5}},
+            {"step": "arithmetic", "field": "f", "op": "add", "operand": {"field": "j"}},
+            {"step": "arithmetic", "field": "j", "op": "div", "operand": {"value": 2}, "target": "k"}
+        ]);
+        let row = apply(steps, json!({"i": 2, "f": 0.5, "j": 3}));
+        assert_eq!(row, json!({"i": 3.0, "f": 3.5, "j": 3, "k": 1}));
+    }
+
+    #[test]
+    fn arithmetic_keeps_the_field_on_mismatch() {
+        let steps = json!([
+            {"step": "arithmetic", "field": "s", "op": "add", "operand": {"value": 1}},
+            {"step": "arithmetic", "field": "n", "op": "div", "operand": {"value": 0}},
+            {"step": "arithmetic", "field": "n", "op": "sub", "operand": {"value": "x"}, "target": "m"}
+        ]);
+        let row = apply(steps, json!({"s": "a", "n": 4}));
+        assert_eq!(row, json!({"s": "a", "n": 4}));
+    }
+
+    #[test]
+    fn conditional_steps() {
+        let steps = json!([
+            {"step": "rename", "from": "lvl", "to": "level"},
+            {"step": "if", "filter": {"eq": {"field": "level", "value": "warn"}},
+             "steps": [{"step": "set", "field": "alert", "value": true}],
+             "otherwise": [{"step": "drop", "field": "level"}]}
+        ]);
+        assert_eq!(
+            apply(steps.clone(), json!({"lvl": "warn"})),
+            json!({"level": "warn", "alert": true})
+        );
+        assert_eq!(apply(steps, json!({"lvl": "info"})), json!({}));
+    }
+
+    #[test]
+    fn written_fields() {
+        let pipeline: Pipeline = serde_json::from_value(json!([
+            {"step": "rename", "from": "lvl", "to": "level"},
+            {"step": "arithmetic", "field": "n", "op": "add", "operand": {"field": "m"}, "target": "sum"},
+            {"step": "grok", "field": "msg", "patterns": ["%{IP:client.ip}"]},
+            {"step": "if", "filter": {"eq": {"field": "level", "value": "warn"}},
+             "steps": [{"step": "lowercase", "field": "host"}]}
+        ]))
+        .unwrap();
+
+        for field in ["lvl", "level", "sum", "client.ip", "tags", "host"] {
+            assert!(pipeline.writes(field), "{field}");
+        }
+        for field in ["n", "m", "msg", "user"] {
+            assert!(!pipeline.writes(field), "{field}");
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::engine::block;
+use crate::engine::catalog::BlockEntry;
+use crate::engine::catalog::Catalog;
+use crate::engine::filter::Filter;
+use crate::engine::partition::TimeRange;
+use crate::engine::tiering::Pinned;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::sync::Arc;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Query {
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    #[serde(flatten)]
+    pub range: TimeRange,
+    #[serde(default = "Query::default_limit")]
+    pub limit: usize,
+}
+
+impl Query {
+    fn default_limit() -> usize {
+        1000
+    }
+
+    pub fn matches(&self, row: &JsonValue, time_field: &str) -> bool {
+        let in_range = match (self.range.from, self.range.to) {
+            (None, None) => true,
+            _ => row
+                .get(time_field)
+                .and_then(|v| v.as_str())
+                .and_then(|v| OffsetDateTime::parse(v, &Rfc3339).ok())
+                .is_some_and(|v| self.range.contains(v)),
+        };
+
+        in_range && self.filter.as_ref().is_none_or(|f| f.matches(row))
+    }
+
+    /// Blocks the catalog can not rule out by time range and statistics.
+    pub fn blocks(&self, catalog: &Catalog) -> Vec<BlockEntry> {
+        catalog.blocks(&self.range, self.filter.as_ref())
+    }
+
+    /// Scans the flushed blocks of the catalogs that may hold matching rows,
+    /// in event time order of their partitions; `locate` gives the local file
+    /// of a block.
+    pub fn run<E: From<block::Error>>(
+        &self,
+        catalogs: &[Arc<Catalog>],
+        time_field: &str,
+        mut locate: impl FnMut(&Catalog, &BlockEntry) -> Result<Pinned, E>,
+    ) -> Result<Vec<JsonValue>, E> {
+        let _guards = catalogs.iter().map(|v| v.lock_read()).collect::<Vec<_>>();
+        let mut blocks = catalogs
+            .iter()
+            .flat_map(|c| self.blocks(c).into_iter().map(move |v| (c, v)))
+            .collect::<Vec<_>>();
+        blocks.sort_by(|(_, a), (_, b)| (&a.partition, a.id).cmp(&(&b.partition, b.id)));
+
+        let mut rows = Vec::new();
+
+        for (catalog, entry) in blocks {
+            for batch in block::read(&locate(catalog, &entry)?, catalog.keys())? {
+                for row in block::to_json_rows(&batch) {
+                    if rows.len() >= self.limit {
+                        return Ok(rows);
+                    }
+
+                    if self.matches(&row, time_field) {
+                        rows.push(row);
+                    }
+                }
+            }
+        }
+
+        Ok(rows)
+    }
+}
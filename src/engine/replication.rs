@@ -0,0 +1,667 @@
+use crate::engine::block;
+use crate::engine::catalog::BlockEntry;
+use crate::engine::catalog::Catalog;
+use crate::engine::crypto;
+use crate::engine::crypto::MasterKey;
+use crate::engine::crypto::WrappedKey;
+use crate::engine::partition;
+use crate::engine::partition::TimeRange;
+use crate::picodata::rpc;
+use crate::picodata::rpc::Path as RpcPath;
+use crate::picodata::rpc::ProxyClient;
+use crate::picodata::rpc::ProxyRequest;
+use crate::picodata::service::ServiceWarnings;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::select;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+use tokio_util::sync::DropGuard;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("rpc: {0}")]
+    Rpc(#[from] rpc::Error),
+    #[error("block error: {0}")]
+    Block(#[from] block::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("encryption: {0}")]
+    Crypto(#[from] crypto::Error),
+    #[error("malformed replication message")]
+    Malformed,
+    #[error("checksum mismatch of block {0}")]
+    Checksum(Uuid),
+    #[error("no copy of block {0}")]
+    NoCopy(Uuid),
+    #[error("{0} holds {1} copies of blocks this instance has no record of, they are kept")]
+    Unknown(String, usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplicationConfig {
+    /// This instance, the origin of the blocks it ships.
+    pub instance: String,
+    pub interval: Duration,
+    pub client: ProxyClient,
+}
+
+/// A `Path::Replicate` request: the length of a JSON header, the header and
+/// then the block file of a `Block` message.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Message {
+    /// Blocks the origin holds, answered with a `SyncReply`. Copies are
+    /// never dropped for missing from the list, only by a `Remove`.
+    Sync {
+        stream: String,
+        origin: String,
+        blocks: Vec<Uuid>,
+    },
+    /// Blocks the origin removed. The peer drops its copies of them and the
+    /// keys of the origin its copies no longer use, shredded ones included.
+    Remove {
+        stream: String,
+        origin: String,
+        blocks: Vec<Uuid>,
+    },
+    Block {
+        origin: String,
+        entry: Box<BlockEntry>,
+        checksum: String,
+        /// Data key of an encrypted block.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<WrappedKey>,
+    },
+    /// Asks for the copy of a block flushed by `origin`, to repair it there.
+    /// Answered with the block file.
+    Fetch {
+        stream: String,
+        origin: String,
+        id: Uuid,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncReply {
+    /// Blocks of the origin the peer has no copy of.
+    missing: Vec<Uuid>,
+    /// Copies the peer holds of blocks the origin did not list.
+    unknown: Vec<Uuid>,
+}
+
+/// Ships the blocks of a stream to the other instances of the replicaset.
+pub struct Replicator {
+    stream: String,
+    catalog: Arc<Catalog>,
+    config: ReplicationConfig,
+    sw: ServiceWarnings,
+}
+
+/// Copies of blocks flushed by other instances of the replicaset, with a
+/// catalog per origin. The origin decides when they are compacted or removed.
+pub struct Copies {
+    stream: String,
+    dir: PathBuf,
+    master: Option<Arc<MasterKey>>,
+    catalogs: Mutex<BTreeMap<String, Arc<Catalog>>>,
+}
+
+impl Message {
+    pub fn stream(&self) -> &str {
+        match self {
+            Self::Sync { stream, .. } => stream,
+            Self::Remove { stream, .. } => stream,
+            Self::Block { entry, .. } => &entry.stream,
+            Self::Fetch { stream, .. } => stream,
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let header = serde_json::to_vec(self).unwrap();
+        let mut message = Vec::with_capacity(4 + header.len() + data.len());
+        message.extend((header.len() as u32).to_le_bytes());
+        message.extend(header);
+        message.extend(data);
+        message
+    }
+
+    pub fn decode(message: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let (len, rest) = message.split_first_chunk::<4>().ok_or(Error::Malformed)?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len {
+            Err(Error::Malformed)?;
+        }
+
+        let (header, data) = rest.split_at(len);
+        Ok((serde_json::from_slice(header)?, data))
+    }
+}
+
+impl ReplicationConfig {
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// The other instances of the replicaset, taken from picodata each time
+    /// so instances joining or leaving are followed.
+    async fn peers(&self) -> Result<Vec<String>, Error> {
+        Ok(self.client.peers().await?)
+    }
+
+    /// Copy of a block of this instance held by one of the peers.
+    pub async fn fetch(&self, stream: &str, id: Uuid) -> Result<Vec<u8>, Error> {
+        let mut result = Err(Error::NoCopy(id));
+
+        for peer in self.peers().await? {
+            let message = Message::Fetch {
+                stream: stream.to_string(),
+                origin: self.instance.clone(),
+                id,
+            };
+
+            match self.send(&peer, message, &[]).await {
+                Ok(data) => return Ok(data),
+                Err(e) => result = Err(e),
+            }
+        }
+
+        result
+    }
+
+    async fn send(&self, peer: &str, message: Message, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let response = self
+            .client
+            .send_async(ProxyRequest {
+                instance: peer.to_string(),
+                path: RpcPath::Replicate,
+                data: message.encode(data),
+                timeout: Self::TIMEOUT,
+            })
+            .await?;
+
+        Ok(response.as_bytes().to_vec())
+    }
+}
+
+impl Replicator {
+    /// Ships blocks every interval until the returned guard is dropped.
+    pub fn spawn(
+        stream: String,
+        catalog: Arc<Catalog>,
+        config: ReplicationConfig,
+        sw: ServiceWarnings,
+        tt: &TaskTracker,
+    ) -> DropGuard {
+        let ct = CancellationToken::new();
+        let replicator = Arc::new(Self {
+            stream,
+            catalog,
+            config,
+            sw,
+        });
+
+        tt.spawn(replicator.run(ct.clone()));
+        ct.drop_guard()
+    }
+
+    async fn run(self: Arc<Self>, ct: CancellationToken) {
+        let mut ticker = interval(self.config.interval);
+
+        loop {
+            select! {
+                _ = ct.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+
+            let result = select! {
+                _ = ct.cancelled() => return,
+                v = self.replicate_all() => v,
+            };
+
+            self.sw.set_job_error(
+                "replication",
+                &self.stream,
+                result.err().map(|e| e.to_string()),
+            );
+        }
+    }
+
+    /// A peer that is down does not hold up the others.
+    async fn replicate_all(&self) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        for peer in self.config.peers().await? {
+            if let Err(e) = self.replicate(&peer).await {
+                result = Err(e);
+            }
+        }
+
+        result
+    }
+
+    async fn replicate(&self, peer: &str) -> Result<(), Error> {
+        // Before the sync, so copies of removed blocks are not reported as
+        // unknown.
+        let removed = self
+            .catalog
+            .removed()
+            .into_iter()
+            .filter(|(_, v)| v.iter().any(|v| v == peer))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+
+        if !removed.is_empty() {
+            let message = Message::Remove {
+                stream: self.stream.clone(),
+                origin: self.config.instance.clone(),
+                blocks: removed.clone(),
+            };
+            self.config.send(peer, message, &[]).await?;
+            self.catalog.released(peer, &removed)?;
+        }
+
+        let blocks = self.catalog.blocks(&TimeRange::default(), None);
+        let sync = Message::Sync {
+            stream: self.stream.clone(),
+            origin: self.config.instance.clone(),
+            blocks: blocks.iter().map(|v| v.id).collect(),
+        };
+        let reply = serde_json::from_slice::<SyncReply>(&self.config.send(peer, sync, &[]).await?)?;
+        let missing = reply.missing.into_iter().collect::<HashSet<_>>();
+
+        for entry in blocks {
+            if missing.contains(&entry.id) {
+                // Offloaded blocks are kept by the object store, damaged ones
+                // are not spread until they are repaired.
+                if entry.remote.is_some() || entry.damaged.is_some() {
+                    continue;
+                }
+
+                let data = match tokio::fs::read(entry.path(self.catalog.dir())).await {
+                    // Merged away by compaction meanwhile.
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    v => v?,
+                };
+
+                let key = match crypto::key_id(&data) {
+                    Some(id) => Some(
+                        self.catalog
+                            .keys()
+                            .ok_or(crypto::Error::NotConfigured)?
+                            .export(id)?,
+                    ),
+                    None => None,
+                };
+
+                let message = Message::Block {
+                    origin: self.config.instance.clone(),
+                    key,
+                    checksum: entry
+                        .checksum
+                        .clone()
+                        .unwrap_or_else(|| block::checksum(&data)),
+                    entry: Box::new(BlockEntry {
+                        replicas: Vec::new(),
+                        ..entry.clone()
+                    }),
+                };
+                self.config.send(peer, message, &data).await?;
+            }
+
+            if !entry.replicas.iter().any(|v| v == peer) {
+                self.catalog
+                    .update(entry.id, |v| v.replicas.push(peer.to_string()))?;
+            }
+        }
+
+        // Left e.g. by an instance that lost its data and flushes anew. Only
+        // an operator can tell whether they may go.
+        let removed = self.catalog.removed();
+        let unknown = reply
+            .unknown
+            .iter()
+            .filter(|v| !removed.contains_key(v))
+            .count();
+        if unknown > 0 {
+            Err(Error::Unknown(peer.to_string(), unknown))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Copies {
+    pub const DIR_NAME: &'static str = "replicas";
+
+    pub fn open(stream: &str, dir: &Path, master: Option<Arc<MasterKey>>) -> Result<Self, Error> {
+        let dir = dir.join(Self::DIR_NAME);
+        let mut catalogs = BTreeMap::new();
+
+        match std::fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let origin = entry?.file_name().to_string_lossy().to_string();
+                    let catalog = Catalog::open(stream, &dir.join(&origin), master.as_ref(), None)?;
+                    catalogs.insert(origin, catalog);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => Err(e)?,
+        }
+
+        Ok(Self {
+            stream: stream.to_string(),
+            dir,
+            master,
+            catalogs: Mutex::new(catalogs),
+        })
+    }
+
+    pub fn catalogs(&self) -> Vec<Arc<Catalog>> {
+        self.catalogs.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Instances this one holds copies of blocks of.
+    pub fn origins(&self) -> Vec<String> {
+        self.catalogs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, v)| !v.blocks(&TimeRange::default(), None).is_empty())
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// Answers a `Path::Replicate` request of another instance.
+    pub fn handle(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        match Message::decode(message)? {
+            (Message::Sync { origin, blocks, .. }, _) => {
+                Ok(serde_json::to_vec(&self.sync(&origin, &blocks)?)?)
+            }
+            (Message::Remove { origin, blocks, .. }, _) => {
+                self.remove(&origin, &blocks)?;
+                Ok(Vec::new())
+            }
+            (
+                Message::Block {
+                    origin,
+                    entry,
+                    checksum,
+                    key,
+                },
+                data,
+            ) => {
+                self.store(&origin, *entry, &checksum, key, data)?;
+                Ok(Vec::new())
+            }
+            (Message::Fetch { origin, id, .. }, _) => self.fetch(&origin, id),
+        }
+    }
+
+    fn sync(&self, origin: &str, blocks: &[Uuid]) -> Result<SyncReply, Error> {
+        let catalog = self.catalog(origin)?;
+
+        // Damaged copies are shipped again.
+        let (damaged, copies): (Vec<_>, Vec<_>) = catalog
+            .blocks(&TimeRange::default(), None)
+            .into_iter()
+            .partition(|v| v.damaged.is_some());
+        drop_copies(&catalog, &damaged)?;
+
+        let listed = blocks.iter().collect::<HashSet<_>>();
+        let present = copies.iter().map(|v| v.id).collect::<HashSet<_>>();
+        Ok(SyncReply {
+            missing: blocks
+                .iter()
+                .filter(|v| !present.contains(v))
+                .copied()
+                .collect(),
+            unknown: copies
+                .iter()
+                .map(|v| v.id)
+                .filter(|v| !listed.contains(v))
+                .collect(),
+        })
+    }
+
+    fn remove(&self, origin: &str, blocks: &[Uuid]) -> Result<(), Error> {
+        let catalog = self.catalog(origin)?;
+        let ids = blocks.iter().collect::<HashSet<_>>();
+        let removed = catalog
+            .blocks(&TimeRange::default(), None)
+            .into_iter()
+            .filter(|v| ids.contains(&v.id))
+            .collect::<Vec<_>>();
+        drop_copies(&catalog, &removed)?;
+
+        let Some(keyring) = catalog.keys() else {
+            return Ok(());
+        };
+
+        // Keys are imported along with their blocks under the same lock.
+        let _guard = catalog.lock_write();
+        let mut used = HashSet::new();
+        for entry in catalog.blocks(&TimeRange::default(), None) {
+            used.extend(crypto::read_key_id(&entry.path(catalog.dir()))?);
+        }
+        keyring.retain(&used)?;
+
+        Ok(())
+    }
+
+    fn fetch(&self, origin: &str, id: Uuid) -> Result<Vec<u8>, Error> {
+        let catalog = self.catalog(origin)?;
+        let entry = catalog
+            .blocks(&TimeRange::default(), None)
+            .into_iter()
+            .find(|v| v.id == id && v.damaged.is_none())
+            .ok_or(Error::NoCopy(id))?;
+
+        let _guard = catalog.lock_read();
+        match std::fs::read(entry.path(catalog.dir())) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::NoCopy(id)),
+            v => Ok(v?),
+        }
+    }
+
+    fn store(
+        &self,
+        origin: &str,
+        entry: BlockEntry,
+        checksum: &str,
+        key: Option<WrappedKey>,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let relative = entry
+            .partition
+            .components()
+            .all(|v| matches!(v, Component::Normal(_)));
+
+        if !relative || entry.stream != self.stream {
+            Err(Error::Malformed)?;
+        }
+
+        if block::checksum(data) != checksum {
+            Err(Error::Checksum(entry.id))?;
+        }
+
+        let catalog = self.catalog(origin)?;
+        let path = entry.path(catalog.dir());
+        let tmp_path = path.with_extension("tmp");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&tmp_path, data)?;
+        std::fs::File::open(&tmp_path)?.sync_all()?;
+
+        let _guard = catalog.lock_write();
+        if let Some(key) = key {
+            catalog
+                .keys()
+                .ok_or(crypto::Error::NotConfigured)?
+                .import(vec![key])?;
+        }
+        std::fs::rename(&tmp_path, &path)?;
+        catalog.add(BlockEntry {
+            origin: Some(origin.to_string()),
+            remote: None,
+            replicas: Vec::new(),
+            checksum: Some(checksum.to_string()),
+            damaged: None,
+            ..entry
+        })?;
+
+        Ok(())
+    }
+
+    fn catalog(&self, origin: &str) -> Result<Arc<Catalog>, Error> {
+        let valid = !origin.is_empty()
+            && origin
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+        if !valid {
+            Err(Error::Malformed)?;
+        }
+
+        let mut catalogs = self.catalogs.lock().unwrap();
+        if let Some(catalog) = catalogs.get(origin) {
+            return Ok(catalog.clone());
+        }
+
+        let catalog = Catalog::open(
+            &self.stream,
+            &self.dir.join(origin),
+            self.master.as_ref(),
+            None,
+        )?;
+        catalogs.insert(origin.to_string(), catalog.clone());
+        Ok(catalog)
+    }
+}
+
+fn drop_copies(catalog: &Catalog, entries: &[BlockEntry]) -> Result<(), Error> {
+    {
+        let _guard = catalog.lock_write();
+        for entry in entries {
+            block::remove_if_exists(&entry.path(catalog.dir()))?;
+        }
+        catalog.remove(&entries.iter().map(|v| v.id).collect::<Vec<_>>())?;
+    }
+
+    for partition in partition::list(catalog.dir(), &TimeRange::default())? {
+        if std::fs::read_dir(&partition)?.next().is_none() {
+            partition::remove(&partition)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing::write_block_with;
+    use crate::engine::block::testing::TempDir;
+
+    fn master(dir: &Path) -> Arc<MasterKey> {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("master.key"), [7; 32]).unwrap();
+        MasterKey::load(&dir.join("master.key"), None).unwrap()
+    }
+
+    /// A `Block` message of a new block of the origin catalog.
+    fn ship(origin: &Catalog) -> (Uuid, Vec<u8>) {
+        let props = block::properties().build();
+        let path = write_block_with(origin.dir(), vec![0], props, origin.keys());
+        let entry = origin.describe(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let key = crypto::key_id(&data).map(|v| origin.keys().unwrap().export(v).unwrap());
+        let message = Message::Block {
+            origin: "i1".into(),
+            checksum: block::checksum(&data),
+            entry: Box::new(entry.clone()),
+            key,
+        };
+        (entry.id, message.encode(&data))
+    }
+
+    fn sync(copies: &Copies, blocks: Vec<Uuid>) -> SyncReply {
+        let message = Message::Sync {
+            stream: "s".into(),
+            origin: "i1".into(),
+            blocks,
+        };
+        serde_json::from_slice(&copies.handle(&message.encode(&[])).unwrap()).unwrap()
+    }
+
+    fn remove(copies: &Copies, blocks: Vec<Uuid>) {
+        let message = Message::Remove {
+            stream: "s".into(),
+            origin: "i1".into(),
+            blocks,
+        };
+        copies.handle(&message.encode(&[])).unwrap();
+    }
+
+    #[test]
+    fn copies_are_dropped_only_when_removed() {
+        let dir = TempDir::new();
+        let origin = Catalog::open("s", &dir.join("origin"), None, None).unwrap();
+        let copies = Copies::open("s", &dir.join("peer"), None).unwrap();
+        let (id, message) = ship(&origin);
+        copies.handle(&message).unwrap();
+
+        // An origin that lost its catalog does not wipe the copies.
+        let reply = sync(&copies, Vec::new());
+        assert_eq!(reply.unknown, [id]);
+        assert!(reply.missing.is_empty());
+        assert_eq!(
+            copies.catalogs()[0]
+                .blocks(&TimeRange::default(), None)
+                .len(),
+            1
+        );
+
+        let reply = sync(&copies, vec![id]);
+        assert!(reply.unknown.is_empty());
+        assert!(reply.missing.is_empty());
+
+        remove(&copies, vec![id]);
+        assert!(copies.catalogs()[0]
+            .blocks(&TimeRange::default(), None)
+            .is_empty());
+        assert_eq!(sync(&copies, vec![id]).missing, [id]);
+    }
+
+    #[test]
+    fn keys_go_with_the_last_copy_using_them() {
+        let dir = TempDir::new();
+        let master = master(&dir);
+        let origin = Catalog::open("s", &dir.join("origin"), Some(&master), None).unwrap();
+        let copies = Copies::open("s", &dir.join("peer"), Some(master)).unwrap();
+
+        let (first, message) = ship(&origin);
+        copies.handle(&message).unwrap();
+        origin.keys().unwrap().rotate().unwrap();
+        let (second, message) = ship(&origin);
+        copies.handle(&message).unwrap();
+
+        let keys = || copies.catalogs()[0].keys().unwrap().list().len();
+        assert_eq!(keys(), 2);
+        remove(&copies, vec![first]);
+        assert_eq!(keys(), 1);
+        remove(&copies, vec![second]);
+        assert_eq!(keys(), 0);
+    }
+}
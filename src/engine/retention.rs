@@ -0,0 +1,429 @@
+use crate::engine::block;
+use crate::engine::catalog::block_id;
+use crate::engine::catalog::BlockEntry;
+use crate::engine::catalog::Catalog;
+use crate::engine::partition;
+use crate::engine::partition::Hour;
+use crate::engine::partition::TimeRange;
+use crate::engine::tiering;
+use crate::engine::tiering::Remote;
+use crate::picodata::service::ServiceWarnings;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::select;
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+use tokio_util::sync::DropGuard;
+use tokio_util::task::TaskTracker;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("block error: {0}")]
+    Block(#[from] block::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("tiering: {0}")]
+    Tiering(#[from] tiering::Error),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Retention {
+    /// Seconds of event time to keep.
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    /// Bytes of local blocks to keep, the oldest blocks are removed first.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Suspends deletion while the stream's data must be preserved.
+    #[serde(default)]
+    pub legal_hold: bool,
+    /// Seconds rejected rows are kept in the dead letter, a week if not set.
+    #[serde(default)]
+    pub dead_letter_max_age: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RetentionReport {
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_run: Option<OffsetDateTime>,
+    pub held: bool,
+    /// Blocks and bytes removed by the last run.
+    pub removed_blocks: usize,
+    pub reclaimed_bytes: u64,
+    /// Bytes removed since the stream was opened.
+    pub total_reclaimed_bytes: u64,
+}
+
+/// Removes blocks of a stream past its retention limits.
+pub struct Enforcer {
+    stream: String,
+    catalog: Arc<Catalog>,
+    retention: Retention,
+    remote: Option<Arc<Remote>>,
+    report: Mutex<RetentionReport>,
+    sw: ServiceWarnings,
+}
+
+impl Retention {
+    const DEAD_LETTER_MAX_AGE: u64 = 7 * 24 * 3600;
+
+    /// Retention of the stream's dead letter.
+    pub fn dead_letter(&self) -> Self {
+        Self {
+            max_age: Some(
+                self.dead_letter_max_age
+                    .unwrap_or(Self::DEAD_LETTER_MAX_AGE),
+            ),
+            max_bytes: None,
+            legal_hold: self.legal_hold,
+            dead_letter_max_age: None,
+        }
+    }
+}
+
+impl Enforcer {
+    pub fn new(
+        stream: String,
+        catalog: Arc<Catalog>,
+        retention: Retention,
+        remote: Option<Arc<Remote>>,
+        sw: ServiceWarnings,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            stream,
+            catalog,
+            retention,
+            remote,
+            report: Default::default(),
+            sw,
+        })
+    }
+
+    /// Enforces retention every `period` until the returned guard is dropped.
+    pub fn spawn(self: &Arc<Self>, period: Duration, tt: &TaskTracker) -> DropGuard {
+        let ct = CancellationToken::new();
+        tt.spawn(self.clone().run(period, ct.clone()));
+        ct.drop_guard()
+    }
+
+    pub fn report(&self) -> RetentionReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    /// Local blocks of the stream, oldest first, none while it is under
+    /// legal hold.
+    pub fn removable(&self) -> Vec<BlockEntry> {
+        if self.retention.legal_hold {
+            return Vec::new();
+        }
+
+        self.catalog
+            .blocks(&TimeRange::default(), None)
+            .into_iter()
+            .filter(|v| v.remote.is_none())
+            .collect()
+    }
+
+    /// Removes a block ahead of the retention limits, when disk space runs out.
+    pub fn remove(&self, block: &BlockEntry) -> Result<(), Error> {
+        let partition = self.catalog.dir().join(&block.partition);
+
+        {
+            let _guard = self.catalog.lock_write();
+            self.catalog.remove(&[block.id])?;
+            block::remove_if_exists(&block.path(self.catalog.dir()))?;
+        }
+
+        if std::fs::read_dir(&partition)?.next().is_none() {
+            partition::remove(&partition)?;
+        }
+
+        self.report.lock().unwrap().total_reclaimed_bytes += block.bytes;
+        Ok(())
+    }
+
+    async fn run(self: Arc<Self>, period: Duration, ct: CancellationToken) {
+        let mut ticker = interval(period);
+
+        loop {
+            select! {
+                _ = ct.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+
+            let this = self.clone();
+            let (result, offloaded) = spawn_blocking(move || {
+                let mut offloaded = Vec::new();
+                (this.enforce(&mut offloaded), offloaded)
+            })
+            .await
+            .unwrap();
+
+            let result = match result {
+                Ok(()) => self.remove_offloaded(offloaded).await,
+                e => e,
+            };
+
+            self.sw.set_job_error(
+                "retention",
+                &self.stream,
+                result.err().map(|e| e.to_string()),
+            );
+        }
+    }
+
+    /// Expired offloaded blocks are only collected here, they are removed
+    /// with their objects afterwards.
+    fn enforce(&self, offloaded: &mut Vec<BlockEntry>) -> Result<(), Error> {
+        let now = OffsetDateTime::now_utc();
+        let mut removed = (0, 0);
+
+        let result = match self.retention.legal_hold {
+            true => Ok(()),
+            false => self
+                .remove_expired(now, &mut removed)
+                .map(|_| offloaded.extend(self.expired_offloaded(now))),
+        };
+
+        // Blocks removed before a failure are still reported.
+        self.update_report(now, removed);
+        result
+    }
+
+    fn remove_expired(&self, now: OffsetDateTime, removed: &mut (usize, u64)) -> Result<(), Error> {
+        let cutoff = self.retention.max_age.map(|v| now - Duration::from_secs(v));
+
+        let mut kept = Vec::new();
+
+        for partition in partition::list(self.catalog.dir(), &TimeRange::default())? {
+            let expired = cutoff
+                .zip(Hour::of_dir(&partition))
+                .is_some_and(|(cutoff, hour)| hour.end() <= cutoff);
+
+            if !expired {
+                kept.push(partition);
+                continue;
+            }
+
+            // Flushes hold the read lock while they write a block and add it
+            // to the catalog, so none is left behind half-written.
+            let _guard = self.catalog.lock_write();
+            let blocks = Self::blocks(&partition)?;
+            let ids = blocks
+                .iter()
+                .filter_map(|(v, _)| block_id(v))
+                .collect::<Vec<_>>();
+            self.catalog.remove(&ids)?;
+            partition::remove(&partition)?;
+
+            removed.0 += blocks.len();
+            removed.1 += blocks.iter().map(|(_, size)| size).sum::<u64>();
+        }
+
+        let Some(max_bytes) = self.retention.max_bytes else {
+            return Ok(());
+        };
+
+        let mut blocks = Vec::new();
+        for partition in &kept {
+            blocks.extend(Self::blocks(partition)?);
+        }
+
+        let mut total = blocks.iter().map(|(_, size)| size).sum::<u64>();
+
+        for (path, size) in blocks {
+            if total <= max_bytes {
+                break;
+            }
+
+            let _guard = self.catalog.lock_write();
+            self.catalog.remove(&Vec::from_iter(block_id(&path)))?;
+            // Merged away by compaction meanwhile.
+            if !block::remove_if_exists(&path)? {
+                continue;
+            }
+            total -= size;
+
+            removed.0 += 1;
+            removed.1 += size;
+        }
+
+        for partition in &kept {
+            if std::fs::read_dir(partition)?.next().is_none() {
+                partition::remove(partition)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn expired_offloaded(&self, now: OffsetDateTime) -> Vec<BlockEntry> {
+        let Some(cutoff) = self.retention.max_age.map(|v| now - Duration::from_secs(v)) else {
+            return Vec::new();
+        };
+
+        self.catalog
+            .blocks(&TimeRange::default(), None)
+            .into_iter()
+            .filter(|v| v.remote.is_some())
+            .filter(|v| Hour::of_dir(&v.partition).is_some_and(|hour| hour.end() <= cutoff))
+            .collect()
+    }
+
+    /// Deletes the objects of expired offloaded blocks. A block leaves the
+    /// catalog only once its object is deleted, so an object store failure
+    /// is retried by the next run instead of leaving the object behind.
+    async fn remove_offloaded(&self, expired: Vec<BlockEntry>) -> Result<(), Error> {
+        let Some(remote) = &self.remote else {
+            return Ok(());
+        };
+
+        let mut removed = (0, 0);
+        let mut result = Ok(());
+
+        for block in expired {
+            let Some(key) = &block.remote else {
+                continue;
+            };
+
+            result = match remote.delete(key).await {
+                Ok(()) => self.catalog.remove(&[block.id]).map_err(Error::from),
+                Err(e) => Err(e.into()),
+            };
+            if result.is_err() {
+                break;
+            }
+
+            removed.0 += 1;
+            removed.1 += block.bytes;
+        }
+
+        let mut report = self.report.lock().unwrap();
+        report.removed_blocks += removed.0;
+        report.reclaimed_bytes += removed.1;
+        report.total_reclaimed_bytes += removed.1;
+        result
+    }
+
+    fn blocks(partition: &Path) -> Result<Vec<(PathBuf, u64)>, Error> {
+        block::list_partition(partition)?
+            .into_iter()
+            .map(|path| {
+                let size = std::fs::metadata(&path)?.len();
+                Ok((path, size))
+            })
+            .collect()
+    }
+
+    fn update_report(&self, now: OffsetDateTime, (blocks, bytes): (usize, u64)) {
+        let mut report = self.report.lock().unwrap();
+        report.last_run = Some(now);
+        report.held = self.retention.legal_hold;
+        report.removed_blocks = blocks;
+        report.reclaimed_bytes = bytes;
+        report.total_reclaimed_bytes += bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing::TempDir;
+    use crate::engine::block::Provenance;
+    use crate::engine::schema::build_schema;
+    use crate::engine::schema::RECEIVED_AT;
+    use arrow::array::RecordBatch;
+    use arrow::array::StringArray;
+    use arrow::array::TimestampNanosecondArray;
+    use uuid::Uuid;
+
+    fn add_block(catalog: &Catalog, time: OffsetDateTime) {
+        let schema = build_schema(&[], RECEIVED_AT, 1);
+        let text = || Arc::new(StringArray::from(vec!["x"])) as _;
+        let nanos = time.unix_timestamp_nanos() as i64;
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                text(),
+                Arc::new(TimestampNanosecondArray::from(vec![nanos]).with_timezone("UTC")),
+                text(),
+                text(),
+                text(),
+            ],
+        )
+        .unwrap();
+
+        let partition = Hour::of(nanos).dir(catalog.dir());
+        std::fs::create_dir_all(&partition).unwrap();
+        let path = block::path(&partition, Uuid::now_v7());
+        let props = block::properties().build();
+        block::write(&path, &batch, props, &Provenance::default(), None).unwrap();
+        catalog.add(catalog.describe(&path).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn expired_partitions_are_removed() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let now = OffsetDateTime::now_utc();
+        add_block(&catalog, now - Duration::from_secs(30 * 24 * 3600));
+        add_block(&catalog, now - Duration::from_secs(3 * 24 * 3600));
+        add_block(&catalog, now);
+
+        let retention = Retention::default().dead_letter();
+        assert_eq!(retention.max_age, Some(7 * 24 * 3600));
+
+        let enforcer = Enforcer::new(
+            "s".into(),
+            catalog.clone(),
+            retention,
+            None,
+            ServiceWarnings::default(),
+        );
+        enforcer.enforce(&mut Vec::new()).unwrap();
+
+        assert_eq!(enforcer.report().removed_blocks, 1);
+        assert_eq!(catalog.blocks(&TimeRange::default(), None).len(), 2);
+        assert_eq!(
+            partition::list(&dir, &TimeRange::default()).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn legal_hold_keeps_everything() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        add_block(
+            &catalog,
+            OffsetDateTime::now_utc() - Duration::from_secs(3600 * 5),
+        );
+
+        let retention = Retention {
+            max_age: Some(60),
+            max_bytes: Some(0),
+            legal_hold: true,
+            dead_letter_max_age: None,
+        };
+        let enforcer = Enforcer::new(
+            "s".into(),
+            catalog.clone(),
+            retention,
+            None,
+            ServiceWarnings::default(),
+        );
+        enforcer.enforce(&mut Vec::new()).unwrap();
+
+        assert!(enforcer.report().held);
+        assert_eq!(catalog.blocks(&TimeRange::default(), None).len(), 1);
+        assert!(enforcer.removable().is_empty());
+    }
+}
@@ -1,15 +1,123 @@
+use crate::engine::partition::TIME_FIELD;
+use crate::engine::value::Value;
+use arrow::array::Array;
 use arrow::array::ArrayBuilder;
+use arrow::array::AsArray;
 use arrow::array::BooleanBuilder;
 use arrow::array::Float64Builder;
 use arrow::array::Int64Builder;
 use arrow::array::ListBuilder;
 use arrow::array::StringBuilder;
+use arrow::array::TimestampNanosecondBuilder;
 use arrow::datatypes::DataType;
 use arrow::datatypes::Field;
+use arrow::datatypes::Float64Type;
+use arrow::datatypes::Int64Type;
+use arrow::datatypes::Schema;
+use arrow::datatypes::TimeUnit;
+use arrow::datatypes::TimestampNanosecondType;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::Arc;
+use time::OffsetDateTime;
+
+pub const RECORD_ID: &str = "_id";
+pub const RECEIVED_AT: &str = "_received_at";
+pub const INSTANCE: &str = "_instance";
+pub const CLIENT: &str = "_client";
+pub const PEER: &str = "_peer";
+
+/// Schema metadata key holding the stream definition version.
+pub const SCHEMA_VERSION: &str = "version";
+
+/// Number of system fields every stream schema starts with.
+pub const SYSTEM_FIELDS: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldDefinition {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: FieldType,
+    #[serde(default)]
+    pub nullable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    String,
+    I64,
+    F64,
+    Bool,
+    Timestamp,
+    VecString,
+    VecI64,
+    VecF64,
+    VecBool,
+}
+
+impl FieldDefinition {
+    pub fn to_field(&self) -> Field {
+        Field::new(&self.name, self.kind.data_type(), self.nullable)
+    }
+}
+
+impl FieldType {
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Self::String => DataType::Utf8,
+            Self::I64 => DataType::Int64,
+            Self::F64 => DataType::Float64,
+            Self::Bool => DataType::Boolean,
+            Self::Timestamp => timestamp_type(),
+            Self::VecString => DataType::new_list(DataType::Utf8, true),
+            Self::VecI64 => DataType::new_list(DataType::Int64, true),
+            Self::VecF64 => DataType::new_list(DataType::Float64, true),
+            Self::VecBool => DataType::new_list(DataType::Boolean, true),
+        }
+    }
+}
+
+pub fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into()))
+}
+
+/// Names starting with an underscore are kept for system fields.
+pub fn is_reserved(name: &str) -> bool {
+    name.starts_with('_')
+}
+
+/// Stream schema: system fields filled in by the accumulator, then the
+/// fields of the stream definition. Blocks are partitioned by `time_field`.
+pub fn build_schema(fields: &[FieldDefinition], time_field: &str, version: u64) -> Arc<Schema> {
+    let system = [
+        Field::new(RECORD_ID, DataType::Utf8, false),
+        Field::new(RECEIVED_AT, timestamp_type(), false),
+        Field::new(INSTANCE, DataType::Utf8, false),
+        Field::new(CLIENT, DataType::Utf8, true),
+        Field::new(PEER, DataType::Utf8, true),
+    ];
+
+    let metadata = HashMap::from([
+        (TIME_FIELD.to_string(), time_field.to_string()),
+        (SCHEMA_VERSION.to_string(), version.to_string()),
+    ]);
+
+    Arc::new(Schema::new_with_metadata(
+        system
+            .into_iter()
+            .chain(fields.iter().map(|f| f.to_field()))
+            .collect::<Vec<_>>(),
+        metadata,
+    ))
+}
 
 pub trait DomainField {
     fn builder(&self) -> Box<dyn ArrayBuilder>;
     fn append_null(&self, builder: &mut dyn ArrayBuilder);
+    fn to_json(&self, array: &dyn Array, index: usize) -> JsonValue;
 }
 
 impl DomainField for Field {
@@ -19,6 +127,9 @@ impl DomainField for Field {
             DataType::Int64 => Box::new(Int64Builder::new()),
             DataType::Float64 => Box::new(Float64Builder::new()),
             DataType::Boolean => Box::new(BooleanBuilder::new()),
+            DataType::Timestamp(TimeUnit::Nanosecond, tz) => {
+                Box::new(TimestampNanosecondBuilder::new().with_timezone_opt(tz.clone()))
+            }
             DataType::List(v) => match v.data_type() {
                 DataType::Utf8 => Box::new(ListBuilder::new(StringBuilder::new())),
                 DataType::Int64 => Box::new(ListBuilder::new(Int64Builder::new())),
@@ -37,6 +148,10 @@ impl DomainField for Field {
             DataType::Int64 => b.downcast_mut::<Int64Builder>().unwrap().append_null(),
             DataType::Float64 => b.downcast_mut::<Float64Builder>().unwrap().append_null(),
             DataType::Boolean => b.downcast_mut::<BooleanBuilder>().unwrap().append_null(),
+            DataType::Timestamp(TimeUnit::Nanosecond, _) => b
+                .downcast_mut::<TimestampNanosecondBuilder>()
+                .unwrap()
+                .append_null(),
             DataType::List(v) => match v.data_type() {
                 DataType::Utf8 => b
                     .downcast_mut::<ListBuilder<StringBuilder>>()
@@ -59,4 +174,30 @@ impl DomainField for Field {
             _ => unreachable!(),
         }
     }
+
+    fn to_json(&self, array: &dyn Array, index: usize) -> JsonValue {
+        if array.is_null(index) {
+            return JsonValue::Null;
+        }
+
+        match self.data_type() {
+            DataType::Utf8 => array.as_string::<i32>().value(index).into(),
+            DataType::Int64 => array.as_primitive::<Int64Type>().value(index).into(),
+            DataType::Float64 => array.as_primitive::<Float64Type>().value(index).into(),
+            DataType::Boolean => array.as_boolean().value(index).into(),
+            DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+                let v = array.as_primitive::<TimestampNanosecondType>().value(index);
+                OffsetDateTime::from_unix_timestamp_nanos(v.into())
+                    .map_or(JsonValue::Null, |v| Value::from(v).into())
+            }
+            DataType::List(v) => {
+                let list = array.as_list::<i32>().value(index);
+                (0..list.len())
+                    .map(|i| v.to_json(list.as_ref(), i))
+                    .collect::<Vec<_>>()
+                    .into()
+            }
+            _ => unreachable!(),
+        }
+    }
 }
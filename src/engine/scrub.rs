@@ -0,0 +1,454 @@
+use crate::engine::block;
+use crate::engine::catalog::BlockEntry;
+use crate::engine::catalog::Catalog;
+use crate::engine::chain::ChainReport;
+use crate::engine::partition::TimeRange;
+use crate::engine::replication;
+use crate::engine::replication::Copies;
+use crate::engine::replication::ReplicationConfig;
+use crate::picodata::service::ServiceWarnings;
+use serde::Serialize;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::select;
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tokio_util::sync::DropGuard;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("block error: {0}")]
+    Block(#[from] block::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("replication: {0}")]
+    Replication(#[from] replication::Error),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScrubConfig {
+    pub interval: Duration,
+    /// Bytes per second a stream's scrub may read.
+    pub throughput: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScrubReport {
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_run: Option<OffsetDateTime>,
+    /// Local blocks and bytes verified by the last run.
+    pub checked_blocks: usize,
+    pub checked_bytes: u64,
+    /// Damaged blocks found by the last run.
+    pub damaged: Vec<DamagedBlock>,
+    /// Blocks repaired since the stream was opened.
+    pub total_repaired: usize,
+    /// Last verification of the hash chain, which runs with every scrub.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain: Option<ChainReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DamagedBlock {
+    pub id: Uuid,
+    pub partition: PathBuf,
+    /// Instance that flushed a damaged copy, which it ships again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    pub reason: String,
+    pub repaired: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repair_error: Option<String>,
+}
+
+/// Re-reads the local blocks of a stream, copies of other replicas included,
+/// to find files damaged on disk before a query does.
+pub struct Scrubber {
+    stream: String,
+    catalog: Arc<Catalog>,
+    copies: Arc<Copies>,
+    config: ScrubConfig,
+    replication: Option<ReplicationConfig>,
+    report: Mutex<ScrubReport>,
+    sw: ServiceWarnings,
+}
+
+impl Scrubber {
+    pub fn new(
+        stream: String,
+        catalog: Arc<Catalog>,
+        copies: Arc<Copies>,
+        config: ScrubConfig,
+        replication: Option<ReplicationConfig>,
+        sw: ServiceWarnings,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            stream,
+            catalog,
+            copies,
+            config,
+            replication,
+            report: Default::default(),
+            sw,
+        })
+    }
+
+    /// Scrubs every interval until the returned guard is dropped.
+    pub fn spawn(self: &Arc<Self>, tt: &TaskTracker) -> DropGuard {
+        let ct = CancellationToken::new();
+        tt.spawn(self.clone().run(ct.clone()));
+        ct.drop_guard()
+    }
+
+    pub fn report(&self) -> ScrubReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    async fn run(self: Arc<Self>, ct: CancellationToken) {
+        let mut ticker = interval(self.config.interval);
+        ticker.tick().await;
+
+        loop {
+            select! {
+                _ = ct.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+
+            let result = select! {
+                _ = ct.cancelled() => return,
+                v = self.clone().scrub_all() => v,
+            };
+
+            let result = result.map(|_| {
+                let report = self.report.lock().unwrap();
+                let damaged = report.damaged.iter().filter(|v| !v.repaired).count();
+                (damaged > 0).then(|| format!("{damaged} damaged blocks"))
+            });
+
+            let e = match result {
+                Ok(v) => v,
+                Err(e) => Some(e.to_string()),
+            };
+            self.sw.set_job_error("scrub", &self.stream, e);
+
+            let result = select! {
+                _ = ct.cancelled() => return,
+                v = self.verify_chain() => v,
+            };
+
+            if let Err(e) = result {
+                self.sw
+                    .set_job_error("chain", &self.stream, Some(e.to_string()));
+            }
+        }
+    }
+
+    /// Walks the hash chain of the stream and raises a warning while it is
+    /// broken, none without a chain.
+    pub async fn verify_chain(&self) -> Result<Option<ChainReport>, block::Error> {
+        let catalog = self.catalog.clone();
+        let report = spawn_blocking(move || catalog.verify_chain())
+            .await
+            .unwrap()?;
+
+        if let Some(report) = &report {
+            self.sw
+                .set_job_error("chain", &self.stream, report.warning());
+            self.report.lock().unwrap().chain = Some(report.clone());
+        }
+
+        Ok(report)
+    }
+
+    async fn scrub_all(self: Arc<Self>) -> Result<(), Error> {
+        let mut report = ScrubReport {
+            last_run: Some(OffsetDateTime::now_utc()),
+            ..Default::default()
+        };
+
+        let mut catalogs = vec![self.catalog.clone()];
+        catalogs.extend(self.copies.catalogs());
+
+        for catalog in catalogs {
+            // Offloaded blocks are kept intact by the object store.
+            let blocks = catalog
+                .blocks(&TimeRange::default(), None)
+                .into_iter()
+                .filter(|v| v.remote.is_none());
+
+            for entry in blocks {
+                let verified = catalog.clone();
+                let Some((entry, damage)) = spawn_blocking(move || verify(&verified, entry.id))
+                    .await
+                    .unwrap()
+                else {
+                    continue;
+                };
+
+                report.checked_blocks += 1;
+                report.checked_bytes += entry.bytes;
+
+                match damage {
+                    Some(reason) => {
+                        let damaged = self.handle(&catalog, &entry, reason).await?;
+                        report.total_repaired += damaged.repaired as usize;
+                        report.damaged.push(damaged);
+                    }
+                    // Found intact again, e.g. restored by hand.
+                    None if entry.damaged.is_some() => {
+                        catalog.update(entry.id, |v| v.damaged = None)?;
+                    }
+                    None => {}
+                }
+
+                // Scrubbing yields the disk to ingest and queries.
+                let throughput = self.config.throughput.max(1) as f64;
+                sleep(Duration::from_secs_f64(entry.bytes as f64 / throughput)).await;
+            }
+        }
+
+        let mut current = self.report.lock().unwrap();
+        report.total_repaired += current.total_repaired;
+        report.chain = current.chain.take();
+        *current = report;
+        Ok(())
+    }
+
+    /// Marks a block damaged and repairs a block of this instance from a
+    /// copy. Damaged copies are dropped on the next sync and shipped again.
+    async fn handle(
+        self: &Arc<Self>,
+        catalog: &Catalog,
+        entry: &BlockEntry,
+        reason: String,
+    ) -> Result<DamagedBlock, Error> {
+        catalog.update(entry.id, |v| v.damaged = Some(reason.clone()))?;
+
+        let mut damaged = DamagedBlock {
+            id: entry.id,
+            partition: entry.partition.clone(),
+            origin: entry.origin.clone(),
+            reason,
+            repaired: false,
+            repair_error: None,
+        };
+
+        if entry.origin.is_none() {
+            match self.repair(entry).await {
+                Ok(v) => damaged.repaired = v,
+                Err(e) => damaged.repair_error = Some(e.to_string()),
+            }
+        }
+
+        Ok(damaged)
+    }
+
+    /// False without replication or a checksum to verify the copy against.
+    async fn repair(self: &Arc<Self>, entry: &BlockEntry) -> Result<bool, Error> {
+        let (Some(replication), Some(checksum)) = (&self.replication, &entry.checksum) else {
+            return Ok(false);
+        };
+
+        let data = replication.fetch(&self.stream, entry.id).await?;
+        if block::checksum(&data) != *checksum {
+            Err(replication::Error::Checksum(entry.id))?;
+        }
+
+        let this = self.clone();
+        let entry = entry.clone();
+        spawn_blocking(move || this.swap(&entry, &data))
+            .await
+            .unwrap()
+    }
+
+    /// Replaces the damaged file, unless the block was removed meanwhile.
+    fn swap(&self, entry: &BlockEntry, data: &[u8]) -> Result<bool, Error> {
+        let path = entry.path(self.catalog.dir());
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, data)?;
+        File::open(&tmp_path)?.sync_all()?;
+
+        let _guard = self.catalog.lock_write();
+        if self.catalog.get(entry.id).is_none() {
+            std::fs::remove_file(&tmp_path)?;
+            return Ok(false);
+        }
+
+        std::fs::rename(&tmp_path, &path)?;
+        self.catalog.update(entry.id, |v| v.damaged = None)?;
+        Ok(true)
+    }
+}
+
+/// Checks a block against its checksum and decodes it, none if the block
+/// was compacted or removed meanwhile.
+fn verify(catalog: &Catalog, id: Uuid) -> Option<(BlockEntry, Option<String>)> {
+    let _guard = catalog.lock_read();
+    let entry = catalog.get(id)?;
+    let path = entry.path(catalog.dir());
+
+    let damage = match (block::checksum_file(&path), &entry.checksum) {
+        (Err(e), _) => Some(e.to_string()),
+        (Ok(actual), Some(expected)) if actual != *expected => {
+            Some("checksum mismatch".to_string())
+        }
+        _ => block::read(&path, catalog.keys())
+            .err()
+            .map(|e| e.to_string()),
+    };
+
+    Some((entry, damage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing::write_block;
+    use crate::engine::block::testing::TempDir;
+    use crate::engine::replication::Message;
+    use std::path::Path;
+
+    fn scrubber(dir: &Path) -> Arc<Scrubber> {
+        let catalog = Catalog::open("s", dir, None, None).unwrap();
+        let copies = Arc::new(Copies::open("s", dir, None).unwrap());
+        let config = ScrubConfig {
+            interval: Duration::from_secs(3600),
+            throughput: u64::MAX,
+        };
+        Scrubber::new(
+            "s".to_string(),
+            catalog,
+            copies,
+            config,
+            None,
+            ServiceWarnings::default(),
+        )
+    }
+
+    #[test]
+    fn damage_is_told_apart() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir, None, None).unwrap();
+        let mut ids = Vec::new();
+        for checksum in [true, true, false] {
+            let entry = catalog.describe(&write_block(&dir, vec![0])).unwrap();
+            ids.push(entry.id);
+            catalog
+                .add(BlockEntry {
+                    checksum: checksum.then_some(entry.checksum.unwrap()),
+                    ..entry
+                })
+                .unwrap();
+        }
+
+        let intact = verify(&catalog, ids[0]).unwrap();
+        assert!(intact.1.is_none());
+
+        let path = catalog.get(ids[1]).unwrap().path(&dir);
+        let mut data = std::fs::read(&path).unwrap();
+        data[10] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+        let (_, damage) = verify(&catalog, ids[1]).unwrap();
+        assert_eq!(damage.as_deref(), Some("checksum mismatch"));
+
+        // Without a checksum the block has to decode.
+        let path = catalog.get(ids[2]).unwrap().path(&dir);
+        std::fs::write(&path, b"not a block").unwrap();
+        let (_, damage) = verify(&catalog, ids[2]).unwrap();
+        assert!(damage.is_some_and(|v| v != "checksum mismatch"));
+
+        catalog.remove(&[ids[0]]).unwrap();
+        assert!(verify(&catalog, ids[0]).is_none());
+    }
+
+    #[tokio::test]
+    async fn damaged_blocks_are_reported_until_intact() {
+        let dir = TempDir::new();
+        let scrubber = scrubber(&dir);
+        let catalog = scrubber.catalog.clone();
+
+        let path = write_block(&dir, vec![0]);
+        let entry = catalog.describe(&path).unwrap();
+        let id = entry.id;
+        catalog.add(entry).unwrap();
+
+        // Offloaded blocks are not read.
+        let offloaded = catalog.describe(&write_block(&dir, vec![0])).unwrap();
+        std::fs::remove_file(offloaded.path(&dir)).unwrap();
+        catalog
+            .add(BlockEntry {
+                remote: Some("key".to_string()),
+                ..offloaded
+            })
+            .unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        let mut bad = data.clone();
+        bad[10] ^= 0xff;
+        std::fs::write(&path, &bad).unwrap();
+
+        scrubber.clone().scrub_all().await.unwrap();
+        let report = scrubber.report();
+        assert_eq!(report.checked_blocks, 1);
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].id, id);
+        // Without replication there is nothing to repair from.
+        assert!(!report.damaged[0].repaired);
+        assert!(report.damaged[0].repair_error.is_none());
+        assert!(catalog.get(id).unwrap().damaged.is_some());
+
+        std::fs::write(&path, &data).unwrap();
+        scrubber.clone().scrub_all().await.unwrap();
+        let report = scrubber.report();
+        assert!(report.damaged.is_empty());
+        assert_eq!(report.total_repaired, 0);
+        assert!(catalog.get(id).unwrap().damaged.is_none());
+    }
+
+    #[tokio::test]
+    async fn damaged_copies_are_shipped_again() {
+        let dir = TempDir::new();
+        let origin = Catalog::open("s", &dir.join("origin"), None, None).unwrap();
+        let scrubber = scrubber(&dir.join("peer"));
+
+        let path = write_block(origin.dir(), vec![0]);
+        let entry = origin.describe(&path).unwrap();
+        let id = entry.id;
+        let data = std::fs::read(&path).unwrap();
+        let message = Message::Block {
+            origin: "i1".into(),
+            checksum: block::checksum(&data),
+            entry: Box::new(entry.clone()),
+            key: None,
+        };
+        scrubber.copies.handle(&message.encode(&data)).unwrap();
+
+        let copy = entry.path(scrubber.copies.catalogs()[0].dir());
+        std::fs::write(&copy, b"not a block").unwrap();
+        scrubber.clone().scrub_all().await.unwrap();
+
+        // Only the origin repairs a block, by shipping it again.
+        let report = scrubber.report();
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].origin.as_deref(), Some("i1"));
+        assert!(!report.damaged[0].repaired);
+
+        let message = Message::Sync {
+            stream: "s".into(),
+            origin: "i1".into(),
+            blocks: vec![id],
+        };
+        let reply = scrubber.copies.handle(&message.encode(&[])).unwrap();
+        let reply = serde_json::from_slice::<serde_json::Value>(&reply).unwrap();
+        assert_eq!(reply["missing"], serde_json::json!([id]));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::engine::accumulator::Accumulator;
+use crate::engine::accumulator::Error;
+use crate::engine::accumulator::FailedRows;
+use crate::engine::accumulator::Limits;
+use crate::engine::accumulator::Origin;
+use crate::engine::accumulator::Rows;
+use futures::future::join_all;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::value::RawValue;
+use serde_json::Value as JsonValue;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// How inserts of a stream are spread over accumulator shards, each
+/// converting rows on its own worker task and flushing its own blocks. The
+/// workers run on a multi-thread runtime, so shards convert in parallel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sharding {
+    #[serde(default = "Sharding::default_shards")]
+    pub shards: usize,
+    /// Rows with equal values of this field go to the same shard; without a
+    /// key whole batches are spread round-robin. The key is read from the
+    /// input rows, so the pipeline must leave it alone.
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+pub struct Shards {
+    accumulators: Vec<Accumulator>,
+    key: Option<String>,
+    next: AtomicUsize,
+}
+
+impl Default for Sharding {
+    fn default() -> Self {
+        Self {
+            shards: Self::default_shards(),
+            key: None,
+        }
+    }
+}
+
+impl Sharding {
+    pub const MAX_SHARDS: usize = 64;
+
+    fn default_shards() -> usize {
+        1
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=Self::MAX_SHARDS).contains(&self.shards) {
+            Err(format!("shards must be from 1 to {}", Self::MAX_SHARDS))?;
+        }
+
+        Ok(())
+    }
+
+    /// Limits of one shard, the stream's memory budget is split between them.
+    pub fn limits(&self, limits: Limits) -> Limits {
+        Limits {
+            queue_depth: limits.queue_depth,
+            memory_budget: limits.memory_budget / self.shards.max(1),
+        }
+    }
+}
+
+impl Shards {
+    pub fn new(sharding: &Sharding, accumulator: impl FnMut() -> Accumulator) -> Self {
+        Self {
+            accumulators: std::iter::repeat_with(accumulator)
+                .take(sharding.shards.max(1))
+                .collect(),
+            key: sharding.key.clone(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Rows split by key are added to their shards concurrently. Room is
+    /// taken in every shard first, so either all shards take their rows or
+    /// none does and the insert can be retried as a whole.
+    pub async fn add_rows(&self, rows: Rows, origin: &Origin) -> Result<FailedRows, Error> {
+        let (Some(key), true) = (&self.key, self.accumulators.len() > 1) else {
+            let i = self.next.fetch_add(1, Ordering::Relaxed) % self.accumulators.len();
+            return self.accumulators[i].add_rows(rows, origin).await;
+        };
+
+        let split = match rows {
+            Rows::Json(values) => self
+                .split(values, |v| self.shard(v.get(key)))
+                .into_iter()
+                .map(Rows::Json)
+                .collect::<Vec<_>>(),
+            Rows::Raw(values) => self
+                .split(values, |v| self.shard(raw_field(v, key).as_ref()))
+                .into_iter()
+                .map(Rows::Raw)
+                .collect(),
+        };
+
+        let mut reserved = Vec::new();
+        for (accumulator, rows) in self.accumulators.iter().zip(split) {
+            if !rows.is_empty() {
+                reserved.push((accumulator.reserve(rows.size())?, rows));
+            }
+        }
+
+        let mut failed = FailedRows::new();
+        let results = join_all(
+            reserved
+                .into_iter()
+                .map(|(reserved, rows)| reserved.add_rows(rows, origin)),
+        )
+        .await;
+        for result in results {
+            failed.extend(result?);
+        }
+
+        Ok(failed)
+    }
+
+    fn split<T>(&self, values: Vec<T>, shard: impl Fn(&T) -> usize) -> Vec<Vec<T>> {
+        let mut split = std::iter::repeat_with(Vec::new)
+            .take(self.accumulators.len())
+            .collect::<Vec<_>>();
+        for value in values {
+            split[shard(&value)].push(value);
+        }
+
+        split
+    }
+
+    fn shard(&self, key: Option<&JsonValue>) -> usize {
+        let mut hasher = DefaultHasher::new();
+        match key {
+            Some(JsonValue::String(v)) => v.hash(&mut hasher),
+            Some(v) => v.to_string().hash(&mut hasher),
+            None => {}
+        }
+
+        hasher.finish() as usize % self.accumulators.len()
+    }
+}
+
+/// A field of a raw row, read without decoding the rest of it.
+fn raw_field(row: &RawValue, name: &str) -> Option<JsonValue> {
+    let mut fields = serde_json::from_str::<HashMap<String, &RawValue>>(row.get()).ok()?;
+    serde_json::from_str(fields.remove(name)?.get()).ok()
+}
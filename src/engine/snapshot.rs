@@ -0,0 +1,553 @@
+use crate::engine::block;
+use crate::engine::catalog::BlockEntry;
+use crate::engine::catalog::Catalog;
+use crate::engine::crypto;
+use crate::engine::crypto::Keyring;
+use crate::engine::crypto::WrappedKey;
+use crate::engine::partition::Hour;
+use crate::engine::partition::TimeRange;
+use crate::engine::stream::StreamDefinition;
+use crate::engine::tiering;
+use crate::engine::tiering::Pinned;
+use lzzzz::lz4f::ReadDecompressor;
+use lzzzz::lz4f::WriteCompressor;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use thiserror::Error;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("block error: {0}")]
+    Block(#[from] block::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("lz4: {0}")]
+    Lz4(#[from] lzzzz::Error),
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("tiering: {0}")]
+    Tiering(#[from] tiering::Error),
+    #[error("encryption: {0}")]
+    Crypto(#[from] crypto::Error),
+    #[error("snapshot not found: {0}")]
+    NotFound(Uuid),
+    #[error("malformed archive: {0}")]
+    Malformed(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub id: Uuid,
+    pub stream: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    pub blocks: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Restore {
+    pub restored: usize,
+    /// Blocks the stream already had.
+    pub skipped: usize,
+}
+
+/// What a snapshot holds besides the block files.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub info: SnapshotInfo,
+    pub definition: StreamDefinition,
+    pub blocks: Vec<BlockEntry>,
+    /// Data keys of encrypted blocks, added when the archive is exported.
+    /// They unwrap only with the same master key.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<WrappedKey>,
+}
+
+/// Point-in-time copies of a stream: its catalog with hard links to the
+/// block files, which stay valid as the stream compacts and expires blocks.
+///
+/// An archive is an lz4 frame of named entries, each a little-endian `u32`
+/// name length, the name, a `u64` data length and the data. The manifest
+/// comes first, followed by the blocks in manifest order.
+pub struct Snapshots {
+    dir: PathBuf,
+    /// Held while snapshots are written, so an export does not archive
+    /// blocks being rewritten.
+    lock: Mutex<()>,
+}
+
+impl Snapshots {
+    pub const DIR_NAME: &'static str = "snapshots";
+    const MANIFEST: &'static str = "snapshot.json";
+    const BLOCKS: &'static str = "blocks";
+    const ARCHIVE: &'static str = "archive.lz4";
+
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.join(Self::DIR_NAME),
+            lock: Mutex::default(),
+        }
+    }
+
+    /// Offloaded blocks are copied in through `locate`, which gives their
+    /// local file, so the snapshot does not depend on objects that retention
+    /// or erasure delete later. Fails if an offloaded block is removed before
+    /// it is copied.
+    pub fn create(
+        &self,
+        definition: &StreamDefinition,
+        catalog: &Catalog,
+        locate: impl FnMut(&BlockEntry) -> Result<Pinned, Error>,
+    ) -> Result<SnapshotInfo, Error> {
+        let _guard = self.lock.lock().unwrap();
+        let id = Uuid::now_v7();
+        let tmp_dir = self.dir.join(format!("{id}.tmp"));
+        let result = self.write(id, &tmp_dir, definition, catalog, locate);
+        if result.is_err() {
+            let _ = std::fs::remove_dir_all(&tmp_dir);
+        }
+
+        result
+    }
+
+    fn write(
+        &self,
+        id: Uuid,
+        tmp_dir: &Path,
+        definition: &StreamDefinition,
+        catalog: &Catalog,
+        mut locate: impl FnMut(&BlockEntry) -> Result<Pinned, Error>,
+    ) -> Result<SnapshotInfo, Error> {
+        let blocks_dir = tmp_dir.join(Self::BLOCKS);
+        std::fs::create_dir_all(&blocks_dir)?;
+
+        let mut blocks = {
+            let _guard = catalog.lock_read();
+            let blocks = catalog.blocks(&TimeRange::default(), None);
+            for entry in blocks.iter().filter(|v| v.remote.is_none()) {
+                let path = entry.path(&blocks_dir);
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                std::fs::hard_link(entry.path(catalog.dir()), path)?;
+            }
+
+            blocks
+        };
+
+        // Downloads are not made under the lock, cached files are replaced
+        // and never changed in place, so a link to one keeps its content.
+        for entry in blocks.iter_mut().filter(|v| v.remote.is_some()) {
+            let cached = locate(entry)?;
+            let path = entry.path(&blocks_dir);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::hard_link(&*cached, path)?;
+            entry.remote = None;
+        }
+
+        let manifest = Manifest {
+            info: SnapshotInfo {
+                id,
+                stream: catalog.stream().to_string(),
+                created_at: OffsetDateTime::now_utc(),
+                blocks: blocks.len(),
+                bytes: blocks.iter().map(|v| v.bytes).sum(),
+            },
+            definition: definition.clone(),
+            blocks,
+            keys: Vec::new(),
+        };
+
+        std::fs::write(
+            tmp_dir.join(Self::MANIFEST),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
+        std::fs::rename(tmp_dir, self.dir.join(id.to_string()))?;
+
+        Ok(manifest.info)
+    }
+
+    pub fn list(&self) -> Result<Vec<SnapshotInfo>, Error> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => Err(e)?,
+        };
+
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            // Snapshots interrupted halfway have no id as a name.
+            let Some(id) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|v| Uuid::try_parse(v).ok())
+            else {
+                continue;
+            };
+
+            snapshots.push(self.manifest(id)?.info);
+        }
+
+        snapshots.sort_by_key(|v| v.id);
+        Ok(snapshots)
+    }
+
+    pub fn delete(&self, id: Uuid) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        match std::fs::remove_dir_all(self.dir.join(id.to_string())) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::NotFound(id)),
+            v => Ok(v?),
+        }
+    }
+
+    /// Writes the archive of a snapshot once and returns its path; `locate`
+    /// gives the local file of an offloaded block, which only snapshots of
+    /// earlier versions record. Exporting fails once the keys of its
+    /// encrypted blocks are shredded.
+    pub fn export(
+        &self,
+        id: Uuid,
+        keys: Option<&Keyring>,
+        mut locate: impl FnMut(&BlockEntry) -> Result<Pinned, Error>,
+    ) -> Result<PathBuf, Error> {
+        let _guard = self.lock.lock().unwrap();
+        let dir = self.dir.join(id.to_string());
+        let path = dir.join(Self::ARCHIVE);
+        if path.exists() {
+            return Ok(path);
+        }
+
+        let mut manifest = self.manifest(id)?;
+        let mut paths = Vec::new();
+        for entry in &manifest.blocks {
+            let path = match entry.remote {
+                Some(_) => locate(entry)?,
+                None => entry.path(&dir.join(Self::BLOCKS)).into(),
+            };
+
+            if let Some(key) = crypto::read_key_id(&path)? {
+                let keys = keys.ok_or(crypto::Error::NotConfigured)?;
+                if manifest.keys.iter().all(|v| v.id != key) {
+                    manifest.keys.push(keys.export(key)?);
+                }
+            }
+
+            paths.push(path);
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+
+        {
+            // The frame is finished when the compressor is dropped.
+            let mut writer = WriteCompressor::new(&mut file, Default::default())?;
+            let data = serde_json::to_vec(&manifest)?;
+            write_entry(
+                &mut writer,
+                Self::MANIFEST,
+                data.len() as u64,
+                data.as_slice(),
+            )?;
+
+            for (entry, path) in manifest.blocks.iter().zip(paths) {
+                let block = File::open(&*path)?;
+                let len = block.metadata()?.len();
+                write_entry(&mut writer, &block_name(entry), len, block)?;
+            }
+
+            writer.flush()?;
+        }
+
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &path)?;
+        Ok(path)
+    }
+
+    /// Passes the block files of every snapshot to `rewrite`, which replaces
+    /// a file in place, or removes it once no row is left, and returns
+    /// whether it did. Offloaded blocks recorded by earlier versions are
+    /// skipped. Exported archives of the changed snapshots are removed.
+    /// Returns the number of changed snapshots.
+    pub fn rewrite<E: From<Error>>(
+        &self,
+        catalog: &Catalog,
+        mut rewrite: impl FnMut(&BlockEntry, &Path) -> Result<bool, E>,
+    ) -> Result<usize, E> {
+        let _guard = self.lock.lock().unwrap();
+        let mut changed = 0;
+
+        for info in self.list()? {
+            let dir = self.dir.join(info.id.to_string());
+            let mut manifest = self.manifest(info.id)?;
+            let mut blocks = Vec::new();
+            let mut rewritten = false;
+
+            for entry in std::mem::take(&mut manifest.blocks) {
+                let path = entry.path(&dir.join(Self::BLOCKS));
+                if entry.remote.is_some() || !rewrite(&entry, &path)? {
+                    blocks.push(entry);
+                    continue;
+                }
+
+                rewritten = true;
+                if path.exists() {
+                    let described = catalog.describe(&path).map_err(Error::from)?;
+                    blocks.push(BlockEntry {
+                        id: entry.id,
+                        partition: entry.partition,
+                        ..described
+                    });
+                }
+            }
+
+            if !rewritten {
+                continue;
+            }
+
+            manifest.info.blocks = blocks.len();
+            manifest.info.bytes = blocks.iter().map(|v| v.bytes).sum();
+            manifest.blocks = blocks;
+
+            let path = dir.join(Self::MANIFEST);
+            let tmp_path = path.with_extension("tmp");
+            std::fs::write(
+                &tmp_path,
+                serde_json::to_vec_pretty(&manifest).map_err(Error::from)?,
+            )
+            .map_err(Error::from)?;
+            std::fs::rename(&tmp_path, &path).map_err(Error::from)?;
+            block::remove_if_exists(&dir.join(Self::ARCHIVE)).map_err(Error::from)?;
+
+            changed += 1;
+        }
+
+        Ok(changed)
+    }
+
+    /// Reads the manifest at the start of an archive.
+    pub fn read_manifest(archive: &Path) -> Result<Manifest, Error> {
+        let mut reader = ReadDecompressor::new(BufReader::new(File::open(archive)?))?;
+        let data = read_entry(&mut reader, Self::MANIFEST)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Adds the blocks of an archive to a catalog, skipping blocks it already
+    /// has, so an interrupted restore can be repeated.
+    pub fn restore(archive: &Path, catalog: &Catalog) -> Result<Restore, Error> {
+        let mut reader = ReadDecompressor::new(BufReader::new(File::open(archive)?))?;
+        let manifest =
+            serde_json::from_slice::<Manifest>(&read_entry(&mut reader, Self::MANIFEST)?)?;
+        if !manifest.keys.is_empty() {
+            catalog
+                .keys()
+                .ok_or(crypto::Error::NotConfigured)?
+                .import(manifest.keys)?;
+        }
+
+        let present = catalog
+            .blocks(&TimeRange::default(), None)
+            .into_iter()
+            .map(|v| v.id)
+            .collect::<HashSet<_>>();
+
+        let mut restore = Restore {
+            restored: 0,
+            skipped: 0,
+        };
+
+        for entry in manifest.blocks {
+            let relative = entry
+                .partition
+                .components()
+                .all(|v| matches!(v, Component::Normal(_)));
+            if !relative || Hour::of_dir(&entry.partition).is_none() {
+                Err(Error::Malformed(format!(
+                    "partition {}",
+                    entry.partition.display()
+                )))?;
+            }
+
+            let data = read_entry(&mut reader, &block_name(&entry))?;
+            if present.contains(&entry.id) {
+                restore.skipped += 1;
+                continue;
+            }
+
+            if entry
+                .checksum
+                .as_ref()
+                .is_some_and(|v| *v != block::checksum(&data))
+            {
+                Err(Error::Malformed(format!(
+                    "checksum mismatch of block {}",
+                    entry.id
+                )))?;
+            }
+
+            let path = entry.path(catalog.dir());
+            let tmp_path = path.with_extension("tmp");
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(&tmp_path, data)?;
+            File::open(&tmp_path)?.sync_all()?;
+
+            let _guard = catalog.lock_write();
+            std::fs::rename(&tmp_path, &path)?;
+            catalog.add(BlockEntry {
+                stream: catalog.stream().to_string(),
+                remote: None,
+                origin: None,
+                replicas: Vec::new(),
+                damaged: None,
+                ..entry
+            })?;
+            restore.restored += 1;
+        }
+
+        Ok(restore)
+    }
+
+    fn manifest(&self, id: Uuid) -> Result<Manifest, Error> {
+        let path = self.dir.join(id.to_string()).join(Self::MANIFEST);
+        match std::fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::NotFound(id)),
+            Err(e) => Err(e)?,
+        }
+    }
+}
+
+fn block_name(entry: &BlockEntry) -> String {
+    format!(
+        "{}/{}/{}.{}",
+        Snapshots::BLOCKS,
+        entry.partition.display(),
+        entry.id,
+        block::EXTENSION
+    )
+}
+
+fn write_entry(
+    writer: &mut impl Write,
+    name: &str,
+    len: u64,
+    data: impl Read,
+) -> Result<(), Error> {
+    writer.write_all(&(name.len() as u32).to_le_bytes())?;
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+
+    if std::io::copy(&mut data.take(len), writer)? != len {
+        Err(Error::Malformed(format!("{name} changed while archived")))?;
+    }
+
+    Ok(())
+}
+
+fn read_entry(reader: &mut impl Read, expected: &str) -> Result<Vec<u8>, Error> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut name = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut name)?;
+
+    if name != expected.as_bytes() {
+        Err(Error::Malformed(format!(
+            "expected {expected}, found {}",
+            String::from_utf8_lossy(&name)
+        )))?;
+    }
+
+    let mut len = [0; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    let mut data = Vec::new();
+    reader.take(len).read_to_end(&mut data)?;
+
+    if data.len() as u64 != len {
+        Err(Error::Malformed(format!("{expected} is truncated")))?;
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing::write_block;
+    use crate::engine::block::testing::TempDir;
+
+    #[test]
+    fn offloaded_blocks_are_copied_into_snapshots() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir.join("s"), None, None).unwrap();
+        let definition =
+            serde_json::from_value::<StreamDefinition>(serde_json::json!({"fields": []})).unwrap();
+
+        // The block is offloaded, its only local file is the cached one.
+        let path = write_block(catalog.dir(), vec![0]);
+        let entry = catalog.describe(&path).unwrap();
+        let cached = dir.join("cached");
+        std::fs::rename(&path, &cached).unwrap();
+        catalog
+            .add(BlockEntry {
+                remote: Some("key".to_string()),
+                ..entry
+            })
+            .unwrap();
+
+        let snapshots = Snapshots::new(catalog.dir());
+        let info = snapshots
+            .create(&definition, &catalog, |_| Ok(cached.clone().into()))
+            .unwrap();
+        assert_eq!(info.blocks, 1);
+
+        // Retention deletes the object and its cached copy.
+        std::fs::remove_file(&cached).unwrap();
+        let archive = snapshots
+            .export(info.id, None, |_| panic!("the block is in the snapshot"))
+            .unwrap();
+
+        let restored = Catalog::open("r", &dir.join("r"), None, None).unwrap();
+        let restore = Snapshots::restore(&archive, &restored).unwrap();
+        assert_eq!(restore.restored, 1);
+    }
+
+    #[test]
+    fn failed_snapshots_leave_nothing_behind() {
+        let dir = TempDir::new();
+        let catalog = Catalog::open("s", &dir.join("s"), None, None).unwrap();
+        let definition =
+            serde_json::from_value::<StreamDefinition>(serde_json::json!({"fields": []})).unwrap();
+
+        let entry = catalog
+            .describe(&write_block(catalog.dir(), vec![0]))
+            .unwrap();
+        catalog
+            .add(BlockEntry {
+                remote: Some("key".to_string()),
+                ..entry
+            })
+            .unwrap();
+
+        let snapshots = Snapshots::new(catalog.dir());
+        let result = snapshots.create(&definition, &catalog, |_| {
+            Err(tiering::Error::NoObjectStore)?
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            std::fs::read_dir(catalog.dir().join(Snapshots::DIR_NAME))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+}
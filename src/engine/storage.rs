@@ -0,0 +1,230 @@
+use crate::engine::block;
+use arrow::datatypes::Schema;
+use parquet::basic::Compression;
+use parquet::basic::GzipLevel;
+use parquet::basic::ZstdLevel;
+use parquet::file::properties::EnabledStatistics;
+use parquet::file::properties::WriterProperties;
+use parquet::schema::types::ColumnPath;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// How blocks of a stream are written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Storage {
+    #[serde(default)]
+    pub codec: Codec,
+    /// Rows per row group of compacted blocks.
+    #[serde(default = "Storage::default_max_row_group_size")]
+    pub max_row_group_size: usize,
+    #[serde(default = "Storage::default_true")]
+    pub dictionary: bool,
+    /// Per-column overrides of `dictionary`.
+    #[serde(default)]
+    pub dictionary_columns: BTreeMap<String, bool>,
+    /// Page-level statistics with column and offset indexes.
+    #[serde(default = "Storage::default_true")]
+    pub page_index: bool,
+    /// Columns to write bloom filters for, e.g. trace ids.
+    #[serde(default)]
+    pub bloom_filters: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Codec {
+    None,
+    Snappy,
+    #[default]
+    Lz4,
+    Gzip {
+        #[serde(default = "Codec::default_gzip_level")]
+        level: u32,
+    },
+    Zstd {
+        #[serde(default = "Codec::default_zstd_level")]
+        level: i32,
+    },
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            codec: Codec::default(),
+            max_row_group_size: Self::default_max_row_group_size(),
+            dictionary: true,
+            dictionary_columns: BTreeMap::new(),
+            page_index: true,
+            bloom_filters: Vec::new(),
+        }
+    }
+}
+
+impl Storage {
+    fn default_max_row_group_size() -> usize {
+        1024 * 1024
+    }
+
+    fn default_true() -> bool {
+        true
+    }
+
+    /// Checks the settings against the schema of the stream, bloom filters
+    /// and dictionary overrides must name its columns.
+    pub fn validate(&self, schema: &Schema) -> Result<(), String> {
+        if self.max_row_group_size == 0 {
+            Err("max_row_group_size must be positive")?;
+        }
+
+        let columns = self
+            .bloom_filters
+            .iter()
+            .chain(self.dictionary_columns.keys());
+
+        for name in columns {
+            if schema.field_with_name(name).is_err() {
+                Err(format!("unknown storage column: {name}"))?;
+            }
+        }
+
+        self.codec.compression().map(|_| ())
+    }
+
+    /// Writer properties for blocks of up to `rows` rows; bloom filters are
+    /// sized for that many distinct values per row group.
+    pub fn properties(&self, rows: usize) -> WriterProperties {
+        let ndv = rows.min(self.max_row_group_size) as u64;
+        let statistics = match self.page_index {
+            true => EnabledStatistics::Page,
+            false => EnabledStatistics::Chunk,
+        };
+
+        let mut props = block::properties()
+            .set_compression(self.codec.compression().unwrap_or(Compression::LZ4_RAW))
+            .set_max_row_group_size(self.max_row_group_size)
+            .set_dictionary_enabled(self.dictionary)
+            .set_statistics_enabled(statistics);
+
+        for (column, enabled) in &self.dictionary_columns {
+            props =
+                props.set_column_dictionary_enabled(ColumnPath::from(column.as_str()), *enabled);
+        }
+
+        for column in &self.bloom_filters {
+            props = props
+                .set_column_bloom_filter_enabled(ColumnPath::from(column.as_str()), true)
+                .set_column_bloom_filter_ndv(ColumnPath::from(column.as_str()), ndv);
+        }
+
+        props.build()
+    }
+}
+
+impl Codec {
+    fn default_gzip_level() -> u32 {
+        6
+    }
+
+    fn default_zstd_level() -> i32 {
+        3
+    }
+
+    fn compression(&self) -> Result<Compression, String> {
+        Ok(match self {
+            Self::None => Compression::UNCOMPRESSED,
+            Self::Snappy => Compression::SNAPPY,
+            Self::Lz4 => Compression::LZ4_RAW,
+            Self::Gzip { level } => {
+                Compression::GZIP(GzipLevel::try_new(*level).map_err(|e| e.to_string())?)
+            }
+            Self::Zstd { level } => {
+                Compression::ZSTD(ZstdLevel::try_new(*level).map_err(|e| e.to_string())?)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::schema::build_schema;
+    use crate::engine::schema::FieldDefinition;
+    use crate::engine::schema::FieldType;
+    use crate::engine::schema::RECEIVED_AT;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn storage(value: serde_json::Value) -> Storage {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn schema() -> Arc<Schema> {
+        let field = |name: &str| FieldDefinition {
+            name: name.into(),
+            kind: FieldType::String,
+            nullable: true,
+        };
+
+        build_schema(&[field("level"), field("trace_id")], RECEIVED_AT, 1)
+    }
+
+    #[test]
+    fn defaults() {
+        let storage = storage(json!({}));
+        let props = storage.properties(1000);
+        let column = ColumnPath::from("a");
+
+        assert!(storage.validate(&schema()).is_ok());
+        assert_eq!(props.compression(&column), Compression::LZ4_RAW);
+        assert_eq!(props.max_row_group_size(), 1024 * 1024);
+        assert!(props.dictionary_enabled(&column));
+        assert_eq!(props.statistics_enabled(&column), EnabledStatistics::Page);
+        assert!(props.bloom_filter_properties(&column).is_none());
+    }
+
+    #[test]
+    fn settings_reach_the_writer() {
+        let storage = storage(json!({
+            "codec": {"type": "zstd", "level": 9},
+            "max_row_group_size": 500,
+            "dictionary": false,
+            "dictionary_columns": {"level": true},
+            "page_index": false,
+            "bloom_filters": ["trace_id"]
+        }));
+        let props = storage.properties(100_000);
+        let level = ColumnPath::from("level");
+        let trace_id = ColumnPath::from("trace_id");
+
+        assert!(storage.validate(&schema()).is_ok());
+        assert_eq!(
+            props.compression(&level),
+            Compression::ZSTD(ZstdLevel::try_new(9).unwrap())
+        );
+        assert_eq!(props.max_row_group_size(), 500);
+        assert!(props.dictionary_enabled(&level));
+        assert!(!props.dictionary_enabled(&trace_id));
+        assert_eq!(props.statistics_enabled(&level), EnabledStatistics::Chunk);
+        assert_eq!(props.bloom_filter_properties(&trace_id).unwrap().ndv, 500);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        assert!(storage(json!({"max_row_group_size": 0}))
+            .validate(&schema())
+            .is_err());
+        assert!(storage(json!({"codec": {"type": "gzip", "level": 12}}))
+            .validate(&schema())
+            .is_err());
+        assert!(storage(json!({"codec": {"type": "zstd", "level": 99}}))
+            .validate(&schema())
+            .is_err());
+        assert!(storage(json!({"bloom_filters": ["span_id"]}))
+            .validate(&schema())
+            .is_err());
+        assert!(storage(json!({"dictionary_columns": {"span_id": false}}))
+            .validate(&schema())
+            .is_err());
+    }
+}
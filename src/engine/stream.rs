@@ -0,0 +1,780 @@
+use crate::engine::accumulator;
+use crate::engine::accumulator::Accumulator;
+use crate::engine::accumulator::FailedRow;
+use crate::engine::accumulator::FailedRows;
+use crate::engine::accumulator::Origin;
+use crate::engine::accumulator::Rows;
+use crate::engine::accumulator::Writer;
+use crate::engine::block;
+use crate::engine::catalog::BlockEntry;
+use crate::engine::catalog::Catalog;
+use crate::engine::chain::ChainReport;
+use crate::engine::compaction::Compactor;
+use crate::engine::compaction::Rewriter;
+use crate::engine::crypto::KeyInfo;
+use crate::engine::crypto::Keyring;
+use crate::engine::dead_letter::DeadLetter;
+use crate::engine::dedup::Dedup;
+use crate::engine::dedup::Idempotency;
+use crate::engine::dedup::Inserted;
+use crate::engine::erasure::Eraser;
+use crate::engine::erasure::Erasure;
+use crate::engine::erasure::ErasureRequest;
+use crate::engine::erasure::Erasures;
+use crate::engine::partition;
+use crate::engine::partition::TimeRange;
+use crate::engine::pipeline::Pipeline;
+use crate::engine::query::Query;
+use crate::engine::replication::Copies;
+use crate::engine::replication::Replicator;
+use crate::engine::retention;
+use crate::engine::retention::Enforcer;
+use crate::engine::retention::Retention;
+use crate::engine::retention::RetentionReport;
+use crate::engine::schema::build_schema;
+use crate::engine::schema::is_reserved;
+use crate::engine::schema::FieldDefinition;
+use crate::engine::schema::FieldType;
+use crate::engine::schema::RECEIVED_AT;
+use crate::engine::scrub::ScrubReport;
+use crate::engine::scrub::Scrubber;
+use crate::engine::shard::Sharding;
+use crate::engine::shard::Shards;
+use crate::engine::snapshot;
+use crate::engine::snapshot::Restore;
+use crate::engine::snapshot::SnapshotInfo;
+use crate::engine::snapshot::Snapshots;
+use crate::engine::storage::Storage;
+use crate::engine::tiering;
+use crate::engine::tiering::Pinned;
+use crate::engine::tiering::Remote;
+use crate::engine::tiering::Tierer;
+use crate::engine::tiering::Tiering;
+use crate::engine::Config;
+use crate::engine::Error;
+use crate::picodata::service::ServiceWarnings;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tokio_util::sync::DropGuard;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamDefinition {
+    #[serde(default)]
+    pub version: u64,
+    pub fields: Vec<FieldDefinition>,
+    #[serde(default)]
+    pub pipeline: Pipeline,
+    #[serde(default)]
+    pub idempotency: Idempotency,
+    /// Timestamp field blocks are partitioned by, receive time if not set.
+    #[serde(default)]
+    pub time_field: Option<String>,
+    #[serde(default)]
+    pub retention: Retention,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub sharding: Sharding,
+    #[serde(default)]
+    pub tiering: Tiering,
+}
+
+pub struct Stream {
+    name: String,
+    definition: StreamDefinition,
+    shards: Shards,
+    dead_letter: DeadLetter,
+    shared: Arc<Shared>,
+    retention: Arc<Enforcer>,
+    scrubber: Arc<Scrubber>,
+    remote: Option<Arc<Remote>>,
+    sw: ServiceWarnings,
+    _compactor: DropGuard,
+    _retention: DropGuard,
+    _dead_letter_retention: DropGuard,
+    _scrubber: DropGuard,
+    _tierer: Option<DropGuard>,
+    _replicator: Option<DropGuard>,
+}
+
+/// Stream state that outlives redefinitions of the stream.
+pub struct Shared {
+    dedup: Dedup,
+    catalog: Arc<Catalog>,
+    dead_letter: Arc<Catalog>,
+    copies: Arc<Copies>,
+    snapshots: Arc<Snapshots>,
+    erasures: Arc<Erasures>,
+    _erasures: DropGuard,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Replay {
+    pub replayed: usize,
+    pub rejected: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Shred {
+    pub destroyed_keys: Vec<Uuid>,
+    pub removed_blocks: usize,
+}
+
+impl StreamDefinition {
+    const FILE_NAME: &'static str = "stream.json";
+
+    pub fn load(dir: &Path) -> Result<Option<Self>, Error> {
+        match std::fs::read(dir.join(Self::FILE_NAME)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), Error> {
+        let tmp_path = dir.join(format!("{}.tmp", Self::FILE_NAME));
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(tmp_path, dir.join(Self::FILE_NAME))?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.fields.is_empty() {
+            Err(Error::InvalidDefinition("no fields".into()))?;
+        }
+
+        let mut names = HashSet::new();
+        for f in &self.fields {
+            if is_reserved(&f.name) {
+                Err(Error::InvalidDefinition(format!(
+                    "reserved field name: {}",
+                    f.name
+                )))?;
+            }
+
+            if !names.insert(f.name.as_str()) {
+                Err(Error::InvalidDefinition(format!(
+                    "duplicate field: {}",
+                    f.name
+                )))?;
+            }
+        }
+
+        if let Some(name) = &self.time_field {
+            let valid = self
+                .fields
+                .iter()
+                .any(|f| &f.name == name && f.kind == FieldType::Timestamp && !f.nullable);
+
+            if !valid {
+                Err(Error::InvalidDefinition(format!(
+                    "time field must be a non-nullable timestamp: {name}"
+                )))?;
+            }
+        }
+
+        let schema = build_schema(&self.fields, self.time_field(), self.version);
+        self.storage
+            .validate(&schema)
+            .map_err(Error::InvalidDefinition)?;
+        self.sharding.validate().map_err(Error::InvalidDefinition)?;
+
+        if let Some(key) = &self.sharding.key {
+            if !self.fields.iter().any(|f| &f.name == key) {
+                Err(Error::InvalidDefinition(format!(
+                    "unknown shard key: {key}"
+                )))?;
+            }
+
+            // Rows are sharded before the pipeline runs on the shard's task.
+            if self.pipeline.writes(key) {
+                Err(Error::InvalidDefinition(format!(
+                    "shard key is written by the pipeline: {key}"
+                )))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn time_field(&self) -> &str {
+        self.time_field.as_deref().unwrap_or(RECEIVED_AT)
+    }
+}
+
+impl Shared {
+    pub fn open(name: &str, dir: &Path, config: &Config) -> Result<Arc<Self>, Error> {
+        let master = config.encryption.as_ref();
+        let erasures = Erasures::open(dir)?;
+        Ok(Arc::new(Self {
+            dedup: Dedup::default(),
+            catalog: Catalog::open(name, dir, master, config.server_key.as_ref())?,
+            dead_letter: Catalog::open(name, &dir.join(DeadLetter::DIR_NAME), master, None)?,
+            copies: Arc::new(Copies::open(name, dir, master.cloned())?),
+            snapshots: Arc::new(Snapshots::new(dir)),
+            _erasures: erasures.drop_guard(),
+            erasures,
+        }))
+    }
+}
+
+impl Shared {
+    fn keyring<'a>(&self, catalog: &'a Catalog) -> Result<&'a Keyring, Error> {
+        catalog.keys().ok_or(Error::EncryptionNotConfigured)
+    }
+
+    /// Returns the object store keys of the removed offloaded blocks.
+    fn shred(&self) -> Result<(Shred, Vec<String>), Error> {
+        let mut shred = Shred {
+            destroyed_keys: Vec::new(),
+            removed_blocks: 0,
+        };
+        let mut offloaded = Vec::new();
+
+        for catalog in [&self.catalog, &self.dead_letter] {
+            let blocks = {
+                // Flushes hold the read lock from sealing a block to adding
+                // it, so none sealed with a destroyed key is added after the
+                // removal. Rewrites in flight find their inputs removed.
+                let _guard = catalog.lock_write();
+                shred.destroyed_keys.extend(self.keyring(catalog)?.shred()?);
+
+                let blocks = catalog.blocks(&TimeRange::default(), None);
+                catalog.remove(&blocks.iter().map(|v| v.id).collect::<Vec<_>>())?;
+                for entry in &blocks {
+                    block::remove_if_exists(&entry.path(catalog.dir()))?;
+                }
+                blocks
+            };
+
+            for partition in partition::list(catalog.dir(), &TimeRange::default())? {
+                if std::fs::read_dir(&partition)?.next().is_none() {
+                    partition::remove(&partition)?;
+                }
+            }
+
+            shred.removed_blocks += blocks.len();
+            offloaded.extend(blocks.into_iter().filter_map(|v| v.remote));
+        }
+
+        Ok((shred, offloaded))
+    }
+}
+
+impl Stream {
+    pub fn new(
+        name: String,
+        definition: StreamDefinition,
+        config: &Config,
+        shared: Arc<Shared>,
+        tt: &TaskTracker,
+        sw: &ServiceWarnings,
+    ) -> Self {
+        let compactor = Compactor::spawn(
+            name.clone(),
+            shared.catalog.clone(),
+            config.compaction,
+            definition
+                .storage
+                .properties(definition.storage.max_row_group_size),
+            sw.clone(),
+            tt,
+        );
+
+        let retention = Enforcer::new(
+            name.clone(),
+            shared.catalog.clone(),
+            definition.retention.clone(),
+            config.remote.clone(),
+            sw.clone(),
+        );
+        let retention_guard = retention.spawn(config.retention_interval, tt);
+        let dead_letter_retention = Enforcer::new(
+            format!("{name}/{}", DeadLetter::DIR_NAME),
+            shared.dead_letter.clone(),
+            definition.retention.dead_letter(),
+            None,
+            sw.clone(),
+        )
+        .spawn(config.retention_interval, tt);
+        let scrubber = Scrubber::new(
+            name.clone(),
+            shared.catalog.clone(),
+            shared.copies.clone(),
+            config.scrub,
+            config.replication.clone(),
+            sw.clone(),
+        );
+        let scrubber_guard = scrubber.spawn(tt);
+        let schema = build_schema(
+            &definition.fields,
+            definition.time_field(),
+            definition.version,
+        );
+        let tierer = config
+            .remote
+            .clone()
+            .zip(definition.tiering.after)
+            .map(|(remote, after)| {
+                Tierer::spawn(
+                    name.clone(),
+                    shared.catalog.clone(),
+                    Duration::from_secs(after),
+                    remote,
+                    sw.clone(),
+                    tt,
+                )
+            });
+        let replicator = config
+            .replication
+            .clone()
+            .map(|v| Replicator::spawn(name.clone(), shared.catalog.clone(), v, sw.clone(), tt));
+        let shards = Shards::new(&definition.sharding, || {
+            Accumulator::new(
+                schema.clone(),
+                definition.pipeline.clone(),
+                definition.sharding.limits(config.limits),
+                config.instance_id.clone(),
+                config.memory.clone(),
+                Writer::new(
+                    definition.storage.properties(Accumulator::MAX_ROWS),
+                    shared.catalog.clone(),
+                    "flush",
+                    sw.clone(),
+                ),
+                tt,
+            )
+        });
+
+        Self {
+            dead_letter: DeadLetter::new(shared.dead_letter.clone(), config, tt, sw),
+            name,
+            definition,
+            shards,
+            shared,
+            retention,
+            scrubber,
+            remote: config.remote.clone(),
+            sw: sw.clone(),
+            _compactor: compactor,
+            _retention: retention_guard,
+            _dead_letter_retention: dead_letter_retention,
+            _scrubber: scrubber_guard,
+            _tierer: tierer,
+            _replicator: replicator,
+        }
+    }
+
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    }
+
+    pub fn definition(&self) -> &StreamDefinition {
+        &self.definition
+    }
+
+    pub fn shared(&self) -> Arc<Shared> {
+        self.shared.clone()
+    }
+
+    pub fn retention(&self) -> RetentionReport {
+        self.retention.report()
+    }
+
+    pub fn scrub(&self) -> ScrubReport {
+        self.scrubber.report()
+    }
+
+    pub async fn verify_chain(&self) -> Result<ChainReport, Error> {
+        self.scrubber
+            .verify_chain()
+            .await?
+            .ok_or(Error::ChainNotConfigured)
+    }
+
+    pub fn removable_blocks(&self) -> Vec<BlockEntry> {
+        self.retention.removable()
+    }
+
+    pub fn remove_block(&self, block: &BlockEntry) -> Result<(), retention::Error> {
+        self.retention.remove(block)
+    }
+
+    /// Inserts a batch at most once per idempotency key within the stream's
+    /// dedup window; a retried batch gets the result of the first attempt.
+    pub async fn insert(
+        &self,
+        rows: Rows,
+        origin: &Origin,
+        key: Option<&str>,
+    ) -> Result<Inserted, Error> {
+        match key {
+            Some(key) => self
+                .shared
+                .dedup
+                .batch(key, self.definition.idempotency.window())
+                .get_or_try_init(|| self.insert_unique(rows, origin))
+                .await
+                .cloned(),
+            None => self.insert_unique(rows, origin).await,
+        }
+    }
+
+    /// Drops rows whose id was already seen within the dedup window. Ids of
+    /// rejected rows are released so that corrected rows can be sent again.
+    async fn insert_unique(&self, rows: Rows, origin: &Origin) -> Result<Inserted, Error> {
+        let idempotency = &self.definition.idempotency;
+        if idempotency.id_field.is_none() {
+            return Ok(Inserted {
+                failed: self.append(rows, origin).await?,
+                duplicates: 0,
+            });
+        }
+
+        let values = rows.into_json();
+        let total = values.len();
+        let ids = values
+            .iter()
+            .map(|v| idempotency.row_id(v))
+            .collect::<Vec<_>>();
+
+        let fresh = self.shared.dedup.claim_rows(&ids, idempotency.window());
+
+        let (values, claimed): (Vec<_>, Vec<_>) = values
+            .into_iter()
+            .zip(ids)
+            .zip(fresh)
+            .filter_map(|(v, fresh)| fresh.then_some(v))
+            .unzip();
+        let duplicates = total - claimed.len();
+
+        match self.append(Rows::Json(values), origin).await {
+            Ok(failed) => {
+                let rejected = failed
+                    .iter()
+                    .map(|v| idempotency.row_id(&v.row))
+                    .collect::<Vec<_>>();
+                self.shared.dedup.release_rows(&rejected);
+
+                Ok(Inserted { failed, duplicates })
+            }
+            Err(e) => {
+                self.shared.dedup.release_rows(&claimed);
+                Err(e)
+            }
+        }
+    }
+
+    /// Rows are accepted once the shards take them. A dead letter that cannot
+    /// take the rejected ones is reported rather than failing the insert, as
+    /// a retried insert would add the accepted rows again. The rejected rows
+    /// are returned to the client either way.
+    async fn append(&self, rows: Rows, origin: &Origin) -> Result<FailedRows, Error> {
+        let failed = self.shards.add_rows(rows, origin).await?;
+
+        if !failed.is_empty() {
+            let result = self.dead_letter.write(&self.name, origin, &failed).await;
+            self.sw.set_job_error(
+                "dead letter",
+                &self.name,
+                result.err().map(|e| e.to_string()),
+            );
+        }
+
+        Ok(failed)
+    }
+
+    /// Blocks of the stream, copies of blocks of other replicas included.
+    pub fn blocks(&self, query: &Query) -> Vec<BlockEntry> {
+        let mut blocks = query.blocks(&self.shared.catalog);
+        for catalog in self.shared.copies.catalogs() {
+            blocks.extend(query.blocks(&catalog));
+        }
+
+        blocks.sort_by(|a, b| (&a.partition, a.id).cmp(&(&b.partition, b.id)));
+        blocks
+    }
+
+    /// Offloaded blocks are fetched into the cache one at a time, as the
+    /// scan reaches them. Copies of blocks of other replicas are scanned too,
+    /// so history survives a failover.
+    pub async fn query(&self, query: Query) -> Result<Vec<JsonValue>, Error> {
+        let mut catalogs = vec![self.shared.catalog.clone()];
+        catalogs.extend(self.shared.copies.catalogs());
+        let time_field = self.definition.time_field().to_string();
+        let remote = self.remote.clone();
+        let handle = Handle::current();
+
+        spawn_blocking(move || {
+            query.run(&catalogs, &time_field, |catalog, entry| {
+                match (&entry.remote, &remote) {
+                    (Some(key), Some(remote)) => Ok(handle.block_on(remote.fetch(key))?),
+                    (Some(_), None) => Err(tiering::Error::NoObjectStore)?,
+                    (None, _) => Ok(entry.path(catalog.dir()).into()),
+                }
+            })
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn query_dead_letter(&self, query: Query) -> Result<Vec<JsonValue>, Error> {
+        let catalog = self.dead_letter.catalog().clone();
+        spawn_blocking(move || {
+            query.run(&[catalog], RECEIVED_AT, |catalog, entry| {
+                Ok::<_, Error>(entry.path(catalog.dir()).into())
+            })
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Answers a replication request of another instance of the replicaset.
+    pub async fn replicate(&self, message: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let shared = self.shared.clone();
+        Ok(spawn_blocking(move || shared.copies.handle(&message))
+            .await
+            .unwrap()?)
+    }
+
+    /// Offloaded blocks are downloaded into the snapshot.
+    pub async fn create_snapshot(&self) -> Result<SnapshotInfo, Error> {
+        let shared = self.shared.clone();
+        let definition = self.definition.clone();
+        let locate = self.locate();
+
+        Ok(spawn_blocking(move || {
+            shared
+                .snapshots
+                .create(&definition, &shared.catalog, locate)
+        })
+        .await
+        .unwrap()?)
+    }
+
+    pub async fn snapshots(&self) -> Result<Vec<SnapshotInfo>, Error> {
+        let shared = self.shared.clone();
+        Ok(spawn_blocking(move || shared.snapshots.list())
+            .await
+            .unwrap()?)
+    }
+
+    pub async fn delete_snapshot(&self, id: Uuid) -> Result<(), Error> {
+        let shared = self.shared.clone();
+        Ok(spawn_blocking(move || shared.snapshots.delete(id))
+            .await
+            .unwrap()?)
+    }
+
+    /// Path of the snapshot archive, offloaded blocks are fetched into it.
+    pub async fn export_snapshot(&self, id: Uuid) -> Result<PathBuf, Error> {
+        let shared = self.shared.clone();
+        let locate = self.locate();
+
+        Ok(
+            spawn_blocking(move || shared.snapshots.export(id, shared.catalog.keys(), locate))
+                .await
+                .unwrap()?,
+        )
+    }
+
+    /// Local file of an offloaded block, for use on a blocking thread.
+    fn locate(&self) -> impl FnMut(&BlockEntry) -> Result<Pinned, snapshot::Error> {
+        let remote = self.remote.clone();
+        let handle = Handle::current();
+
+        move |entry| match (&entry.remote, &remote) {
+            (Some(key), Some(remote)) => Ok(handle.block_on(remote.fetch(key))?),
+            _ => Err(tiering::Error::NoObjectStore)?,
+        }
+    }
+
+    pub async fn restore(&self, archive: PathBuf) -> Result<Restore, Error> {
+        let shared = self.shared.clone();
+        Ok(
+            spawn_blocking(move || Snapshots::restore(&archive, &shared.catalog))
+                .await
+                .unwrap()?,
+        )
+    }
+
+    pub fn keys(&self) -> Result<Vec<KeyInfo>, Error> {
+        Ok(self.shared.keyring(&self.shared.catalog)?.list())
+    }
+
+    /// New blocks of the stream and its dead letters are encrypted with new
+    /// keys. Existing blocks are not re-encrypted: they keep their keys, which
+    /// stay in the keyring, unless compaction or erasure happens to rewrite
+    /// them.
+    pub fn rotate_key(&self) -> Result<KeyInfo, Error> {
+        self.shared.keyring(&self.shared.dead_letter)?.rotate()?;
+        Ok(self.shared.keyring(&self.shared.catalog)?.rotate()?)
+    }
+
+    /// Destroys the data keys of the stream and its dead letters, then
+    /// removes the blocks they made unreadable. Copies of the blocks in
+    /// snapshots, on the object store and on other replicas stay unreadable;
+    /// the replicas destroy their keys once they drop their copies.
+    pub async fn shred(&self) -> Result<Shred, Error> {
+        let shared = self.shared.clone();
+        let (shred, offloaded) = spawn_blocking(move || shared.shred()).await.unwrap()?;
+
+        if let Some(remote) = &self.remote {
+            for key in offloaded {
+                remote.delete(&key).await?;
+            }
+        }
+
+        Ok(shred)
+    }
+
+    /// Starts a job erasing or redacting the flushed rows of the stream that
+    /// match the request, in offloaded blocks, dead letters and snapshots as
+    /// well. Replicas drop their copies of the rewritten blocks when
+    /// replication tells them. Copies this instance keeps for other
+    /// instances are left as they are, the job records them.
+    pub fn erase(
+        &self,
+        request: ErasureRequest,
+        origin: &Origin,
+        tt: &TaskTracker,
+    ) -> Result<Erasure, Error> {
+        request
+            .validate(&self.definition.fields, self.definition.time_field())
+            .map_err(Error::InvalidRedaction)?;
+
+        let eraser = Eraser {
+            stream: self.name.clone(),
+            rewriter: Rewriter::new(
+                self.shared.catalog.clone(),
+                self.definition
+                    .storage
+                    .properties(self.definition.storage.max_row_group_size),
+            ),
+            dead_letter: self.dead_letter.rewriter(),
+            snapshots: self.shared.snapshots.clone(),
+            copies: self.shared.copies.clone(),
+            time_field: self.definition.time_field().to_string(),
+            remote: self.remote.clone(),
+            sw: self.sw.clone(),
+        };
+
+        Ok(self
+            .shared
+            .erasures
+            .start(eraser, request, origin.client.clone(), tt)?)
+    }
+
+    pub fn erasures(&self) -> Vec<Erasure> {
+        self.shared.erasures.list()
+    }
+
+    pub fn erasure(&self, id: Uuid) -> Result<Erasure, Error> {
+        self.shared
+            .erasures
+            .get(id)
+            .ok_or(Error::ErasureNotFound(id))
+    }
+
+    /// Re-ingests dead-letter rows through the current definition, with the
+    /// client and receive time they were first received with. `origin` only
+    /// stands in for a missing receive time. Rows rejected again end up in a
+    /// new dead-letter block, written before the replayed block is removed.
+    /// Once rows of a block are accepted the block is removed, so a later
+    /// replay does not add them again, even when the dead letter stopped
+    /// meanwhile and the rows rejected again are lost.
+    pub async fn replay_dead_letter(&self, origin: &Origin) -> Result<Replay, Error> {
+        let (_guard, blocks) = self.dead_letter.blocks().await;
+        let mut replay = Replay {
+            replayed: 0,
+            rejected: 0,
+        };
+
+        for path in blocks {
+            let groups = self.dead_letter.rows(path.clone(), origin).await?;
+            let mut accepted = false;
+            let mut stopped = None;
+            let mut written = Ok(());
+
+            for (origin, rows) in groups {
+                let total = rows.len();
+                let failed = match &stopped {
+                    Some(reason) => Self::rejected(rows, reason),
+                    None => match self.replay_rows(&rows, &origin, accepted).await {
+                        Ok(failed) => failed,
+                        // Earlier rows of the block are in, the rest goes
+                        // back to the dead letter.
+                        Err(e) if accepted => {
+                            let reason = e.to_string();
+                            let failed = Self::rejected(rows, &reason);
+                            stopped = Some(reason);
+                            failed
+                        }
+                        Err(e) => Err(e)?,
+                    },
+                };
+
+                accepted = true;
+                replay.replayed += total - failed.len();
+                replay.rejected += failed.len();
+                if !failed.is_empty() {
+                    let result = self
+                        .dead_letter
+                        .write_waiting(&self.name, &origin, &failed)
+                        .await;
+                    written = written.and(result);
+                }
+            }
+
+            self.dead_letter.remove(&path)?;
+            written?;
+        }
+
+        Ok(replay)
+    }
+
+    /// Once earlier rows of a block are accepted, waits out a full queue or
+    /// memory budget instead of failing.
+    async fn replay_rows(
+        &self,
+        rows: &[JsonValue],
+        origin: &Origin,
+        waiting: bool,
+    ) -> Result<FailedRows, accumulator::Error> {
+        loop {
+            match self
+                .shards
+                .add_rows(Rows::Json(rows.to_vec()), origin)
+                .await
+            {
+                Err(
+                    accumulator::Error::QueueFull(v) | accumulator::Error::MemoryBudgetExceeded(v),
+                ) if waiting => sleep(v).await,
+                v => return v,
+            }
+        }
+    }
+
+    fn rejected(rows: Vec<JsonValue>, reason: &str) -> FailedRows {
+        rows.into_iter()
+            .map(|row| FailedRow {
+                row,
+                reason: reason.to_string(),
+            })
+            .collect()
+    }
+}
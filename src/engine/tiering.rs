@@ -0,0 +1,416 @@
+use crate::engine::block;
+use crate::engine::catalog::BlockEntry;
+use crate::engine::catalog::Catalog;
+use crate::engine::object_store;
+use crate::engine::object_store::ObjectStore;
+use crate::engine::partition;
+use crate::engine::partition::TimeRange;
+use crate::picodata::service::ServiceWarnings;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Deref;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::select;
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+use tokio_util::sync::DropGuard;
+use tokio_util::task::TaskTracker;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("object store: {0}")]
+    ObjectStore(#[from] object_store::Error),
+    #[error("block error: {0}")]
+    Block(#[from] block::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("block is offloaded but no object store is configured")]
+    NoObjectStore,
+}
+
+/// When blocks of a stream are moved to the object store.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Tiering {
+    /// Seconds of event time after which blocks are offloaded.
+    #[serde(default)]
+    pub after: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TieringConfig {
+    pub interval: Duration,
+    /// Bytes of fetched blocks kept on local disk.
+    pub cache_size: u64,
+}
+
+/// The object store holding offloaded blocks and a local cache of the blocks
+/// fetched back for queries.
+#[derive(Debug)]
+pub struct Remote {
+    store: ObjectStore,
+    config: TieringConfig,
+    cache_dir: PathBuf,
+    /// Locks of the keys being fetched or deleted.
+    fetching: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    pins: Pins,
+}
+
+/// Numbers of readers of each cached file.
+type Pins = Arc<Mutex<HashMap<PathBuf, usize>>>;
+
+/// Path of a block file. A fetched file is not evicted from the cache until
+/// this is dropped, local paths convert into it as they are.
+pub struct Pinned {
+    path: PathBuf,
+    pins: Option<Pins>,
+}
+
+/// Offloads the old blocks of a stream.
+pub struct Tierer {
+    stream: String,
+    catalog: Arc<Catalog>,
+    after: Duration,
+    remote: Arc<Remote>,
+    sw: ServiceWarnings,
+}
+
+impl Remote {
+    /// Not a valid stream name, so it is never taken for a stream directory.
+    const CACHE_DIR: &'static str = ".cache";
+
+    pub fn new(store: ObjectStore, dir: &Path, config: TieringConfig) -> Arc<Self> {
+        Arc::new(Self {
+            store,
+            config,
+            cache_dir: dir.join(Self::CACHE_DIR),
+            fetching: Mutex::default(),
+            pins: Pins::default(),
+        })
+    }
+
+    pub fn key(stream: &str, entry: &BlockEntry) -> String {
+        format!(
+            "{stream}/{}/{}.{}",
+            entry.partition.display(),
+            entry.id,
+            block::EXTENSION
+        )
+    }
+
+    /// Local path of an offloaded block, downloaded into the cache if needed.
+    /// Fetches of other keys are not held up by the download.
+    pub async fn fetch(&self, key: &str) -> Result<Pinned, Error> {
+        let path = self.cache_dir.join(key);
+        let lock = self.lock(key);
+        let result = async {
+            let _guard = lock.lock().await;
+            let pinned = self.pin(path.clone());
+
+            if let Ok(file) = File::options().write(true).open(&path) {
+                // Recently used blocks are evicted last.
+                file.set_modified(SystemTime::now())?;
+                return Ok(pinned);
+            }
+
+            let data = self.store.get(key).await?;
+            let tmp_path = path.with_extension("tmp");
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(&tmp_path, data)?;
+            std::fs::rename(&tmp_path, &path)?;
+
+            self.evict()?;
+            Ok(pinned)
+        }
+        .await;
+
+        self.unlock(key, lock);
+        result
+    }
+
+    /// Deletes an offloaded block and its cached copy, which is removed even
+    /// when the object store fails. No fetch of the key runs meanwhile.
+    pub async fn delete(&self, key: &str) -> Result<(), Error> {
+        let lock = self.lock(key);
+        let result = async {
+            let _guard = lock.lock().await;
+            let deleted = self.store.delete(key).await;
+            block::remove_if_exists(&self.cache_dir.join(key))?;
+            Ok(deleted?)
+        }
+        .await;
+
+        self.unlock(key, lock);
+        result
+    }
+
+    fn lock(&self, key: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut fetching = self.fetching.lock().unwrap();
+        fetching.entry(key.to_string()).or_default().clone()
+    }
+
+    /// Forgets the lock of a key nobody else waits for.
+    fn unlock(&self, key: &str, lock: Arc<tokio::sync::Mutex<()>>) {
+        let mut fetching = self.fetching.lock().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            fetching.remove(key);
+        }
+    }
+
+    fn pin(&self, path: PathBuf) -> Pinned {
+        *self.pins.lock().unwrap().entry(path.clone()).or_default() += 1;
+        Pinned {
+            path,
+            pins: Some(self.pins.clone()),
+        }
+    }
+
+    /// Removes the least recently used blocks over the cache size, except
+    /// those being read.
+    fn evict(&self) -> Result<(), Error> {
+        let mut files = Vec::new();
+        let mut dirs = vec![self.cache_dir.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                match metadata.is_dir() {
+                    true => dirs.push(entry.path()),
+                    false => files.push((metadata.modified()?, metadata.len(), entry.path())),
+                }
+            }
+        }
+
+        files.sort();
+        let mut total = files.iter().map(|(_, size, _)| size).sum::<u64>();
+
+        // Held while removing, so a file is not pinned between the check and
+        // the removal.
+        let pins = self.pins.lock().unwrap();
+        for (_, size, path) in files {
+            if total <= self.config.cache_size {
+                break;
+            }
+
+            if !pins.contains_key(&path) {
+                block::remove_if_exists(&path)?;
+                total -= size;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl From<PathBuf> for Pinned {
+    fn from(path: PathBuf) -> Self {
+        Self { path, pins: None }
+    }
+}
+
+impl Deref for Pinned {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        let Some(pins) = &self.pins else {
+            return;
+        };
+
+        let mut pins = pins.lock().unwrap();
+        if let Some(count) = pins.get_mut(&self.path) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.path);
+            }
+        }
+    }
+}
+
+impl Tierer {
+    /// Offloads blocks every interval until the returned guard is dropped.
+    pub fn spawn(
+        stream: String,
+        catalog: Arc<Catalog>,
+        after: Duration,
+        remote: Arc<Remote>,
+        sw: ServiceWarnings,
+        tt: &TaskTracker,
+    ) -> DropGuard {
+        let ct = CancellationToken::new();
+        let tierer = Arc::new(Self {
+            stream,
+            catalog,
+            after,
+            remote,
+            sw,
+        });
+
+        tt.spawn(tierer.run(ct.clone()));
+        ct.drop_guard()
+    }
+
+    async fn run(self: Arc<Self>, ct: CancellationToken) {
+        let mut ticker = interval(self.remote.config.interval);
+
+        loop {
+            select! {
+                _ = ct.cancelled() => return,
+                _ = ticker.tick() => {}
+            }
+
+            let result = select! {
+                _ = ct.cancelled() => return,
+                v = self.clone().offload_all() => v,
+            };
+
+            self.sw
+                .set_job_error("tiering", &self.stream, result.err().map(|e| e.to_string()));
+        }
+    }
+
+    async fn offload_all(self: Arc<Self>) -> Result<(), Error> {
+        let cutoff = OffsetDateTime::now_utc() - self.after;
+        let range = TimeRange {
+            from: None,
+            to: Some(cutoff),
+        };
+
+        for entry in self.catalog.blocks(&range, None) {
+            let path = entry.path(self.catalog.dir());
+
+            if entry.remote.is_some() {
+                // Left behind by a crash right after the upload.
+                block::remove_if_exists(&path)?;
+                continue;
+            }
+
+            // Damaged blocks stay local until the scrub repairs them.
+            if entry.damaged.is_some() || entry.max_time.is_none_or(|v| v >= cutoff) {
+                continue;
+            }
+
+            let data = match tokio::fs::read(&path).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                v => v?,
+            };
+
+            let key = Remote::key(&self.stream, &entry);
+            self.remote.store.put(&key, data).await?;
+
+            let this = self.clone();
+            let remote_key = key.clone();
+            let offloaded = spawn_blocking(move || this.swap(entry, remote_key))
+                .await
+                .unwrap()?;
+
+            if !offloaded {
+                self.remote.delete(&key).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Points the catalog entry to the uploaded copy and removes the local
+    /// one, unless the block was compacted or removed meanwhile.
+    fn swap(&self, entry: BlockEntry, key: String) -> Result<bool, Error> {
+        let path = entry.path(self.catalog.dir());
+        let partition = self.catalog.dir().join(&entry.partition);
+
+        {
+            let _guard = self.catalog.lock_write();
+            if !path.exists() {
+                return Ok(false);
+            }
+
+            self.catalog.update(entry.id, |v| v.remote = Some(key))?;
+            std::fs::remove_file(&path)?;
+        }
+
+        if std::fs::read_dir(&partition)?.next().is_none() {
+            partition::remove(&partition)?;
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::block::testing::TempDir;
+    use crate::engine::object_store::ObjectStoreConfig;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn pinned_files_are_not_evicted() {
+        let dir = TempDir::new();
+        let store = ObjectStore::new(ObjectStoreConfig {
+            endpoint: "http://127.0.0.1:1".into(),
+            bucket: "b".into(),
+            region: "us-east-1".into(),
+            access_key: "k".into(),
+            secret_key: "s".into(),
+        })
+        .unwrap();
+        let config = TieringConfig {
+            interval: Duration::from_secs(60),
+            cache_size: 10,
+        };
+        let remote = Remote::new(store, &dir, config);
+
+        let cached = |key: &str| {
+            let path = remote.cache_dir.join(key);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, [0; 8]).unwrap();
+            path
+        };
+        let a = cached("s/a");
+        let b = cached("s/b");
+
+        // A cached file is pinned without going to the object store.
+        let pinned = remote.fetch("s/a").await.unwrap();
+        assert_eq!(&*pinned, a.as_path());
+        File::options()
+            .write(true)
+            .open(&b)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+
+        remote.evict().unwrap();
+        assert!(a.exists());
+        assert!(!b.exists());
+
+        drop(pinned);
+        let c = cached("s/c");
+        File::options()
+            .write(true)
+            .open(&c)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+
+        remote.evict().unwrap();
+        assert!(!a.exists());
+        assert!(c.exists());
+        assert!(remote.pins.lock().unwrap().is_empty());
+        assert!(remote.fetching.lock().unwrap().is_empty());
+    }
+}
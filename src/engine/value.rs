@@ -4,8 +4,12 @@ use ordered_float::OrderedFloat;
 use regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::Value as JsonValue;
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::mem::discriminant;
 use std::net::IpAddr;
+use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 use unicase::UniCase;
 use uuid::Uuid;
@@ -92,6 +96,15 @@ impl<'a> Value<'a> {
         }
     }
 
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Self::I64(lv), Self::F64(rv)) => OrderedFloat(*lv as f64).partial_cmp(rv),
+            (Self::F64(lv), Self::I64(rv)) => lv.partial_cmp(&OrderedFloat(*rv as f64)),
+            _ if discriminant(self) == discriminant(other) => self.partial_cmp(other),
+            _ => None,
+        }
+    }
+
     pub fn eq_fold(&self, other: &Self) -> bool {
         match (self, other) {
             (Self::String(lv), Self::String(rv)) => UniCase::unicode(lv) == UniCase::unicode(rv),
@@ -237,6 +250,8 @@ impl<'a> Value<'a> {
         match (self, other) {
             (Self::I64(lv), Self::I64(rv)) => lv.saturating_add(*rv).into(),
             (Self::F64(lv), Self::F64(rv)) => Value::F64(*lv + *rv),
+            (Self::I64(lv), Self::F64(rv)) => Value::F64(OrderedFloat(*lv as f64) + *rv),
+            (Self::F64(lv), Self::I64(rv)) => Value::F64(*lv + OrderedFloat(*rv as f64)),
             _ => Value::Null,
         }
     }
@@ -245,6 +260,8 @@ impl<'a> Value<'a> {
         match (self, other) {
             (Self::I64(lv), Self::I64(rv)) => lv.saturating_sub(*rv).into(),
             (Self::F64(lv), Self::F64(rv)) => Value::F64(*lv - *rv),
+            (Self::I64(lv), Self::F64(rv)) => Value::F64(OrderedFloat(*lv as f64) - *rv),
+            (Self::F64(lv), Self::I64(rv)) => Value::F64(*lv - OrderedFloat(*rv as f64)),
             _ => Value::Null,
         }
     }
@@ -253,6 +270,8 @@ impl<'a> Value<'a> {
         match (self, other) {
             (Self::I64(lv), Self::I64(rv)) => lv.saturating_mul(*rv).into(),
             (Self::F64(lv), Self::F64(rv)) => Value::F64(*lv * *rv),
+            (Self::I64(lv), Self::F64(rv)) => Value::F64(OrderedFloat(*lv as f64) * *rv),
+            (Self::F64(lv), Self::I64(rv)) => Value::F64(*lv * OrderedFloat(*rv as f64)),
             _ => Value::Null,
         }
     }
@@ -261,6 +280,12 @@ impl<'a> Value<'a> {
         match (self, other) {
             (Self::I64(lv), Self::I64(rv)) if *rv != 0 => lv.saturating_div(*rv).into(),
             (Self::F64(lv), Self::F64(rv)) if *rv != 0.0 => Value::F64(*lv / *rv),
+            (Self::I64(lv), Self::F64(rv)) if *rv != 0.0 => {
+                Value::F64(OrderedFloat(*lv as f64) / *rv)
+            }
+            (Self::F64(lv), Self::I64(rv)) if *rv != 0 => {
+                Value::F64(*lv / OrderedFloat(*rv as f64))
+            }
             _ => Value::Null,
         }
     }
@@ -287,6 +312,80 @@ impl<'a> Value<'a> {
     }
 }
 
+//
+// JsonValue <-> Value.
+//
+
+impl<'a> From<&'a JsonValue> for Value<'a> {
+    fn from(value: &'a JsonValue) -> Self {
+        match value {
+            JsonValue::String(v) => Self::String(Cow::Borrowed(v)),
+            JsonValue::Bool(v) => Self::Bool(*v),
+            JsonValue::Number(v) => match v.as_i64() {
+                Some(v) => Self::I64(v),
+                None => v.as_f64().map_or(Self::Null, |v| v.into()),
+            },
+            JsonValue::Array(v) if v.iter().all(|v| v.is_string()) && !v.is_empty() => v
+                .iter()
+                .map(|v| v.as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+                .into(),
+            JsonValue::Array(v) if v.iter().all(|v| v.is_i64()) && !v.is_empty() => v
+                .iter()
+                .map(|v| v.as_i64().unwrap())
+                .collect::<Vec<_>>()
+                .into(),
+            JsonValue::Array(v) if v.iter().all(|v| v.is_number()) && !v.is_empty() => v
+                .iter()
+                .map(|v| OrderedFloat(v.as_f64().unwrap()))
+                .collect::<Vec<_>>()
+                .into(),
+            JsonValue::Array(v) if v.iter().all(|v| v.is_boolean()) && !v.is_empty() => v
+                .iter()
+                .map(|v| v.as_bool().unwrap())
+                .collect::<Vec<_>>()
+                .into(),
+            _ => Self::Null,
+        }
+    }
+}
+
+impl From<Value<'_>> for JsonValue {
+    fn from(value: Value<'_>) -> Self {
+        match value {
+            Value::String(v) => v.into_owned().into(),
+            Value::VecString(v) => v.into_owned().into(),
+
+            Value::I64(v) => v.into(),
+            Value::VecI64(v) => v.into_owned().into(),
+
+            Value::F64(v) => v.into_inner().into(),
+            Value::VecF64(v) => v.iter().map(|v| v.into_inner()).collect::<Vec<_>>().into(),
+
+            Value::Timestamp(v) => v.format(&Rfc3339).map_or(JsonValue::Null, |v| v.into()),
+            Value::VecTimestamp(v) => v
+                .iter()
+                .map(|v| v.format(&Rfc3339).map_or(JsonValue::Null, |v| v.into()))
+                .collect::<Vec<_>>()
+                .into(),
+
+            Value::Ip(v) => v.to_string().into(),
+            Value::VecIp(v) => v.iter().map(|v| v.to_string()).collect::<Vec<_>>().into(),
+
+            Value::IpNet(v) => v.to_string().into(),
+            Value::VecIpNet(v) => v.iter().map(|v| v.to_string()).collect::<Vec<_>>().into(),
+
+            Value::Uuid(v) => v.to_string().into(),
+            Value::VecUuid(v) => v.iter().map(|v| v.to_string()).collect::<Vec<_>>().into(),
+
+            Value::Bool(v) => v.into(),
+            Value::VecBool(v) => v.into_owned().into(),
+
+            Value::Null => JsonValue::Null,
+        }
+    }
+}
+
 //
 // T -> Value.
 //
@@ -2,44 +2,162 @@ mod api;
 mod engine;
 pub(crate) mod picodata;
 
+#[cfg(feature = "bench")]
+pub use engine::bench;
+
 use crate::api::tls_config;
+use crate::engine::CompactionConfig;
+use crate::engine::DiskConfig;
 use crate::engine::Engine;
+use crate::engine::Limits;
+use crate::engine::MasterKey;
+use crate::engine::MemoryBudget;
+use crate::engine::ObjectStore;
+use crate::engine::Remote;
+use crate::engine::ReplicationConfig;
+use crate::engine::ScrubConfig;
+use crate::engine::ServerKey;
+use crate::engine::TieringConfig;
+use crate::picodata::rpc::Inbox;
+use crate::picodata::rpc::Path;
 use crate::picodata::rpc::ProxyClient;
 use crate::picodata::service::ServiceConfig;
 use crate::picodata::service::ServiceWarnings;
 use anyhow::Result;
+use picoplugin::internal::types::InstanceInfo;
 use picoplugin::interplay::channel::oneshot;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::Duration;
+use tokio::select;
 use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
 pub fn entrypoint(
     cfg: ServiceConfig,
+    instance: InstanceInfo,
     rpc_client: ProxyClient,
+    inbox: Inbox,
     done_tx: oneshot::Sender<()>,
     ct: CancellationToken,
     sw: ServiceWarnings,
 ) -> Result<()> {
-    let engine = Engine::new();
-    let api_server = api::start_server(
-        SocketAddr::from_str(&format!("0.0.0.0:{}", cfg.api_port))?,
-        tls_config(&cfg.api_ca_crt, &cfg.api_crt, &cfg.api_key)?,
-        api::State::new(engine, rpc_client),
-        ct,
-    );
+    let addr = SocketAddr::from_str(&format!("0.0.0.0:{}", cfg.api_port))?;
+    let tls = tls_config(&cfg.api_ca_crt, &cfg.api_crt, &cfg.api_key)?;
+    let tt = TaskTracker::new();
 
     std::thread::spawn(move || {
-        if let Err(e) = tokio::runtime::Builder::new_current_thread()
+        // Shards of a stream convert rows on worker threads in parallel.
+        let rt = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
-            .unwrap()
-            .block_on(api_server)
-        {
+            .unwrap();
+
+        if let Err(e) = rt.block_on(async {
+            let config = engine_config(&cfg, &instance, &rpc_client)?;
+            let engine = Engine::open(config, tt.clone(), sw.clone())?;
+            let state = api::State::new(engine, rpc_client);
+            tt.spawn(serve_inbox(state.clone(), inbox, ct.clone()));
+            api::start_server(addr, tls, state, ct).await
+        }) {
             sw.set_public_api_error(Some(e.to_string()));
         }
 
+        tt.close();
+        rt.block_on(tt.wait());
         done_tx.send(());
     });
 
     Ok(())
 }
+
+/// Answers requests of other instances until cancelled.
+async fn serve_inbox(state: api::State, mut inbox: Inbox, ct: CancellationToken) {
+    loop {
+        let request = select! {
+            _ = ct.cancelled() => return,
+            v = inbox.recv() => match v {
+                Some(v) => v,
+                None => return,
+            },
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            let response = match request.path {
+                Path::Replicate => state
+                    .engine()
+                    .replicate(request.data)
+                    .await
+                    .map_err(|e| e.to_string()),
+                // Not registered with the inbox.
+                Path::Insert => Err("inserts are not served by the inbox".to_string()),
+            };
+
+            request.response_tx.send(response);
+        });
+    }
+}
+
+fn engine_config(
+    cfg: &ServiceConfig,
+    instance: &InstanceInfo,
+    rpc_client: &ProxyClient,
+) -> Result<engine::Config> {
+    let instance_id = instance.instance_id().to_string();
+    let remote = match &cfg.object_store {
+        Some(v) => Some(Remote::new(
+            ObjectStore::new(v.clone())?,
+            &cfg.data_dir,
+            TieringConfig {
+                interval: Duration::from_secs(cfg.tiering_interval),
+                cache_size: cfg.tiering_cache_size,
+            },
+        )),
+        None => None,
+    };
+
+    let replication = ReplicationConfig {
+        instance: instance_id.clone(),
+        interval: Duration::from_secs(cfg.replication_interval),
+        client: rpc_client.clone(),
+    };
+
+    Ok(engine::Config {
+        dir: cfg.data_dir.clone(),
+        limits: Limits {
+            queue_depth: cfg.ingest_queue_depth,
+            memory_budget: cfg.stream_memory_budget,
+        },
+        memory: MemoryBudget::new(cfg.memory_budget),
+        instance_id: instance_id.into(),
+        compaction: CompactionConfig {
+            interval: Duration::from_secs(cfg.compaction_interval),
+            block_size: cfg.compaction_block_size,
+            throughput: cfg.compaction_throughput,
+        },
+        retention_interval: Duration::from_secs(cfg.retention_interval),
+        scrub: ScrubConfig {
+            interval: Duration::from_secs(cfg.scrub_interval),
+            throughput: cfg.scrub_throughput,
+        },
+        disk: DiskConfig {
+            interval: Duration::from_secs(cfg.disk_check_interval),
+            soft_limit: cfg.disk_soft_limit,
+            hard_limit: cfg.disk_hard_limit,
+            emergency_retention: cfg.disk_emergency_retention,
+        },
+        remote,
+        replication: Some(replication),
+        encryption: cfg
+            .encryption_key
+            .as_deref()
+            .map(|v| MasterKey::load(v, cfg.encryption_previous_key.as_deref()))
+            .transpose()?,
+        server_key: Some(ServerKey::load(
+            &cfg.api_key,
+            &cfg.api_crt,
+            &cfg.api_previous_crts,
+        )?),
+    })
+}
@@ -6,22 +6,31 @@ use picoplugin::plugin::prelude::PicoContext;
 use picoplugin::system::tarantool::cbus::RecvError;
 use picoplugin::system::tarantool::error::BoxError;
 use picoplugin::system::tarantool::error::Error as TarantoolError;
+use picoplugin::system::tarantool::error::TarantoolErrorCode;
 use picoplugin::system::tarantool::fiber;
+use picoplugin::system::tarantool::index::IteratorType;
+use picoplugin::system::tarantool::space::Space;
 use picoplugin::transport::context::Context;
 use picoplugin::transport::rpc;
+use std::fmt;
 use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::mpsc;
 use tokio::task::spawn_blocking;
 use tokio::task::JoinError;
 
 const PROXY_CHANNEL_CAPACITY: usize = 100;
 
+/// The picodata table of the instances of the cluster.
+const INSTANCES: &str = "_pico_instance";
+
 #[derive(Clone)]
 pub struct ProxyClient(channel::Sender<ProxyMessage>);
 
 #[derive(Debug, Clone)]
 pub struct ProxyRequest {
-    pub target: rpc::RequestTarget<'static>,
+    /// Id of the instance the request is sent to.
+    pub instance: String,
     pub path: Path,
     pub data: Vec<u8>,
     pub timeout: Duration,
@@ -30,8 +39,18 @@ pub struct ProxyRequest {
 #[derive(Debug, Clone, Copy)]
 pub enum Path {
     Insert,
+    Replicate,
+}
+
+/// A request of another instance, answered on the tokio runtime.
+pub struct InboundRequest {
+    pub path: Path,
+    pub data: Vec<u8>,
+    pub response_tx: oneshot::Sender<Result<Vec<u8>, String>>,
 }
 
+pub type Inbox = mpsc::UnboundedReceiver<InboundRequest>;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("spawn proxy server: {0}")]
@@ -46,15 +65,23 @@ pub enum Error {
     ProxyReceive(#[from] RecvError),
     #[error("request: {0}")]
     Request(String),
+    #[error("peers: {0}")]
+    Peers(String),
     #[error("tokio task join: {0}")]
     TokioTaskJoin(#[from] JoinError),
 }
 
 struct ProxyServer;
 
-struct ProxyMessage {
-    request: ProxyRequest,
-    response_tx: oneshot::Sender<Result<rpc::Response, Error>>,
+enum ProxyMessage {
+    Request {
+        request: ProxyRequest,
+        response_tx: oneshot::Sender<Result<rpc::Response, Error>>,
+    },
+    /// Asks for the other instances of the replicaset.
+    Peers {
+        response_tx: oneshot::Sender<Result<Vec<String>, Error>>,
+    },
 }
 
 struct ServiceInfo {
@@ -73,26 +100,52 @@ impl ProxyClient {
         spawn_blocking(move || Self::send(&tx, request)).await?
     }
 
+    /// Ids of the other instances of this replicaset, as picodata knows them
+    /// now.
+    pub async fn peers(&self) -> Result<Vec<String>, Error> {
+        let tx = self.0.clone();
+        spawn_blocking(move || {
+            let (response_tx, response_rx) = oneshot::channel();
+            tx.send(ProxyMessage::Peers { response_tx })
+                .map_err(|e| Error::Peers(e.to_string()))?;
+            response_rx.receive()?
+        })
+        .await?
+    }
+
     fn send(
         tx: &channel::Sender<ProxyMessage>,
         request: ProxyRequest,
     ) -> Result<rpc::Response, Error> {
         let (response_tx, response_rx) = oneshot::channel();
 
-        tx.send(ProxyMessage {
+        tx.send(ProxyMessage::Request {
             request,
             response_tx,
         })
-        .map_err(|e| Error::ProxySend(e.to_string(), e.0.request))?;
+        .map_err(|e| {
+            let reason = e.to_string();
+            match e.0 {
+                ProxyMessage::Request { request, .. } => Error::ProxySend(reason, request),
+                ProxyMessage::Peers { .. } => unreachable!(),
+            }
+        })?;
 
         response_rx.receive()?
     }
 }
 
+impl fmt::Debug for ProxyClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ProxyClient")
+    }
+}
+
 impl Path {
     pub fn as_str(&self) -> &str {
         match self {
             Self::Insert => "/insert",
+            Self::Replicate => "/replicate",
         }
     }
 }
@@ -108,20 +161,56 @@ impl From<&PicoContext> for ServiceInfo {
 }
 
 impl ProxyServer {
-    fn run(rx: channel::EndpointReceiver<ProxyMessage>, _: InstanceInfo, service: ServiceInfo) {
+    fn run(
+        rx: channel::EndpointReceiver<ProxyMessage>,
+        instance: InstanceInfo,
+        service: ServiceInfo,
+    ) {
         while let Ok(msg) = rx.receive() {
-            match rpc::RequestBuilder::new(msg.request.target)
-                .plugin_service(&service.plugin_name, &service.name)
-                .plugin_version(&service.plugin_version)
-                .path(msg.request.path.as_str())
-                .input(rpc::Request::from_bytes(&msg.request.data))
-                .timeout(msg.request.timeout)
-                .send()
-            {
-                Ok(response) => msg.response_tx.send(Ok(response)),
-                Err(e) => msg.response_tx.send(Err(Error::Request(e.to_string()))),
+            match msg {
+                ProxyMessage::Request {
+                    request,
+                    response_tx,
+                } => response_tx.send(Self::request(&request, &service)),
+                ProxyMessage::Peers { response_tx } => response_tx.send(Self::peers(&instance)),
+            }
+        }
+    }
+
+    fn request(request: &ProxyRequest, service: &ServiceInfo) -> Result<rpc::Response, Error> {
+        rpc::RequestBuilder::new(rpc::RequestTarget::Id(&request.instance))
+            .plugin_service(&service.plugin_name, &service.name)
+            .plugin_version(&service.plugin_version)
+            .path(request.path.as_str())
+            .input(rpc::Request::from_bytes(&request.data))
+            .timeout(request.timeout)
+            .send()
+            .map_err(|e| Error::Request(e.to_string()))
+    }
+
+    fn peers(instance: &InstanceInfo) -> Result<Vec<String>, Error> {
+        let error = |e: &dyn fmt::Display| Error::Peers(e.to_string());
+        let space = Space::find(INSTANCES).ok_or_else(|| error(&INSTANCES))?;
+        let mut peers = Vec::new();
+
+        for tuple in space
+            .select(IteratorType::All, &())
+            .map_err(|e| error(&e))?
+        {
+            let field = |name| {
+                tuple
+                    .try_get::<_, String>(name)
+                    .map_err(|e| error(&e))?
+                    .ok_or_else(|| error(&format!("{INSTANCES} has no {name}")))
+            };
+
+            let id = field("instance_id")?;
+            if field("replicaset_id")? == instance.replicaset_id() && id != instance.instance_id() {
+                peers.push(id);
             }
         }
+
+        Ok(peers)
     }
 }
 
@@ -146,3 +235,32 @@ where
         .register(handler)
         .map_err(|e| Error::ServerRegister(e.to_string()))
 }
+
+/// Registers handlers of `paths` that pass requests to the returned inbox
+/// and wait for the answer.
+pub fn register_inbox(ctx: &PicoContext, paths: &[Path]) -> Result<Inbox, Error> {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    for path in paths.iter().copied() {
+        let tx = tx.clone();
+        register_server(ctx, path, move |request, _| {
+            let (response_tx, response_rx) = oneshot::channel();
+            let error = |e: String| BoxError::new(TarantoolErrorCode::ProcC, e);
+
+            tx.send(InboundRequest {
+                path,
+                data: request.as_bytes().to_vec(),
+                response_tx,
+            })
+            .map_err(|_| error("service is stopped".into()))?;
+
+            match response_rx.receive() {
+                Ok(Ok(data)) => Ok(rpc::Response::from(data)),
+                Ok(Err(e)) => Err(error(e)),
+                Err(e) => Err(error(e.to_string())),
+            }
+        })?;
+    }
+
+    Ok(rx)
+}
@@ -1,5 +1,7 @@
+use crate::engine::ObjectStoreConfig;
 use crate::entrypoint;
 use crate::picodata::rpc;
+use picoplugin::internal::instance_info;
 use picoplugin::interplay::channel::oneshot;
 use picoplugin::plugin::interface::Service as PicoService;
 use picoplugin::plugin::prelude::service_registrar;
@@ -7,6 +9,7 @@ use picoplugin::plugin::prelude::CallbackResult;
 use picoplugin::plugin::prelude::PicoContext;
 use picoplugin::plugin::prelude::ServiceRegistry;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -27,7 +30,60 @@ pub struct ServiceConfig {
     pub api_ca_crt: PathBuf,
     pub api_crt: PathBuf,
     pub api_key: PathBuf,
+    /// Certificates the API server had before. Hash chain links they signed
+    /// stay trusted, links of other certificates are reported broken.
+    #[serde(default)]
+    pub api_previous_crts: Vec<PathBuf>,
     pub data_dir: PathBuf,
+    #[serde(default = "ServiceConfig::default_ingest_queue_depth")]
+    pub ingest_queue_depth: usize,
+    #[serde(default = "ServiceConfig::default_stream_memory_budget")]
+    pub stream_memory_budget: usize,
+    /// Bytes of not yet flushed rows of all streams.
+    #[serde(default = "ServiceConfig::default_memory_budget")]
+    pub memory_budget: usize,
+    #[serde(default = "ServiceConfig::default_compaction_interval")]
+    pub compaction_interval: u64,
+    #[serde(default = "ServiceConfig::default_compaction_block_size")]
+    pub compaction_block_size: u64,
+    #[serde(default = "ServiceConfig::default_compaction_throughput")]
+    pub compaction_throughput: u64,
+    #[serde(default = "ServiceConfig::default_retention_interval")]
+    pub retention_interval: u64,
+    /// Seconds between scrubs re-reading every local block.
+    #[serde(default = "ServiceConfig::default_scrub_interval")]
+    pub scrub_interval: u64,
+    #[serde(default = "ServiceConfig::default_scrub_throughput")]
+    pub scrub_throughput: u64,
+    #[serde(default = "ServiceConfig::default_disk_check_interval")]
+    pub disk_check_interval: u64,
+    /// Free bytes of the data volume below which a warning is raised.
+    #[serde(default = "ServiceConfig::default_disk_soft_limit")]
+    pub disk_soft_limit: u64,
+    /// Free bytes of the data volume below which inserts are rejected.
+    #[serde(default = "ServiceConfig::default_disk_hard_limit")]
+    pub disk_hard_limit: u64,
+    /// Remove the oldest blocks when free space drops below the hard limit.
+    #[serde(default)]
+    pub disk_emergency_retention: bool,
+    /// Where streams with tiering offload their cold blocks.
+    #[serde(default)]
+    pub object_store: Option<ObjectStoreConfig>,
+    #[serde(default = "ServiceConfig::default_tiering_interval")]
+    pub tiering_interval: u64,
+    /// Bytes of offloaded blocks cached on local disk for queries.
+    #[serde(default = "ServiceConfig::default_tiering_cache_size")]
+    pub tiering_cache_size: u64,
+    #[serde(default = "ServiceConfig::default_replication_interval")]
+    pub replication_interval: u64,
+    /// Master key file, 32 bytes raw or as hex. Blocks are encrypted at rest
+    /// when it is set. It must be the same on all instances, so replicas can
+    /// read the copies they hold.
+    #[serde(default)]
+    pub encryption_key: Option<PathBuf>,
+    /// The master key before a rotation, until the data keys are rewrapped.
+    #[serde(default)]
+    pub encryption_previous_key: Option<PathBuf>,
 }
 
 #[derive(Clone, Default)]
@@ -36,12 +92,17 @@ pub struct ServiceWarnings(Arc<Mutex<ServiceWarningsInner>>);
 #[derive(Default)]
 struct ServiceWarningsInner {
     public_api_server: Option<String>,
+    ingest_backpressure: BTreeMap<String, String>,
+    disk: Option<String>,
+    jobs: BTreeMap<(String, String), String>,
 }
 
 #[derive(Debug, Error)]
 enum Error {
     #[error("rpc: {0}")]
     Rpc(#[from] rpc::Error),
+    #[error("instance info: {0}")]
+    InstanceInfo(String),
     #[error("entrypoint: {0:?}")]
     Entrypoint(#[from] anyhow::Error),
 }
@@ -57,9 +118,19 @@ impl PicoService for Service {
 
         let (done_tx, done_rx) = oneshot::channel::<()>();
         let rpc_client = rpc::spawn_proxy_server(ctx).map_err(|e| Error::Rpc(e))?;
+        let inbox = rpc::register_inbox(ctx, &[rpc::Path::Replicate]).map_err(|e| Error::Rpc(e))?;
+        let instance = instance_info().map_err(|e| Error::InstanceInfo(e.to_string()))?;
 
-        entrypoint(cfg, rpc_client, done_tx, self.ct.clone(), self.sw.clone())
-            .map_err(|e| Error::Entrypoint(e))?;
+        entrypoint(
+            cfg,
+            instance,
+            rpc_client,
+            inbox,
+            done_tx,
+            self.ct.clone(),
+            self.sw.clone(),
+        )
+        .map_err(|e| Error::Entrypoint(e))?;
 
         self.done_rx = Some(done_rx);
         Ok(())
@@ -79,11 +150,95 @@ impl PicoService for Service {
     }
 }
 
+impl ServiceConfig {
+    fn default_ingest_queue_depth() -> usize {
+        16
+    }
+
+    fn default_stream_memory_budget() -> usize {
+        64 * 1024 * 1024
+    }
+
+    fn default_memory_budget() -> usize {
+        512 * 1024 * 1024
+    }
+
+    fn default_compaction_interval() -> u64 {
+        300
+    }
+
+    fn default_compaction_block_size() -> u64 {
+        128 * 1024 * 1024
+    }
+
+    fn default_compaction_throughput() -> u64 {
+        32 * 1024 * 1024
+    }
+
+    fn default_retention_interval() -> u64 {
+        60
+    }
+
+    fn default_scrub_interval() -> u64 {
+        24 * 60 * 60
+    }
+
+    fn default_scrub_throughput() -> u64 {
+        8 * 1024 * 1024
+    }
+
+    fn default_disk_check_interval() -> u64 {
+        10
+    }
+
+    fn default_disk_soft_limit() -> u64 {
+        10 * 1024 * 1024 * 1024
+    }
+
+    fn default_disk_hard_limit() -> u64 {
+        1024 * 1024 * 1024
+    }
+
+    fn default_tiering_interval() -> u64 {
+        300
+    }
+
+    fn default_tiering_cache_size() -> u64 {
+        1024 * 1024 * 1024
+    }
+
+    fn default_replication_interval() -> u64 {
+        10
+    }
+}
+
 impl ServiceWarnings {
     pub fn set_public_api_error(&self, e: Option<String>) {
         self.0.lock().unwrap().public_api_server = e;
     }
 
+    pub fn set_ingest_backpressure(&self, stream: &str, e: Option<String>) {
+        let mut guard = self.0.lock().unwrap();
+        match e {
+            Some(e) => guard.ingest_backpressure.insert(stream.to_string(), e),
+            None => guard.ingest_backpressure.remove(stream),
+        };
+    }
+
+    pub fn set_disk_warning(&self, e: Option<String>) {
+        self.0.lock().unwrap().disk = e;
+    }
+
+    /// Last error of a background job run for a stream, cleared on success.
+    pub fn set_job_error(&self, job: &str, stream: &str, e: Option<String>) {
+        let mut guard = self.0.lock().unwrap();
+        let key = (job.to_string(), stream.to_string());
+        match e {
+            Some(e) => guard.jobs.insert(key, e),
+            None => guard.jobs.remove(&key),
+        };
+    }
+
     fn check(&self) -> CallbackResult<()> {
         let mut errors = Vec::new();
         let guard = self.0.lock().unwrap();
@@ -92,6 +247,18 @@ impl ServiceWarnings {
             errors.push(format!("public api server: {}", e));
         }
 
+        for (stream, e) in &guard.ingest_backpressure {
+            errors.push(format!("ingest backpressure on {}: {}", stream, e));
+        }
+
+        if let Some(e) = &guard.disk {
+            errors.push(format!("disk: {}", e));
+        }
+
+        for ((job, stream), e) in &guard.jobs {
+            errors.push(format!("{} of {}: {}", job, stream, e));
+        }
+
         if errors.is_empty() {
             return Ok(());
         }